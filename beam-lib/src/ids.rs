@@ -12,6 +12,13 @@ pub type AddressingId = crate::AppId;
 #[cfg(feature = "strict-ids")]
 static BROKER_ID: std::sync::OnceLock<String> = std::sync::OnceLock::new();
 
+/// Maximum length of a full id, like a DNS name
+#[cfg(feature = "strict-ids")]
+pub const MAX_ID_LEN: usize = 253;
+
+#[cfg(feature = "strict-ids")]
+static LENIENT_IDS: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
 #[cfg(feature = "strict-ids")]
 #[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
 #[serde(untagged)]
@@ -105,6 +112,22 @@ pub fn set_broker_id(id: String) {
     }
 }
 
+/// Lets deserialization accept ids with surrounding whitespace or a trailing dot and normalize them.
+/// By default such ids are rejected.
+#[cfg(feature = "strict-ids")]
+pub fn set_lenient_ids(lenient: bool) {
+    LENIENT_IDS.store(lenient, std::sync::atomic::Ordering::Relaxed);
+}
+
+#[cfg(feature = "strict-ids")]
+fn normalize_id(id: &str, lenient: bool) -> &str {
+    if !lenient {
+        return id;
+    }
+    let id = id.trim();
+    id.strip_suffix('.').unwrap_or(id)
+}
+
 #[cfg(feature = "strict-ids")]
 pub fn get_broker_id() -> &'static String {
     BROKER_ID
@@ -173,6 +196,9 @@ impl_id!(ProxyId);
 
 #[cfg(feature = "strict-ids")]
 fn get_id_type(id: &str) -> Result<BeamIdType, BeamIdError> {
+    if id.len() > MAX_ID_LEN {
+        return Err(BeamIdError::IdTooLong);
+    }
     let rest = strip_broker_id(id)?;
     let Some(rest) = rest.strip_suffix('.') else {
         return Ok(BeamIdType::BrokerId);
@@ -244,10 +270,12 @@ impl ProxyId {
 
 #[cfg(feature = "strict-ids")]
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
 pub enum BeamIdError {
     InvalidNumberOfIdFragments,
     InvalidIdKind,
     InvalidIdFragment,
+    IdTooLong,
     #[cfg(feature = "strict-ids")]
     WrongBrokerId,
 }
@@ -262,6 +290,7 @@ impl Display for BeamIdError {
             BeamIdError::InvalidIdFragment => "Id fragment may only contain alphanumeric values.",
             BeamIdError::InvalidNumberOfIdFragments => "Id had an unexpected amount of fragments.",
             BeamIdError::InvalidIdKind => "Id parsed as a different kind of id then specified.",
+            BeamIdError::IdTooLong => return write!(f, "Id exceeds the maximum length of {MAX_ID_LEN} characters."),
             #[cfg(feature = "strict-ids")]
             BeamIdError::WrongBrokerId => {
                 "The broker id part of the id did not match the global broker id."
@@ -286,8 +315,12 @@ macro_rules! impl_deserialize {
         impl<'de> Deserialize<'de> for $idType {
             fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
                 #[cfg(feature = "strict-ids")]
-                return Self::new(&String::deserialize(deserializer)?)
-                    .map_err(serde::de::Error::custom);
+                {
+                    let id = String::deserialize(deserializer)?;
+                    let lenient = LENIENT_IDS.load(std::sync::atomic::Ordering::Relaxed);
+                    return Self::new(normalize_id(&id, lenient))
+                        .map_err(serde::de::Error::custom);
+                }
                 #[cfg(not(feature = "strict-ids"))]
                 return Ok(Self::new_unchecked(String::deserialize(deserializer)?))
             }
@@ -348,4 +381,38 @@ mod tests {
         let app_id_proxy: AppOrProxyId = proxy_id.clone().into();
         assert_eq!(proxy_id, app_id_proxy.proxy_id());
     }
+
+    #[test]
+    fn test_lenient_ids() {
+        set_broker_id("broker.samply.de".to_string());
+        let canonical = AppId::new("app1.proxy1.broker.samply.de").unwrap();
+        for sloppy in [" app1.proxy1.broker.samply.de", "app1.proxy1.broker.samply.de\n", "app1.proxy1.broker.samply.de."] {
+            assert!(AppId::new(normalize_id(sloppy, false)).is_err(), "{sloppy:?} must be rejected in strict mode");
+            assert_eq!(AppId::new(normalize_id(sloppy, true)).unwrap(), canonical);
+            assert_eq!(ProxyId::new(normalize_id(&sloppy.replacen("app1.", "", 1), true)).unwrap(), canonical.proxy_id());
+        }
+        // Lenient mode only normalizes, it does not accept malformed ids
+        assert!(AppId::new(normalize_id("app1..proxy1.broker.samply.de", true)).is_err());
+        assert!(AppId::new(normalize_id("app 1.proxy1.broker.samply.de", true)).is_err());
+    }
+
+    #[test]
+    fn test_id_length_limit() {
+        set_broker_id("broker.samply.de".to_string());
+        let id_of_len = |len: usize| {
+            // Fill the app and proxy labels so that the full id has the given length
+            let labels = len - ".broker.samply.de".len() - 1;
+            format!("{}.{}.broker.samply.de", "a".repeat(labels / 2), "p".repeat(labels - labels / 2))
+        };
+        let at_limit = id_of_len(MAX_ID_LEN);
+        assert_eq!(at_limit.len(), MAX_ID_LEN);
+        assert!(AppOrProxyId::new(&at_limit).is_ok());
+        assert!(AppId::new(&at_limit).is_ok());
+
+        let over_limit = id_of_len(MAX_ID_LEN + 1);
+        assert_eq!(AppOrProxyId::new(&over_limit), Err(BeamIdError::IdTooLong));
+        assert_eq!(AppId::new(over_limit), Err(BeamIdError::IdTooLong));
+        let long_proxy = format!("{}.broker.samply.de", "p".repeat(MAX_ID_LEN));
+        assert_eq!(ProxyId::new(long_proxy), Err(BeamIdError::IdTooLong));
+    }
 }
@@ -51,6 +51,7 @@ pub struct TaskResult<T> {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SocketTask {
     pub from: AddressingId,
+    #[serde(with = "serde_one_or_many")]
     pub to: Vec<AddressingId>,
     pub ttl: String,
     pub id: MsgId,
@@ -111,6 +112,39 @@ mod serde_string {
     }
 }
 
+/// Serializes a single element as a bare value and everything else as an array.
+/// Deserializing accepts both forms.
+pub mod serde_one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    pub fn serialize<S, T: Serialize>(items: &[T], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match items {
+            [item] => item.serialize(serializer),
+            items => items.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D, T: Deserialize<'de>>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(item) => vec![item],
+            OneOrMany::Many(items) => items,
+        })
+    }
+}
+
 /// Can be used to extract the raw String as sent by the beam proxy without deserializing it into some json value
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct RawString(pub String);
@@ -39,7 +39,11 @@ pub(crate) async fn serve(health: Arc<RwLock<Health>>) -> anyhow::Result<()> {
         config::CONFIG_CENTRAL.bind_addr
     );
     axum::serve(TcpListener::bind(&config::CONFIG_CENTRAL.bind_addr).await?, app.into_make_service_with_connect_info::<SocketAddr>())
-        .with_graceful_shutdown(shared::graceful_shutdown::wait_for_signal())
+        .with_graceful_shutdown(async {
+            shared::graceful_shutdown::wait_for_signal().await;
+            #[cfg(feature = "sockets")]
+            crate::serve_sockets::close_tunnels();
+        })
         .await?;
     Ok(())
 }
@@ -1,10 +1,10 @@
-use std::{sync::Arc, time::{Duration, SystemTime}};
+use std::{collections::HashMap, sync::Arc, time::{Duration, SystemTime}};
 
 use axum::{extract::{State, Path}, http::StatusCode, routing::get, Json, Router, response::Response};
 use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
 use beam_lib::ProxyId;
 use serde::{Serialize, Deserialize};
-use shared::{crypto_jwt::Authorized, Msg, config::CONFIG_CENTRAL};
+use shared::{capabilities::{capabilities, Capabilities}, crypto_jwt::Authorized, Msg, config::CONFIG_CENTRAL};
 use tokio::sync::RwLock;
 
 use crate::{health::{Health, VaultStatus, Verdict, ProxyStatus, InitStatus}, compare_client_server_version::log_version_mismatch};
@@ -19,8 +19,10 @@ struct HealthOutput {
 pub(crate) fn router(health: Arc<RwLock<Health>>) -> Router {
     Router::new()
         .route("/v1/health", get(handler))
+        .route("/v1/info", get(info))
         .route("/v1/health/proxies/:proxy_id", get(proxy_health))
         .route("/v1/health/proxies", get(get_all_proxies))
+        .route("/v1/health/rejections", get(rejections))
         .route("/v1/control", get(get_control_tasks).layer(axum::middleware::from_fn(log_version_mismatch)))
         .with_state(health)
 }
@@ -45,6 +47,24 @@ async fn handler(
     (statuscode, Json(health_as_json))
 }
 
+// GET /v1/info
+async fn info() -> Json<Capabilities> {
+    Json(Capabilities { broker_id: Some(beam_lib::get_broker_id().clone()), ..capabilities() })
+}
+
+// GET /v1/health/rejections
+async fn rejections(auth: TypedHeader<Authorization<Basic>>) -> Result<Json<HashMap<&'static str, u64>>, StatusCode> {
+    let Some(ref monitoring_key) = CONFIG_CENTRAL.monitoring_api_key else {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+
+    if auth.password() != monitoring_key {
+        return Err(StatusCode::UNAUTHORIZED)
+    }
+
+    Ok(Json(shared::rejections::REJECTIONS.counts()))
+}
+
 async fn get_all_proxies(State(state): State<Arc<RwLock<Health>>>) -> Json<Vec<ProxyId>> {
     Json(state.read().await.proxies.keys().cloned().collect())
 }
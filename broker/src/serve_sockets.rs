@@ -1,12 +1,15 @@
-use std::{sync::Arc, collections::{HashMap, HashSet}, ops::Deref, time::Duration};
+use std::{sync::{Arc, Mutex}, collections::{HashMap, HashSet}, ops::Deref, pin::Pin, task::{Context, Poll}, time::Duration};
 
-use axum::{extract::{Path, Request, State}, http::{header, request::Parts, HeaderValue, StatusCode}, response::{IntoResponse, Response}, routing::get, RequestExt, Router};
+use axum::{extract::{Path, Request, State}, http::{header, request::Parts, HeaderValue, StatusCode}, response::{IntoResponse, Response}, routing::get, Json, RequestExt, Router};
+use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
+use beam_lib::AppOrProxyId;
 use bytes::BufMut;
 use hyper_util::rt::TokioIo;
+use once_cell::sync::Lazy;
 use serde::{Serialize, Serializer, ser::SerializeSeq};
 use shared::{config::{CONFIG_CENTRAL, CONFIG_SHARED}, crypto_jwt::Authorized, expire_map::LazyExpireMap, serde_helpers::DerefSerializer, Encrypted, HasWaitId, HowLongToBlock, Msg, MsgEmpty, MsgId, MsgSigned, MsgSocketRequest};
-use tokio::sync::{RwLock, broadcast::{Sender, self}, oneshot};
-use tracing::{debug, log::error, warn};
+use tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, sync::{Notify, RwLock, broadcast::{Sender, self}, oneshot}, time::Instant};
+use tracing::{debug, info, log::error, warn};
 
 use crate::task_manager::{TaskManager, Task};
 
@@ -14,27 +17,32 @@ use crate::task_manager::{TaskManager, Task};
 #[derive(Clone)]
 struct SocketState {
     task_manager: Arc<TaskManager<MsgSocketRequest<Encrypted>>>,
-    waiting_connections: Arc<LazyExpireMap<MsgId, oneshot::Sender<hyper::upgrade::OnUpgrade>>>
-}
-
-impl SocketState {
-    const WAITING_CONNECTIONS_TIMEOUT: Duration = Duration::from_secs(60);
-    const WAITING_CONNECTIONS_CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+    waiting_connections: Arc<LazyExpireMap<MsgId, oneshot::Sender<hyper::upgrade::OnUpgrade>>>,
+    /// Number of closed tunnels by [`SocketCloseReason::as_str`]
+    closed_tunnels: Arc<Mutex<HashMap<&'static str, u64>>>,
+    open_tunnels: Arc<PairTunnels>,
 }
 
 impl Default for SocketState {
     fn default() -> Self {
         let waiting_connections: Arc<LazyExpireMap<_, _>> = Default::default();
+        let task_manager = TaskManager::<MsgSocketRequest<Encrypted>>::new(false);
+        let open_tunnels: Arc<PairTunnels> = Default::default();
         let cons = waiting_connections.clone();
+        let (tm, tunnels) = (task_manager.clone(), open_tunnels.clone());
         tokio::spawn(async move {
             loop {
-                tokio::time::sleep(Self::WAITING_CONNECTIONS_CLEANUP_INTERVAL).await;
-                cons.retain_expired();
+                tokio::time::sleep(CONFIG_CENTRAL.waiter_max_idle).await;
+                // Dropping the sender of an abandoned waiter makes its connection return 410 Gone
+                cons.remove_expired();
+                tunnels.release_unconnected(|id| tm.get(id).map_or(true, |req| req.msg.is_expired()));
             }
         });
         Self {
-            task_manager: TaskManager::new(),
-            waiting_connections
+            task_manager,
+            waiting_connections,
+            closed_tunnels: Default::default(),
+            open_tunnels,
         }
     }
 }
@@ -43,9 +51,274 @@ pub(crate) fn router() -> Router {
     Router::new()
         .route("/v1/sockets", get(get_socket_requests).post(post_socket_request))
         .route("/v1/sockets/:id", get(connect_socket))
+        .route("/v1/health/sockets", get(socket_metrics))
         .with_state(SocketState::default())
 }
 
+#[derive(Serialize)]
+struct SocketMetrics {
+    waiting_connections: usize,
+    closed_tunnels: HashMap<&'static str, u64>,
+}
+
+// GET /v1/health/sockets
+async fn socket_metrics(
+    state: State<SocketState>,
+    auth: TypedHeader<Authorization<Basic>>,
+) -> Result<Json<SocketMetrics>, StatusCode> {
+    let Some(ref monitoring_key) = CONFIG_CENTRAL.monitoring_api_key else {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+
+    if auth.password() != monitoring_key {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(Json(SocketMetrics {
+        waiting_connections: state.waiting_connections.len(),
+        closed_tunnels: state.closed_tunnels.lock().unwrap().clone(),
+    }))
+}
+
+/// Socket requests and open tunnels by sender and recipient, cf. `--max-sockets-per-pair`.
+/// A request takes up a slot from being posted until its tunnel is closed or it expires unconnected.
+#[derive(Default)]
+struct PairTunnels {
+    inner: Mutex<PairTunnelsInner>,
+}
+
+#[derive(Default)]
+struct PairTunnelsInner {
+    open: HashMap<(AppOrProxyId, AppOrProxyId), usize>,
+    /// Pairs reserved by each request and whether its tunnel has been connected
+    reserved: HashMap<MsgId, (Vec<(AppOrProxyId, AppOrProxyId)>, bool)>,
+}
+
+impl PairTunnelsInner {
+    fn release(&mut self, id: &MsgId) {
+        let Some((pairs, _)) = self.reserved.remove(id) else {
+            return;
+        };
+        for pair in &pairs {
+            if let Some(n) = self.open.get_mut(pair) {
+                *n -= 1;
+                if *n == 0 {
+                    self.open.remove(pair);
+                }
+            }
+        }
+    }
+}
+
+impl PairTunnels {
+    /// Reserves a slot for the socket request `id` unless the sender already has `max` requests or tunnels to one of the recipients
+    fn reserve(&self, id: MsgId, from: &AppOrProxyId, to: &[AppOrProxyId], max: usize) -> Result<(), StatusCode> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(recipient) = to.iter().find(|recipient| inner.open.get(&(from.clone(), (*recipient).clone())).is_some_and(|n| *n >= max)) {
+            warn!("Rejecting socket request of {from}: already {max} tunnels open or requested to {recipient}");
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        let pairs: Vec<_> = to.iter().map(|recipient| (from.clone(), recipient.clone())).collect();
+        for pair in &pairs {
+            *inner.open.entry(pair.clone()).or_default() += 1;
+        }
+        inner.reserved.insert(id, (pairs, false));
+        Ok(())
+    }
+
+    /// Frees the slot of a socket request that will not be connected
+    fn release(&self, id: &MsgId) {
+        self.inner.lock().unwrap().release(id);
+    }
+
+    /// Frees the slots of requests that have not been connected and for which `is_gone` holds, e.g. because they expired
+    fn release_unconnected(&self, is_gone: impl Fn(&MsgId) -> bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let gone: Vec<_> = inner.reserved
+            .iter()
+            .filter(|(id, (_, connected))| !connected && is_gone(id))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &gone {
+            inner.release(id);
+        }
+    }
+
+    /// Keeps the slot of the request taken up by its tunnel until the returned slot is dropped
+    fn connect(self: &Arc<Self>, id: MsgId) -> TunnelSlot {
+        if let Some((_, connected)) = self.inner.lock().unwrap().reserved.get_mut(&id) {
+            *connected = true;
+        }
+        TunnelSlot { tunnels: self.clone(), id }
+    }
+}
+
+struct TunnelSlot {
+    tunnels: Arc<PairTunnels>,
+    id: MsgId,
+}
+
+impl Drop for TunnelSlot {
+    fn drop(&mut self) {
+        self.tunnels.release(&self.id);
+    }
+}
+
+/// Removes a waiting connection from the map once the request waiting for its counterpart is dropped,
+/// e.g. because the client disconnected.
+struct WaiterGuard<'a> {
+    waiting_connections: &'a LazyExpireMap<MsgId, oneshot::Sender<hyper::upgrade::OnUpgrade>>,
+    task_id: MsgId,
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        // If the counterpart connected the entry has already been removed
+        self.waiting_connections.remove(&self.task_id);
+    }
+}
+
+
+/// Why a socket tunnel was closed
+#[derive(Debug)]
+enum SocketCloseReason {
+    /// Both sides shut down the connection
+    PeerClosed,
+    Failed(std::io::Error),
+    /// The tunnel was force-closed after being open for the given time
+    LifetimeExceeded(Duration),
+    /// The tunnel was closed after no data was sent for the given time
+    Idle(Duration),
+    /// A peer did not accept data written to it for the given time
+    WriteTimeout(Duration),
+    /// The broker is shutting down
+    Shutdown,
+}
+
+impl SocketCloseReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::PeerClosed => "peer_closed",
+            Self::Failed(_) => "error",
+            Self::LifetimeExceeded(_) => "lifetime",
+            Self::Idle(_) => "idle",
+            Self::WriteTimeout(_) => "write_timeout",
+            Self::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Wakes all relaying tunnels so they close on shutdown
+static TUNNELS_SHUTDOWN: Lazy<Notify> = Lazy::new(Notify::new);
+
+pub(crate) fn close_tunnels() {
+    TUNNELS_SHUTDOWN.notify_waiters();
+}
+
+/// Records when data was last read from the wrapped socket and since when writing to it has been stalled
+struct ActivityTracked<S> {
+    inner: S,
+    last_activity: Arc<Mutex<Instant>>,
+    write_stalled_since: Arc<Mutex<Option<Instant>>>,
+}
+
+impl<S> ActivityTracked<S> {
+    fn new(inner: S, last_activity: Arc<Mutex<Instant>>) -> Self {
+        Self { inner, last_activity, write_stalled_since: Default::default() }
+    }
+
+    fn track_write<T>(&self, res: Poll<T>) -> Poll<T> {
+        let mut stalled_since = self.write_stalled_since.lock().unwrap();
+        if res.is_pending() {
+            stalled_since.get_or_insert_with(Instant::now);
+        } else {
+            *stalled_since = None;
+        }
+        res
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActivityTracked<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            *self.last_activity.lock().unwrap() = Instant::now();
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActivityTracked<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.track_write(res)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let res = Pin::new(&mut self.inner).poll_flush(cx);
+        self.track_write(res)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Relays between both sockets until they are closed, `max_lifetime` has passed, no data was sent for `max_idle`,
+/// a peer did not accept written data for `write_timeout` or `shutdown` is notified. The sockets are closed on return.
+async fn relay<A, B>(a: A, b: B, max_lifetime: Option<Duration>, max_idle: Option<Duration>, write_timeout: Option<Duration>, shutdown: &Notify) -> SocketCloseReason
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let last_activity = Arc::new(Mutex::new(Instant::now()));
+    let mut a = ActivityTracked::new(a, last_activity.clone());
+    let mut b = ActivityTracked::new(b, last_activity.clone());
+    let write_stalls = [a.write_stalled_since.clone(), b.write_stalled_since.clone()];
+    let lifetime = async {
+        let Some(max) = max_lifetime else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep(max).await;
+        max
+    };
+    let idle = async {
+        let Some(max) = max_idle else {
+            return std::future::pending().await;
+        };
+        loop {
+            let deadline = *last_activity.lock().unwrap() + max;
+            if Instant::now() >= deadline {
+                return max;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    };
+    let write_stall = async {
+        let Some(max) = write_timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let stalled_since = write_stalls.iter().filter_map(|since| *since.lock().unwrap()).min();
+            let deadline = stalled_since.unwrap_or_else(Instant::now) + max;
+            if stalled_since.is_some() && Instant::now() >= deadline {
+                return max;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    };
+    tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut a, &mut b) => match result {
+            Ok(_) => SocketCloseReason::PeerClosed,
+            Err(e) => SocketCloseReason::Failed(e),
+        },
+        max = lifetime => SocketCloseReason::LifetimeExceeded(max),
+        max = idle => SocketCloseReason::Idle(max),
+        max = write_stall => SocketCloseReason::WriteTimeout(max),
+        _ = shutdown.notified() => SocketCloseReason::Shutdown,
+    }
+}
 
 async fn get_socket_requests(
     mut block: HowLongToBlock,
@@ -70,7 +343,16 @@ async fn post_socket_request(
     msg: MsgSigned<MsgSocketRequest<Encrypted>>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let msg_id = msg.wait_id();
-    state.task_manager.post_task(msg)?;
+    if let Some(max) = CONFIG_CENTRAL.max_distinct_recipients_per_sender {
+        state.task_manager.check_distinct_recipients(msg.get_from(), msg.get_to(), max, CONFIG_CENTRAL.distinct_recipients_window)?;
+    }
+    if let Some(max) = CONFIG_CENTRAL.max_sockets_per_pair {
+        state.open_tunnels.reserve(msg_id, msg.get_from(), msg.get_to(), max)?;
+    }
+    if let Err(e) = state.task_manager.post_task(msg) {
+        state.open_tunnels.release(&msg_id);
+        return Err(e.into());
+    }
 
     Ok((
         StatusCode::CREATED,
@@ -110,13 +392,19 @@ async fn connect_socket(
         }
     } else {
         let (tx, rx) = tokio::sync::oneshot::channel();
-        state.waiting_connections.insert_for(SocketState::WAITING_CONNECTIONS_TIMEOUT, task_id, tx);
-        let Ok(other_con) = rx.await else {
+        state.waiting_connections.insert_for(CONFIG_CENTRAL.waiter_max_idle, task_id, tx);
+        let guard = WaiterGuard { waiting_connections: &state.waiting_connections, task_id };
+        let other_con = rx.await;
+        drop(guard);
+        let Ok(other_con) = other_con else {
             debug!("Socket expired because nobody connected");
             return Err(StatusCode::GONE);
         };
+        // Taken over before the request is removed so its slot is not freed as expired
+        let slot = state.open_tunnels.connect(task_id);
         // We don't care if the task expired by now
         _ = state.task_manager.remove(&task_id);
+        let closed_tunnels = state.closed_tunnels.clone();
         tokio::spawn(async move {
             let (socket1, socket2) = match tokio::try_join!(conn, other_con) {
                 Ok(sockets) => sockets,
@@ -126,10 +414,17 @@ async fn connect_socket(
                 },
             };
 
-            let result = tokio::io::copy_bidirectional(&mut TokioIo::new(socket1), &mut TokioIo::new(socket2)).await;
-            if let Err(e) = result {
-                debug!("Relaying socket connection ended: {e}");
+            let reason = relay(TokioIo::new(socket1), TokioIo::new(socket2), CONFIG_CENTRAL.socket_max_lifetime, CONFIG_CENTRAL.socket_max_idle, CONFIG_CENTRAL.socket_write_timeout, &TUNNELS_SHUTDOWN).await;
+            match &reason {
+                SocketCloseReason::PeerClosed => debug!("Socket tunnel {task_id} closed by its peers"),
+                SocketCloseReason::Failed(e) => debug!("Relaying socket tunnel {task_id} ended: {e}"),
+                SocketCloseReason::LifetimeExceeded(max) => info!("Closed socket tunnel {task_id} after reaching its maximum lifetime of {max:?}"),
+                SocketCloseReason::Idle(max) => info!("Closed socket tunnel {task_id} after {max:?} without traffic"),
+                SocketCloseReason::WriteTimeout(max) => info!("Closed socket tunnel {task_id} as a peer did not accept data for {max:?}"),
+                SocketCloseReason::Shutdown => info!("Closed socket tunnel {task_id} as the broker is shutting down"),
             }
+            *closed_tunnels.lock().unwrap().entry(reason.as_str()).or_default() += 1;
+            drop(slot);
         });
     }
     Ok(([
@@ -137,3 +432,118 @@ async fn connect_socket(
         (header::CONNECTION, HeaderValue::from_static("upgrade"))
     ], StatusCode::SWITCHING_PROTOCOLS).into_response())
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn active_tunnel_is_closed_at_lifetime_cap() {
+        let (mut client1, socket1) = tokio::io::duplex(64);
+        let (mut client2, socket2) = tokio::io::duplex(64);
+        let max = Duration::from_millis(200);
+        let tunnel = tokio::spawn(async move { relay(socket1, socket2, Some(max), None, None, &Notify::new()).await });
+
+        // Keep the tunnel busy beyond its lifetime
+        let writer = tokio::spawn(async move {
+            while client1.write_all(b"ping").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        let mut buf = [0; 4];
+        client2.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let closed = tokio::time::timeout(Duration::from_secs(5), tunnel).await.unwrap().unwrap();
+        assert!(matches!(closed, SocketCloseReason::LifetimeExceeded(d) if d == max));
+        // Both peers see the connection closed
+        tokio::time::timeout(Duration::from_secs(5), writer).await.unwrap().unwrap();
+        let mut rest = Vec::new();
+        assert!(client2.read_to_end(&mut rest).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn tunnel_closed_by_peers_finishes() {
+        let (client1, socket1) = tokio::io::duplex(64);
+        let (client2, socket2) = tokio::io::duplex(64);
+        drop((client1, client2));
+        let closed = relay(socket1, socket2, Some(Duration::from_secs(60)), Some(Duration::from_secs(60)), Some(Duration::from_secs(60)), &Notify::new()).await;
+        assert!(matches!(closed, SocketCloseReason::PeerClosed));
+    }
+
+    #[tokio::test]
+    async fn quiet_tunnel_is_closed_when_idle() {
+        let (mut client1, socket1) = tokio::io::duplex(64);
+        let (mut client2, socket2) = tokio::io::duplex(64);
+        let max_idle = Duration::from_millis(200);
+        let tunnel = tokio::spawn(async move { relay(socket1, socket2, None, Some(max_idle), None, &Notify::new()).await });
+
+        // Traffic resets the idle timer
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client1.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            client2.read_exact(&mut buf).await.unwrap();
+        }
+        assert!(!tunnel.is_finished());
+        let closed = tokio::time::timeout(Duration::from_secs(5), tunnel).await.unwrap().unwrap();
+        assert!(matches!(closed, SocketCloseReason::Idle(d) if d == max_idle));
+    }
+
+    #[tokio::test]
+    async fn stalled_peer_hits_write_timeout() {
+        let (mut client1, socket1) = tokio::io::duplex(64);
+        // The second peer accepted the tunnel but never reads from it
+        let (_client2, socket2) = tokio::io::duplex(64);
+        let write_timeout = Duration::from_millis(200);
+        let tunnel = tokio::spawn(async move { relay(socket1, socket2, None, None, Some(write_timeout), &Notify::new()).await });
+        let writer = tokio::spawn(async move {
+            while client1.write_all(&[0; 16]).await.is_ok() {}
+        });
+        let closed = tokio::time::timeout(Duration::from_secs(5), tunnel).await.unwrap().unwrap();
+        assert!(matches!(closed, SocketCloseReason::WriteTimeout(d) if d == write_timeout));
+        assert_eq!(closed.as_str(), "write_timeout");
+        tokio::time::timeout(Duration::from_secs(5), writer).await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn tunnels_are_limited_per_pair() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let app = |id: &str| AppOrProxyId::new(id).unwrap();
+        let alice = app("app1.proxy1.broker.samply.de");
+        let bob = app("app1.proxy2.broker.samply.de");
+        let carol = app("app1.proxy3.broker.samply.de");
+        let tunnels = Arc::new(PairTunnels::default());
+        // Requests count against the limit before any of them is connected
+        let (first, second) = (MsgId::new(), MsgId::new());
+        tunnels.reserve(first, &alice, std::slice::from_ref(&bob), 2).unwrap();
+        tunnels.reserve(second, &alice, std::slice::from_ref(&bob), 2).unwrap();
+        assert_eq!(tunnels.reserve(MsgId::new(), &alice, std::slice::from_ref(&bob), 2), Err(StatusCode::TOO_MANY_REQUESTS));
+        // Other pairs are unaffected, including the reverse direction
+        assert!(tunnels.reserve(MsgId::new(), &alice, &[carol], 2).is_ok());
+        assert!(tunnels.reserve(MsgId::new(), &bob, std::slice::from_ref(&alice), 2).is_ok());
+
+        // Connected requests keep their slot until the tunnel closes, expired ones free it
+        let tunnel = tunnels.connect(first);
+        tunnels.release_unconnected(|_| true);
+        assert!(tunnels.reserve(MsgId::new(), &alice, std::slice::from_ref(&bob), 2).is_ok());
+        assert!(tunnels.reserve(MsgId::new(), &alice, std::slice::from_ref(&bob), 2).is_err());
+        drop(tunnel);
+        assert!(tunnels.reserve(MsgId::new(), &alice, &[bob], 2).is_ok());
+    }
+
+    #[tokio::test]
+    async fn tunnel_is_closed_on_shutdown() {
+        let (_client1, socket1) = tokio::io::duplex(64);
+        let (_client2, socket2) = tokio::io::duplex(64);
+        let shutdown = Arc::new(Notify::new());
+        let notify = shutdown.clone();
+        let tunnel = tokio::spawn(async move { relay(socket1, socket2, None, None, None, &notify).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.notify_waiters();
+        let closed = tokio::time::timeout(Duration::from_secs(5), tunnel).await.unwrap().unwrap();
+        assert_eq!(closed.as_str(), "shutdown");
+    }
+}
@@ -8,9 +8,10 @@ use axum::{
     extract::{Path, Query, State},
     http::{header, HeaderValue, StatusCode, HeaderMap},
     response::{sse::Event, IntoResponse, Response, Sse},
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
     Json, Router,
 };
+use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
 use beam_lib::AppOrProxyId;
 use futures_core::{stream, Stream};
 use serde::Deserialize;
@@ -29,7 +30,7 @@ use tokio::{
 };
 use tracing::{debug, error, info, trace, warn};
 
-use crate::task_manager::TaskManager;
+use crate::task_manager::{DeliveryAttempt, NackPolicy, Redelivery, TaskManager};
 
 #[derive(Clone)]
 struct TasksState {
@@ -40,15 +41,17 @@ pub(crate) fn router() -> Router {
     let state = TasksState::default();
     Router::new()
         .route("/v1/tasks", get(get_tasks).post(post_task))
+        .route("/v1/tasks/:task_id", delete(delete_task))
         .route("/v1/tasks/:task_id/results", get(get_results_for_task))
         .route("/v1/tasks/:task_id/results/:app_id", put(put_result))
+        .route("/v1/health/tasks/:task_id/deliveries", get(delivery_attempts))
         .with_state(state)
 }
 
 impl Default for TasksState {
     fn default() -> Self {
         TasksState {
-            task_manager: TaskManager::new()
+            task_manager: TaskManager::new(config::CONFIG_CENTRAL.fifo_per_pair)
         }
     }
 }
@@ -104,6 +107,7 @@ async fn get_results_for_task_nostream(
         to: Some(msg.get_from().clone()),
         mode: MsgFilterMode::Or,
     };
+    let _slot = state.task_manager.start_long_poll(msg.get_from(), &block, config::CONFIG_CENTRAL.max_long_polls_per_recipient)?;
     let task_with_results = state.task_manager.wait_for_results(&task_id, &block, |m| filter_for_me.matches(&m.msg)).await?;
     
     DerefSerializer::new(task_with_results.msg.results.values().filter(|m| filter_for_me.matches(&m.msg)), block.wait_count).map_err(|e| {
@@ -131,12 +135,20 @@ async fn get_results_for_task_stream(
         return Err(StatusCode::UNAUTHORIZED);
     }
 
+    let slot = state.task_manager.start_long_poll(&from, &block, config::CONFIG_CENTRAL.max_long_polls_per_recipient)?;
     let filter = MsgFilterNoTask { from: None, to: Some(from), mode: MsgFilterMode::Or };
-    let stream = state.task_manager.stream_results(
+    let results = state.task_manager.stream_results(
         task_id,
         block,
         move |m| filter.matches(&m.msg)
     );
+    // Hold the slot for as long as the client listens
+    let stream = async_stream::stream! {
+        let _slot = slot;
+        for await event in results {
+            yield event;
+        }
+    };
 
     Ok(Sse::new(stream))
 }
@@ -203,9 +215,21 @@ async fn get_tasks(
             .map(std::mem::discriminant)
             .collect(),
     };
-    let tasks = state.task_manager
-        .wait_for_tasks(&block, move |m| filter.matches(m))
-        .await?;
+    let requester = msg.get_from();
+    let _slot = state.task_manager.start_long_poll(requester, &block, config::CONFIG_CENTRAL.max_long_polls_per_recipient)?;
+    let fifo = config::CONFIG_CENTRAL.fifo_per_pair;
+    let todo = unanswered_by.is_some();
+    let task_manager = &state.task_manager;
+    let tasks = task_manager
+        .wait_for_tasks(&block, move |m| {
+            filter.matches(m)
+                && (!fifo || !m.get_to().contains(requester) || task_manager.is_next_for(m, requester))
+                && (!todo || task_manager.is_redeliverable_to(m, requester))
+        })
+        .await?
+        .inspect(|task| if task.get_to().contains(requester) {
+            task_manager.mark_delivered(task, requester);
+        });
     DerefSerializer::new(tasks, block.wait_count).map_err(|e| {
         warn!("Failed to serialize tasks: {e}");
         (StatusCode::INTERNAL_SERVER_ERROR, "Failed to serialize tasks")
@@ -338,7 +362,7 @@ async fn post_task(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<TasksState>,
     msg: MsgSigned<EncryptedMsgTaskRequest>,
-) -> Result<(StatusCode, impl IntoResponse), StatusCode> {
+) -> Result<(StatusCode, impl IntoResponse), Response> {
         // let id = MsgId::new();
     // msg.id = id;
     // TODO: Check if ID is taken
@@ -347,44 +371,103 @@ async fn post_task(
         msg.msg.from, msg
     );
     let id = msg.msg.id;
-    state.task_manager.post_task(msg)?;
+    if let Some(max) = config::CONFIG_CENTRAL.store_max_bytes {
+        state.task_manager.check_storage(max).map_err(IntoResponse::into_response)?;
+    }
+    if let Some(max) = config::CONFIG_CENTRAL.max_distinct_recipients_per_sender {
+        state.task_manager
+            .check_distinct_recipients(&msg.msg.from, &msg.msg.to, max, config::CONFIG_CENTRAL.distinct_recipients_window)
+            .map_err(|e| StatusCode::from(e).into_response())?;
+    }
+    state.task_manager.post_task(msg).map_err(|e| StatusCode::from(e).into_response())?;
     Ok((
         StatusCode::CREATED,
         [(header::LOCATION, format!("/v1/tasks/{}", id))],
     ))
 }
 
+// DELETE /v1/tasks/:task_id
+async fn delete_task(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(task_id): Path<MsgId>,
+    State(state): State<TasksState>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    debug!("delete_task(task={task_id}) called by {} with IP {addr}", msg.get_from());
+    state.task_manager.cancel(&task_id, msg.get_from())?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// GET /v1/health/tasks/:task_id/deliveries
+async fn delivery_attempts(
+    Path(task_id): Path<MsgId>,
+    State(state): State<TasksState>,
+    auth: TypedHeader<Authorization<Basic>>,
+) -> Result<Json<Vec<DeliveryAttempt>>, StatusCode> {
+    let Some(ref monitoring_key) = config::CONFIG_CENTRAL.monitoring_api_key else {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+
+    if auth.password() != monitoring_key {
+        return Err(StatusCode::UNAUTHORIZED)
+    }
+
+    Ok(Json(state.task_manager.delivery_attempts(&task_id)?))
+}
+
 // PUT /v1/tasks/:task_id/results/:app_id
 async fn put_result(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Path((task_id, app_id)): Path<(MsgId, AppOrProxyId)>,
     State(state): State<TasksState>,
     result: MsgSigned<EncryptedMsgTaskResult>,
-) -> Result<StatusCode, (StatusCode, &'static str)> {
+) -> Result<StatusCode, Response> {
     trace!("Called: Task {:?}, {:?} by {addr}", task_id, result);
     if task_id != result.msg.task {
         return Err((
             StatusCode::BAD_REQUEST,
             "Task IDs supplied in path and payload do not match.",
-        ));
+        ).into_response());
     }
     let worker_id = result.msg.from.clone();
     if app_id != worker_id {
         return Err((
             StatusCode::BAD_REQUEST,
             "AppID supplied in URL and signed message do not match.",
-        ));
+        ).into_response());
     }
 
+    if let Some(max) = config::CONFIG_CENTRAL.store_max_bytes {
+        state.task_manager.check_storage(max).map_err(IntoResponse::into_response)?;
+    }
 
-    let status = if state.task_manager.put_result(&task_id, result)? {
+    let work_status = result.msg.status;
+    let status = if state.task_manager.put_result(&task_id, result).map_err(IntoResponse::into_response)? {
         StatusCode::NO_CONTENT
     } else {
         StatusCode::CREATED
     };
+    if let (WorkStatus::TempFailed, Some(policy)) = (work_status, nack_policy()) {
+        match state.task_manager.record_nack(&task_id, &worker_id, &policy) {
+            Redelivery::After(at) => debug!("Task {task_id} will be redelivered to {worker_id} at {at:?}"),
+            Redelivery::DeadLettered => warn!("Task {task_id} reached the maximum number of delivery attempts and is no longer delivered to {worker_id}"),
+        }
+    }
     Ok(status)
 }
 
+fn nack_policy() -> Option<NackPolicy> {
+    let config = &config::CONFIG_CENTRAL;
+    if config.nack_redelivery_delay.is_none() && config.max_delivery_attempts.is_none() {
+        return None;
+    }
+    Some(NackPolicy {
+        delay: config.nack_redelivery_delay.unwrap_or_default(),
+        backoff: config.nack_backoff,
+        max_attempts: config.max_delivery_attempts,
+    })
+}
+
 #[cfg(all(test, never))] // Removed until the errors down below are fixed
 mod test {
     use serde_json::Value;
@@ -1,11 +1,11 @@
 use std::{
     borrow::Cow,
     ops::Deref,
-    time::{Duration, SystemTime}, collections::HashMap, sync::Arc, convert::Infallible,
+    time::{Duration, SystemTime}, collections::{BTreeMap, HashMap, HashSet, VecDeque}, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc}, convert::Infallible,
 };
 
-use axum::{response::{IntoResponse, sse::Event, Sse}, Json, http::StatusCode};
-use dashmap::DashMap;
+use axum::{response::{IntoResponse, Response, sse::Event, Sse}, Json, http::{header, StatusCode}};
+use dashmap::{DashMap, DashSet};
 use futures_core::Stream;
 use once_cell::sync::Lazy;
 use serde::Serialize;
@@ -24,7 +24,15 @@ pub trait Task {
     fn get_results(&self) -> &HashMap<AppOrProxyId, Self::Result>;
     /// Returns true if the value as been updated and false if it was a result from a new app
     fn insert_result(&mut self, result: Self::Result) -> bool;
-    fn is_expired(&self) -> bool;
+    fn expires_at(&self) -> SystemTime;
+    /// Size of the stored results in bytes
+    fn results_size(&self) -> usize {
+        0
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at() < shared::clock::now()
+    }
 }
 
 pub trait HasStatus {
@@ -42,8 +50,12 @@ impl<State: MsgState> Task for MsgTaskRequest<State> {
         &self.results
     }
 
-    fn is_expired(&self) -> bool {
-        self.expire < SystemTime::now()
+    fn expires_at(&self) -> SystemTime {
+        self.expire
+    }
+
+    fn results_size(&self) -> usize {
+        self.results.values().map(|result| result.jwt.len()).sum()
     }
 }
 
@@ -61,8 +73,8 @@ impl<State: MsgState> Task for shared::MsgSocketRequest<State> {
 
     fn insert_result(&mut self, _result: Self::Result) -> bool { false }
 
-    fn is_expired(&self) -> bool {
-        self.expire < SystemTime::now()
+    fn expires_at(&self) -> SystemTime {
+        self.expire
     }
 }
 
@@ -83,27 +95,110 @@ pub struct TaskManager<T: HasWaitId<MsgId> + Task + Msg> {
     new_tasks: broadcast::Sender<MsgId>,
     /// Send the index at which the new result for the given Task was inserted
     new_results: DashMap<MsgId, broadcast::Sender<AppOrProxyId>>,
+    /// Tasks that have been handed out to at least one of their recipients
+    delivered: DashSet<MsgId>,
+    /// Tasks not yet handed out, per sender and recipient in submission order; only tracked if `fifo_per_pair` is set
+    pending_by_pair: DashMap<(AppOrProxyId, AppOrProxyId), BTreeMap<u64, (MsgId, SystemTime)>>,
+    submissions: AtomicU64,
+    fifo_per_pair: bool,
+    /// Recipients each sender addressed recently and until when they count against the sender's limit
+    recent_recipients: DashMap<AppOrProxyId, HashMap<AppOrProxyId, SystemTime>>,
+    /// Size of all stored tasks and their results in bytes, measured by their signed representation
+    stored_bytes: AtomicUsize,
+    /// Number of times a recipient reported a task as temporarily failed and when it may be handed out to them again, `None` once dead-lettered
+    nacked: DashMap<(MsgId, AppOrProxyId), (u32, Option<SystemTime>)>,
+    /// Number of blocking requests currently waiting per polling app or proxy
+    long_polls: DashMap<AppOrProxyId, usize>,
+    /// Latest delivery attempts per task, oldest first and at most `MAX_DELIVERY_ATTEMPTS` each
+    attempts: DashMap<MsgId, VecDeque<DeliveryAttempt>>,
 }
 
-impl<T: HasWaitId<MsgId> + Task + Msg + Send + Sync + 'static> TaskManager<T> {
-    const EXPIRE_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Upper bound of delivery attempts remembered per task; older attempts are forgotten
+pub const MAX_DELIVERY_ATTEMPTS: usize = 20;
+
+/// A task being handed out to one of its recipients or the recipient reporting it as temporarily failed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeliveryAttempt {
+    /// Seconds since the unix epoch
+    pub at: u64,
+    pub recipient: AppOrProxyId,
+    pub outcome: AttemptOutcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttemptOutcome {
+    Delivered,
+    Nacked,
+    DeadLettered,
+}
+
+/// Counts a long-poll against its recipient's limit until dropped, e.g. because the client disconnected
+pub struct LongPollSlot<T: HasWaitId<MsgId> + Task + Msg> {
+    task_manager: Arc<TaskManager<T>>,
+    recipient: AppOrProxyId,
+}
+
+impl<T: HasWaitId<MsgId> + Task + Msg> Drop for LongPollSlot<T> {
+    fn drop(&mut self) {
+        if let Some(mut waiting) = self.task_manager.long_polls.get_mut(&self.recipient) {
+            *waiting -= 1;
+        }
+        self.task_manager.long_polls.remove_if(&self.recipient, |_, waiting| *waiting == 0);
+    }
+}
+
+/// How tasks that a recipient reported as `tempfailed` are handed out to it again
+#[derive(Debug, Clone, Copy)]
+pub struct NackPolicy {
+    /// Time until a nacked task is handed out to the recipient again
+    pub delay: Duration,
+    /// Double the delay with every further nack by the same recipient
+    pub backoff: bool,
+    /// Number of nacks after which the task is no longer handed out to the recipient
+    pub max_attempts: Option<u32>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Redelivery {
+    After(SystemTime),
+    DeadLettered,
+}
+
+const EXPIRE_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
-    pub fn new() -> Arc<Self> {
+/// Size of a stored task including its results
+fn stored_size<T: Task + Msg>(task: &MsgSigned<T>) -> usize {
+    task.jwt.len() + task.msg.results_size()
+}
+
+impl<T: HasWaitId<MsgId> + Task + Msg + Send + Sync + 'static> TaskManager<T> {
+    /// With `fifo_per_pair` the order of undelivered tasks per sender and recipient is kept for [`TaskManager::is_next_for`]
+    pub fn new(fifo_per_pair: bool) -> Arc<Self> {
         let (new_tasks, _) = broadcast::channel(256);
         let task_manager = Arc::new(Self {
             tasks: Default::default(),
             new_tasks,
             new_results: Default::default(),
+            delivered: Default::default(),
+            pending_by_pair: Default::default(),
+            submissions: AtomicU64::new(0),
+            fifo_per_pair,
+            recent_recipients: Default::default(),
+            stored_bytes: AtomicUsize::new(0),
+            nacked: Default::default(),
+            long_polls: Default::default(),
+            attempts: Default::default(),
         });
         let tm = Arc::clone(&task_manager);
         std::thread::spawn(move || {
             loop {
-                std::thread::sleep(Self::EXPIRE_CHECK_INTERVAL);
-                tm.tasks.retain(|_, task| if task.msg.is_expired() {
-                    tm.new_results.remove(&task.msg.wait_id());
-                    false
-                } else {
-                    true
+                std::thread::sleep(EXPIRE_CHECK_INTERVAL);
+                tm.remove_expired();
+                let now = shared::clock::now();
+                tm.recent_recipients.retain(|_, recipients| {
+                    recipients.retain(|_, until| *until > now);
+                    !recipients.is_empty()
                 });
                 // If the memory footprint of the Dashmap will get too large we might need to consider calling DashMap::shrink_to_fit or find a better solution as
                 // this would need to lock the whole map making it inaccessible until everything is reallocated
@@ -121,7 +216,186 @@ impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T> {
     }
 
     pub fn remove(&self, task_id: &MsgId) -> Result<MsgSigned<T>, TaskManagerError> {
-        self.tasks.remove(task_id).ok_or(TaskManagerError::NotFound).map(|v| v.1)
+        let (_, task) = self.tasks.remove(task_id).ok_or(TaskManagerError::NotFound)?;
+        self.new_results.remove(task_id);
+        self.delivered.remove(task_id);
+        self.forget_pending(&task);
+        self.nacked.retain(|(nacked_id, _), _| nacked_id != task_id);
+        self.attempts.remove(task_id);
+        self.stored_bytes.fetch_sub(stored_size(&task), Ordering::Relaxed);
+        Ok(task)
+    }
+
+    /// Drops all expired tasks
+    pub fn remove_expired(&self) {
+        let mut expired = HashSet::new();
+        self.tasks.retain(|_, task| if task.msg.is_expired() {
+            self.new_results.remove(&task.msg.wait_id());
+            self.delivered.remove(&task.msg.wait_id());
+            self.forget_pending(task);
+            self.stored_bytes.fetch_sub(stored_size(task), Ordering::Relaxed);
+            expired.insert(task.msg.wait_id());
+            false
+        } else {
+            true
+        });
+        // Not looked up in `tasks` while holding these locks, as delivering a task locks them while holding it
+        self.nacked.retain(|(id, _), _| !expired.contains(id));
+        self.attempts.retain(|id, _| !expired.contains(id));
+    }
+
+    /// Rejects new submissions once the stored tasks and results take up `max_bytes` or more.
+    /// Stored messages are still delivered and space is freed again as they expire.
+    pub fn check_storage(&self, max_bytes: usize) -> Result<(), TaskManagerError> {
+        if self.stored_bytes.load(Ordering::Relaxed) >= max_bytes {
+            return Err(TaskManagerError::StorageFull);
+        }
+        Ok(())
+    }
+
+    /// Records that a task has been handed out to one of its recipients so it can no longer be canceled.
+    pub fn mark_delivered(&self, task: &MsgSigned<T>, recipient: &AppOrProxyId) {
+        let id = task.wait_id();
+        self.delivered.insert(id);
+        let pair = (task.get_from().clone(), recipient.clone());
+        if let Some(mut pending) = self.pending_by_pair.get_mut(&pair) {
+            pending.retain(|_, (pending_id, _)| *pending_id != id);
+        }
+        self.pending_by_pair.remove_if(&pair, |_, pending| pending.is_empty());
+        self.record_attempt(id, recipient, AttemptOutcome::Delivered);
+    }
+
+    fn record_attempt(&self, task_id: MsgId, recipient: &AppOrProxyId, outcome: AttemptOutcome) {
+        let at = shared::clock::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut attempts = self.attempts.entry(task_id).or_default();
+        if attempts.len() >= MAX_DELIVERY_ATTEMPTS {
+            attempts.pop_front();
+        }
+        attempts.push_back(DeliveryAttempt { at, recipient: recipient.clone(), outcome });
+    }
+
+    /// Latest delivery attempts of a task, oldest first
+    pub fn delivery_attempts(&self, task_id: &MsgId) -> Result<Vec<DeliveryAttempt>, TaskManagerError> {
+        self.get(task_id)?;
+        Ok(self.attempts.get(task_id).map(|attempts| attempts.iter().cloned().collect()).unwrap_or_default())
+    }
+
+    /// Records that `recipient` reported the task as temporarily failed and decides when it is handed out to them again.
+    pub fn record_nack(&self, task_id: &MsgId, recipient: &AppOrProxyId, policy: &NackPolicy) -> Redelivery {
+        let mut nack = self.nacked.entry((*task_id, recipient.clone())).or_insert((0, None));
+        nack.0 += 1;
+        let attempts = nack.0;
+        if policy.max_attempts.is_some_and(|max| attempts >= max) {
+            nack.1 = None;
+            drop(nack);
+            self.record_attempt(*task_id, recipient, AttemptOutcome::DeadLettered);
+            return Redelivery::DeadLettered;
+        }
+        let delay = if policy.backoff {
+            policy.delay.saturating_mul(2u32.saturating_pow(attempts - 1))
+        } else {
+            policy.delay
+        };
+        let redeliver_at = shared::clock::now() + delay;
+        nack.1 = Some(redeliver_at);
+        drop(nack);
+        self.record_attempt(*task_id, recipient, AttemptOutcome::Nacked);
+        Redelivery::After(redeliver_at)
+    }
+
+    /// Returns false while a task nacked by `recipient` waits for redelivery to them or once it has been dead-lettered.
+    pub fn is_redeliverable_to(&self, task: &T, recipient: &AppOrProxyId) -> bool {
+        match self.nacked.get(&(task.wait_id(), recipient.clone())) {
+            Some(nack) => nack.1.is_some_and(|redeliver_at| redeliver_at <= shared::clock::now()),
+            None => true,
+        }
+    }
+
+    /// Returns false if an earlier task from the same sender to `recipient` has not been delivered yet.
+    /// Earlier tasks that expired before being delivered do not hold back later ones.
+    pub fn is_next_for(&self, task: &T, recipient: &AppOrProxyId) -> bool {
+        let Some(pending) = self.pending_by_pair.get(&(task.get_from().clone(), recipient.clone())) else {
+            return true;
+        };
+        let id = task.wait_id();
+        if !pending.values().any(|(pending_id, _)| *pending_id == id) {
+            return true;
+        }
+        let now = shared::clock::now();
+        match pending.values().find(|(_, expire)| *expire >= now) {
+            Some((head, _)) => *head == id,
+            None => true,
+        }
+    }
+
+    fn forget_pending(&self, task: &MsgSigned<T>) {
+        let id = task.wait_id();
+        for recipient in task.get_to() {
+            let pair = (task.get_from().clone(), recipient.clone());
+            if let Some(mut pending) = self.pending_by_pair.get_mut(&pair) {
+                pending.retain(|_, (pending_id, _)| *pending_id != id);
+            }
+            self.pending_by_pair.remove_if(&pair, |_, pending| pending.is_empty());
+        }
+    }
+
+    /// Rejects messages to new recipients once `sender` has addressed `max` distinct recipients within `window`.
+    /// Recipients already addressed within the window can still be used and are remembered for another `window`.
+    pub fn check_distinct_recipients(&self, sender: &AppOrProxyId, to: &[AppOrProxyId], max: usize, window: Duration) -> Result<(), TaskManagerError> {
+        let now = shared::clock::now();
+        let mut recipients = self.recent_recipients.entry(sender.clone()).or_default();
+        recipients.retain(|_, until| *until > now);
+        let new_recipients: HashSet<_> = to.iter().filter(|to| !recipients.contains_key(*to)).collect();
+        if recipients.len() + new_recipients.len() > max {
+            return Err(TaskManagerError::TooManyRecipients);
+        }
+        for recipient in to {
+            recipients.insert(recipient.clone(), now + window);
+        }
+        Ok(())
+    }
+
+    /// Counts a request of `recipient` that blocks according to `block` until the returned slot is dropped.
+    /// Rejects it if the recipient already has `max` such requests waiting. Requests that do not block are not counted.
+    pub fn start_long_poll(self: &Arc<Self>, recipient: &AppOrProxyId, block: &HowLongToBlock, max: Option<usize>) -> Result<Option<LongPollSlot<T>>, TaskManagerError> {
+        let Some(max) = max else {
+            return Ok(None);
+        };
+        if block.wait_count.is_none() && block.wait_time.is_none() {
+            return Ok(None);
+        }
+        let mut waiting = self.long_polls.entry(recipient.clone()).or_default();
+        if *waiting >= max {
+            warn!("Rejecting long-poll of {recipient}: already {max} waiting");
+            return Err(TaskManagerError::TooManyLongPolls);
+        }
+        *waiting += 1;
+        Ok(Some(LongPollSlot { task_manager: self.clone(), recipient: recipient.clone() }))
+    }
+
+    /// Removes a task before any of its recipients has seen it.
+    /// Only the sender of the task may cancel it.
+    pub fn cancel(&self, task_id: &MsgId, requester: &AppOrProxyId) -> Result<MsgSigned<T>, TaskManagerError> {
+        let mut result = Err(TaskManagerError::NotFound);
+        // Checking inside remove_if holds the shard lock so the task can not be delivered in between
+        let removed = self.tasks.remove_if(task_id, |_, task| {
+            result = if task.get_from() != requester {
+                Err(TaskManagerError::Forbidden)
+            } else if self.delivered.contains(task_id) || !task.msg.get_results().is_empty() {
+                Err(TaskManagerError::Delivered)
+            } else {
+                Ok(())
+            };
+            result.is_ok()
+        });
+        result?;
+        self.new_results.remove(task_id);
+        self.nacked.retain(|(nacked_id, _), _| nacked_id != task_id);
+        self.attempts.remove(task_id);
+        let (_, task) = removed.expect("Task was removed as the checks passed");
+        self.forget_pending(&task);
+        self.stored_bytes.fetch_sub(stored_size(&task), Ordering::Relaxed);
+        Ok(task)
     }
 
     pub fn get_tasks_by(&self, filter: impl Fn(&T) -> bool) -> impl Iterator<Item = impl Deref<Target = MsgSigned<T>> + '_> {
@@ -176,9 +450,24 @@ impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T> {
             if !task.msg.is_expired() {
                 return Err(TaskManagerError::Conflict);
             }
+            self.forget_pending(&task);
+            self.nacked.retain(|(nacked_id, _), _| *nacked_id != id);
+            self.attempts.remove(&id);
         }
         let max_receivers = task.get_to().len();
-        self.tasks.insert(id.clone(), task);
+        if self.fifo_per_pair {
+            let submission = self.submissions.fetch_add(1, Ordering::Relaxed);
+            for recipient in task.get_to() {
+                self.pending_by_pair
+                    .entry((task.get_from().clone(), recipient.clone()))
+                    .or_default()
+                    .insert(submission, (id, task.msg.expires_at()));
+            }
+        }
+        self.stored_bytes.fetch_add(stored_size(&task), Ordering::Relaxed);
+        if let Some(replaced) = self.tasks.insert(id.clone(), task) {
+            self.stored_bytes.fetch_sub(stored_size(&replaced), Ordering::Relaxed);
+        }
         // Create a large enough buffer that all receivers can at least create one claimed result and a successfull result
         // while the receiver channel is not being polled filling up the buffer and causing the channel to lag
         let (results_sender, _) = broadcast::channel(1.max(max_receivers) * 2);
@@ -348,7 +637,10 @@ where
             return Err(TaskManagerError::Unauthorized);
         }
         let sender = result.get_from().clone();
+        let size_before = task.msg.results_size();
         let is_updated = task.msg.insert_result(result);
+        self.stored_bytes.fetch_add(task.msg.results_size(), Ordering::Relaxed);
+        self.stored_bytes.fetch_sub(size_before, Ordering::Relaxed);
         // We dont care if noone is listening
         _ = self
             .new_results
@@ -366,6 +658,11 @@ pub enum TaskManagerError {
     NotFound,
     Conflict,
     Unauthorized,
+    Forbidden,
+    Delivered,
+    TooManyRecipients,
+    TooManyLongPolls,
+    StorageFull,
     Gone,
     BroadcastBufferOverflow,
 }
@@ -376,6 +673,11 @@ impl TaskManagerError {
             TaskManagerError::NotFound => "Task not found",
             TaskManagerError::Conflict => "Task already exists",
             TaskManagerError::Unauthorized => "Unauthorized to access this task",
+            TaskManagerError::Forbidden => "Only the sender of a task can cancel it",
+            TaskManagerError::Delivered => "Task has already been delivered",
+            TaskManagerError::TooManyRecipients => "Sender has addressed too many distinct recipients recently",
+            TaskManagerError::TooManyLongPolls => "Too many requests of this recipient are already waiting",
+            TaskManagerError::StorageFull => "Broker storage is full, retry later",
             TaskManagerError::Gone => "Task expired while waiting on it",
             TaskManagerError::BroadcastBufferOverflow => "Internal server error",
         }
@@ -396,11 +698,30 @@ impl From<TaskManagerError> for StatusCode {
             TaskManagerError::Conflict => StatusCode::CONFLICT,
             TaskManagerError::BroadcastBufferOverflow => StatusCode::INTERNAL_SERVER_ERROR,
             TaskManagerError::Unauthorized => StatusCode::UNAUTHORIZED,
+            TaskManagerError::Forbidden => StatusCode::FORBIDDEN,
+            TaskManagerError::Delivered => StatusCode::CONFLICT,
+            TaskManagerError::TooManyRecipients => StatusCode::TOO_MANY_REQUESTS,
+            TaskManagerError::TooManyLongPolls => StatusCode::TOO_MANY_REQUESTS,
+            TaskManagerError::StorageFull => StatusCode::INSUFFICIENT_STORAGE,
             TaskManagerError::Gone => StatusCode::GONE,
         }
     }
 }
 
+impl IntoResponse for TaskManagerError {
+    fn into_response(self) -> Response {
+        match self {
+            // Space is freed at the latest when the next expired tasks are dropped
+            TaskManagerError::StorageFull => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                [(header::RETRY_AFTER, EXPIRE_CHECK_INTERVAL.as_secs().to_string())],
+                self.error_msg(),
+            ).into_response(),
+            other => <(StatusCode, &str)>::from(other).into_response(),
+        }
+    }
+}
+
 fn to_event(json: impl Serialize, event_type: impl AsRef<str>) -> Event {
     Event::default().event(event_type).json_data(json).unwrap_or_else(|e| {
         error!("Unable to serialize message: {e}");
@@ -409,3 +730,273 @@ fn to_event(json: impl Serialize, event_type: impl AsRef<str>) -> Event {
             .data("Internal error: Unable to serialize message.")
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use beam_lib::{set_broker_id, AppId, FailureStrategy};
+    use serde_json::Value;
+    use shared::{Encrypted, EncryptedMsgTaskRequest};
+
+    use super::*;
+
+    fn app(name: &str) -> AppOrProxyId {
+        set_broker_id("broker.samply.de".to_string());
+        AppOrProxyId::App(AppId::new(format!("{name}.proxy1.broker.samply.de")).unwrap())
+    }
+
+    fn task(from: &AppOrProxyId, to: &AppOrProxyId) -> MsgSigned<EncryptedMsgTaskRequest> {
+        MsgSigned {
+            msg: MsgTaskRequest {
+                id: MsgId::new(),
+                from: from.clone(),
+                to: vec![to.clone()],
+                body: Encrypted::default(),
+                expire: SystemTime::now() + Duration::from_secs(60),
+                failure_strategy: FailureStrategy::Discard,
+                results: HashMap::new(),
+                metadata: Value::Null,
+            },
+            jwt: String::new(),
+        }
+    }
+
+    #[test]
+    fn cancel_before_delivery() {
+        let (sender, receiver) = (app("app1"), app("app2"));
+        let tm = TaskManager::new(false);
+        let task = task(&sender, &receiver);
+        let id = task.msg.id;
+        tm.post_task(task).unwrap();
+        assert!(tm.cancel(&id, &sender).is_ok());
+        assert!(matches!(tm.get(&id), Err(TaskManagerError::NotFound)));
+        assert!(matches!(tm.cancel(&id, &sender), Err(TaskManagerError::NotFound)));
+    }
+
+    #[test]
+    fn cancel_after_delivery() {
+        let (sender, receiver) = (app("app1"), app("app2"));
+        let tm = TaskManager::new(false);
+        let task = task(&sender, &receiver);
+        let id = task.msg.id;
+        tm.post_task(task).unwrap();
+        tm.mark_delivered(&tm.get(&id).unwrap(), &receiver);
+        assert!(matches!(tm.cancel(&id, &sender), Err(TaskManagerError::Delivered)));
+        assert!(tm.get(&id).is_ok());
+    }
+
+    #[test]
+    fn cancel_by_non_sender() {
+        let (sender, receiver) = (app("app1"), app("app2"));
+        let tm = TaskManager::new(false);
+        let task = task(&sender, &receiver);
+        let id = task.msg.id;
+        tm.post_task(task).unwrap();
+        assert!(matches!(tm.cancel(&id, &receiver), Err(TaskManagerError::Forbidden)));
+        assert!(tm.get(&id).is_ok());
+    }
+
+    #[test]
+    fn fifo_per_pair() {
+        let (sender, receiver) = (app("app1"), app("app2"));
+        let tm = TaskManager::new(true);
+        let mut expired = task(&sender, &receiver);
+        expired.msg.expire = SystemTime::now() - Duration::from_secs(1);
+        let (first, second) = (task(&sender, &receiver), task(&sender, &receiver));
+        let other_sender = task(&receiver, &receiver);
+        let ids = [&expired, &first, &second, &other_sender].map(|t| t.msg.id);
+        for task in [expired, first, second, other_sender] {
+            tm.post_task(task).unwrap();
+        }
+        let is_next = |id| tm.is_next_for(&tm.get(id).unwrap().msg, &receiver);
+        assert!(is_next(&ids[1]), "An expired task must not hold back later ones");
+        assert!(!is_next(&ids[2]));
+        assert!(is_next(&ids[3]), "Tasks from other senders are independent");
+        tm.mark_delivered(&tm.get(&ids[1]).unwrap(), &receiver);
+        assert!(is_next(&ids[1]));
+        assert!(is_next(&ids[2]));
+    }
+
+    #[test]
+    fn pending_is_only_tracked_for_fifo() {
+        let (sender, receiver) = (app("app1"), app("app2"));
+        let tm = TaskManager::new(false);
+        tm.post_task(task(&sender, &receiver)).unwrap();
+        assert!(tm.pending_by_pair.is_empty());
+    }
+
+    #[cfg(feature = "sockets")]
+    #[test]
+    fn removed_socket_task_is_not_pending() {
+        let (sender, receiver) = (app("app1"), app("app2"));
+        let tm = TaskManager::new(true);
+        let socket_task = MsgSigned {
+            msg: shared::MsgSocketRequest {
+                from: sender,
+                to: vec![receiver],
+                expire: SystemTime::now() + Duration::from_secs(60),
+                id: MsgId::new(),
+                secret: Encrypted::default(),
+                metadata: Value::Null,
+            },
+            jwt: String::new(),
+        };
+        let id = socket_task.msg.id;
+        tm.post_task(socket_task).unwrap();
+        assert!(!tm.pending_by_pair.is_empty());
+        // Socket requests are fetched without being marked as delivered and removed once connected
+        tm.remove(&id).unwrap();
+        assert!(tm.pending_by_pair.is_empty());
+    }
+
+    #[test]
+    fn task_expires_with_mock_clock() {
+        let clock = shared::clock::MockClock::new(SystemTime::now());
+        shared::clock::with_clock(clock.clone(), || {
+            let task = task(&app("app1"), &app("app2"));
+            assert!(!task.msg.is_expired());
+            clock.advance(Duration::from_secs(61));
+            assert!(task.msg.is_expired());
+        });
+    }
+
+    #[test]
+    fn long_polls_are_limited_per_recipient() {
+        let (greedy, other) = (app("app1"), app("app2"));
+        let tm = TaskManager::<EncryptedMsgTaskRequest>::new(false);
+        let block = HowLongToBlock { wait_time: Some(Duration::from_secs(10)), wait_count: None };
+        let first = tm.start_long_poll(&greedy, &block, Some(2)).unwrap();
+        let _second = tm.start_long_poll(&greedy, &block, Some(2)).unwrap();
+        assert!(matches!(tm.start_long_poll(&greedy, &block, Some(2)), Err(TaskManagerError::TooManyLongPolls)));
+        // Other recipients poll freely, as do requests that return immediately
+        assert!(tm.start_long_poll(&other, &block, Some(2)).unwrap().is_some());
+        let no_wait = HowLongToBlock { wait_time: None, wait_count: None };
+        assert!(tm.start_long_poll(&greedy, &no_wait, Some(2)).unwrap().is_none());
+        // A disconnecting client frees its slot
+        drop(first);
+        assert!(tm.start_long_poll(&greedy, &block, Some(2)).is_ok());
+    }
+
+    #[test]
+    fn distinct_recipients_per_sender() {
+        let sender = app("sender");
+        let tm = TaskManager::<EncryptedMsgTaskRequest>::new(false);
+        let window = Duration::from_secs(60);
+        let recipients: Vec<_> = (0..4).map(|i| app(&format!("app{i}"))).collect();
+        for recipient in &recipients[..3] {
+            assert!(tm.check_distinct_recipients(&sender, std::slice::from_ref(recipient), 3, window).is_ok());
+        }
+        assert!(matches!(
+            tm.check_distinct_recipients(&sender, &recipients[3..], 3, window),
+            Err(TaskManagerError::TooManyRecipients)
+        ));
+        // Known recipients and other senders are unaffected
+        assert!(tm.check_distinct_recipients(&sender, &recipients[..3], 3, window).is_ok());
+        assert!(tm.check_distinct_recipients(&app("other"), &recipients[3..], 3, window).is_ok());
+    }
+
+    #[test]
+    fn nacked_task_is_redelivered_after_delay() {
+        let (sender, receiver, other) = (app("app1"), app("app2"), app("app3"));
+        let clock = shared::clock::MockClock::new(SystemTime::now());
+        shared::clock::with_clock(clock.clone(), || {
+            let tm = TaskManager::new(false);
+            let task = task(&sender, &receiver);
+            let id = task.msg.id;
+            tm.post_task(task).unwrap();
+            let policy = NackPolicy { delay: Duration::from_secs(10), backoff: true, max_attempts: Some(3) };
+            let redeliverable = |to| tm.is_redeliverable_to(&tm.get(&id).unwrap().msg, to);
+
+            assert!(redeliverable(&receiver));
+            assert!(matches!(tm.record_nack(&id, &receiver, &policy), Redelivery::After(_)));
+            assert!(!redeliverable(&receiver));
+            assert!(redeliverable(&other), "Nacks only delay redelivery to the nacking recipient");
+            clock.advance(Duration::from_secs(10));
+            assert!(redeliverable(&receiver));
+
+            // The delay doubles with every nack
+            tm.record_nack(&id, &receiver, &policy);
+            clock.advance(Duration::from_secs(19));
+            assert!(!redeliverable(&receiver));
+            clock.advance(Duration::from_secs(1));
+            assert!(redeliverable(&receiver));
+
+            assert_eq!(tm.record_nack(&id, &receiver, &policy), Redelivery::DeadLettered);
+            clock.advance(Duration::from_secs(3600));
+            assert!(!redeliverable(&receiver));
+        });
+    }
+
+    #[test]
+    fn delivery_attempts_are_recorded_and_capped() {
+        let (sender, receiver) = (app("app1"), app("app2"));
+        let tm = TaskManager::new(false);
+        let (task, mut expired) = (task(&sender, &receiver), task(&sender, &receiver));
+        let id = task.msg.id;
+        tm.post_task(task).unwrap();
+        assert!(tm.delivery_attempts(&id).unwrap().is_empty());
+
+        let policy = NackPolicy { delay: Duration::ZERO, backoff: false, max_attempts: None };
+        tm.mark_delivered(&tm.get(&id).unwrap(), &receiver);
+        tm.record_nack(&id, &receiver, &policy);
+        let attempts = tm.delivery_attempts(&id).unwrap();
+        assert_eq!(attempts.iter().map(|a| a.outcome).collect::<Vec<_>>(), [AttemptOutcome::Delivered, AttemptOutcome::Nacked]);
+        assert!(attempts.iter().all(|a| a.recipient == receiver));
+
+        for _ in 0..MAX_DELIVERY_ATTEMPTS {
+            tm.mark_delivered(&tm.get(&id).unwrap(), &receiver);
+        }
+        let attempts = tm.delivery_attempts(&id).unwrap();
+        assert_eq!(attempts.len(), MAX_DELIVERY_ATTEMPTS);
+        assert!(attempts.iter().all(|a| a.outcome == AttemptOutcome::Delivered), "The oldest attempts are forgotten first");
+
+        tm.remove(&id).unwrap();
+        assert!(matches!(tm.delivery_attempts(&id), Err(TaskManagerError::NotFound)));
+
+        expired.msg.expire = SystemTime::now() - Duration::from_secs(1);
+        let id = expired.msg.id;
+        tm.post_task(expired).unwrap();
+        tm.mark_delivered(&tm.get(&id).unwrap(), &receiver);
+        tm.remove_expired();
+        assert!(tm.attempts.is_empty());
+    }
+
+    #[test]
+    fn nacks_of_expired_tasks_are_forgotten() {
+        let (sender, receiver) = (app("app1"), app("app2"));
+        let tm = TaskManager::new(false);
+        let (mut expired, kept) = (task(&sender, &receiver), task(&sender, &receiver));
+        expired.msg.expire = SystemTime::now() - Duration::from_secs(1);
+        let ids = [expired.msg.id, kept.msg.id];
+        tm.post_task(expired).unwrap();
+        tm.post_task(kept).unwrap();
+        let policy = NackPolicy { delay: Duration::from_secs(10), backoff: false, max_attempts: None };
+        for id in &ids {
+            tm.record_nack(id, &receiver, &policy);
+        }
+        tm.remove_expired();
+        assert!(tm.nacked.iter().map(|nack| nack.key().0).eq([ids[1]]));
+    }
+
+    #[test]
+    fn storage_high_water_mark() {
+        let (sender, receiver) = (app("app1"), app("app2"));
+        let tm = TaskManager::new(false);
+        let sized_task = |expire| {
+            let mut task = task(&sender, &receiver);
+            task.msg.expire = expire;
+            task.jwt = "x".repeat(100);
+            task
+        };
+        let max = 250;
+        tm.post_task(sized_task(SystemTime::now() - Duration::from_secs(1))).unwrap();
+        tm.post_task(sized_task(SystemTime::now() + Duration::from_secs(60))).unwrap();
+        assert!(tm.check_storage(max).is_ok());
+        tm.post_task(sized_task(SystemTime::now() + Duration::from_secs(60))).unwrap();
+        assert!(matches!(tm.check_storage(max), Err(TaskManagerError::StorageFull)));
+        assert_eq!(StatusCode::from(TaskManagerError::StorageFull), StatusCode::INSUFFICIENT_STORAGE);
+        assert!(TaskManagerError::StorageFull.into_response().headers().contains_key(header::RETRY_AFTER));
+
+        tm.remove_expired();
+        assert!(tm.check_storage(max).is_ok());
+    }
+}
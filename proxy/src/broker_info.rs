@@ -0,0 +1,175 @@
+use std::{future::Future, sync::RwLock, time::Duration};
+
+use axum::http::{header, HeaderValue, StatusCode};
+use shared::{capabilities::Capabilities, config, config_proxy::Config, errors::SamplyBeamError, http_client::SamplyHttpClient, reqwest};
+use tracing::{debug, error, info, warn};
+
+/// Upper bound of the backoff between attempts to fetch the broker's capabilities
+const MAX_REFETCH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// What the broker advertised on its `/v1/info` endpoint
+static BROKER_INFO: RwLock<BrokerInfo> = RwLock::new(BrokerInfo { msg_versions: None, mismatched_id: None });
+
+struct BrokerInfo {
+    /// Supported message versions. `None` until they could be fetched.
+    msg_versions: Option<Vec<u32>>,
+    /// The id the broker reported if it is not the one derived from `--broker-url`
+    mismatched_id: Option<String>,
+}
+
+/// Fetches the broker's supported message versions and caches them.
+/// On failure the previously cached versions are kept.
+pub(crate) async fn refresh(config: &Config, client: &SamplyHttpClient) {
+    match fetch_capabilities(config, client).await {
+        Ok(caps) => adopt(&BROKER_INFO, caps),
+        Err(e) => warn!("Unable to fetch the broker's supported message versions: {e}"),
+    }
+}
+
+/// Keeps fetching the broker's capabilities in the background until they are known, backing off from `--broker-info-retry-interval`
+pub(crate) fn spawn_refetching(config: Config, client: SamplyHttpClient) {
+    tokio::spawn(async move {
+        let fetch = || fetch_capabilities(&config, &client);
+        refetch_until_known(fetch, &BROKER_INFO, config.broker_info_retry_interval, MAX_REFETCH_INTERVAL).await;
+    });
+}
+
+async fn refetch_until_known<F, Fut>(mut fetch: F, info: &RwLock<BrokerInfo>, initial: Duration, max: Duration)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Capabilities, SamplyBeamError>>,
+{
+    let mut delay = initial;
+    while info.read().unwrap().msg_versions.is_none() {
+        tokio::time::sleep(delay).await;
+        match fetch().await {
+            Ok(caps) => adopt(info, caps),
+            Err(e) => debug!("Still unable to fetch the broker's capabilities; retrying in {:?}: {e}", (delay * 2).min(max)),
+        }
+        delay = (delay * 2).min(max);
+    }
+}
+
+fn adopt(info: &RwLock<BrokerInfo>, caps: Capabilities) {
+    let mut info = info.write().unwrap();
+    if info.msg_versions.as_ref() != Some(&caps.msg_versions) {
+        info!("Broker supports message versions {:?} (previously {:?})", caps.msg_versions, info.msg_versions);
+    }
+    info.msg_versions = Some(caps.msg_versions);
+    info.mismatched_id = caps.broker_id.filter(|reported| reported != beam_lib::get_broker_id());
+    if let Some(reported) = &info.mismatched_id {
+        error!("Broker at --broker-url reports id {reported} instead of {}; refusing to submit or poll messages until this is resolved", beam_lib::get_broker_id());
+    }
+}
+
+async fn fetch_capabilities(config: &Config, client: &SamplyHttpClient) -> Result<Capabilities, SamplyBeamError> {
+    let uri = config.broker_uri
+        .join("/v1/info")
+        .expect("Uri to be constructed correctly");
+    let resp = client
+        .get(uri)
+        .header(header::USER_AGENT, HeaderValue::from_static(env!("SAMPLY_USER_AGENT")))
+        .send()
+        .await?;
+    check_broker_tls(config, &resp)?;
+    if resp.status() != StatusCode::OK {
+        return Err(SamplyBeamError::InternalSynchronizationError(format!(
+            "Unexpected reply from Broker, received status code {}",
+            resp.status()
+        )));
+    }
+    serde_json::from_slice(&resp.bytes().await?).map_err(|e| SamplyBeamError::JsonParseError(e.to_string()))
+}
+
+/// Rejects messages of a version the broker is known not to handle.
+/// If the broker's versions are unknown, e.g. because it predates `/v1/info`, every version is let through.
+pub(crate) fn check_msg_version(version: u32) -> Result<(), SamplyBeamError> {
+    check_supported(BROKER_INFO.read().unwrap().msg_versions.as_deref(), version)
+}
+
+/// Rejects talking to a broker that reported another id than the one derived from `--broker-url`,
+/// e.g. because of hijacked DNS or a misrouting load balancer. Brokers that do not report their id are trusted.
+pub(crate) fn check_broker_id() -> Result<(), SamplyBeamError> {
+    check_reported_id(&BROKER_INFO.read().unwrap())
+}
+
+/// Checks the TLS certificate the broker presented on `resp` against the broker domain and `--pinned-broker-cert-sha256`.
+/// This runs on every response, as each one may have come over a new connection.
+pub(crate) fn check_broker_tls(config: &Config, resp: &reqwest::Response) -> Result<(), SamplyBeamError> {
+    let cert = resp.extensions().get::<reqwest::tls::TlsInfo>().and_then(|tls| tls.peer_certificate());
+    shared::crypto::check_broker_certificate(cert, &config::CONFIG_SHARED.broker_domain, config.pinned_broker_cert_sha256.as_deref())
+}
+
+fn check_reported_id(info: &BrokerInfo) -> Result<(), SamplyBeamError> {
+    match &info.mismatched_id {
+        Some(reported) => Err(SamplyBeamError::BrokerIdMismatch {
+            expected: beam_lib::get_broker_id().clone(),
+            reported: reported.clone(),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn check_supported(supported: Option<&[u32]>, version: u32) -> Result<(), SamplyBeamError> {
+    match supported {
+        Some(supported) if !supported.contains(&version) => Err(SamplyBeamError::UnsupportedMsgVersion {
+            version,
+            supported: supported.to_vec(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use shared::capabilities::{self, MSG_VERSION};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn capabilities_are_adopted_once_info_is_reachable() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let info = RwLock::new(BrokerInfo { msg_versions: None, mismatched_id: None });
+        let attempts = &AtomicUsize::new(0);
+        let fetch = move || async move {
+            if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                Err(SamplyBeamError::InternalSynchronizationError("/v1/info is down".into()))
+            } else {
+                Ok(capabilities::capabilities())
+            }
+        };
+        let refetching = refetch_until_known(fetch, &info, Duration::from_millis(1), Duration::from_millis(5));
+        tokio::time::timeout(Duration::from_secs(5), refetching).await.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(info.read().unwrap().msg_versions.as_deref(), Some(&[MSG_VERSION][..]));
+    }
+
+    #[test]
+    fn broker_of_another_federation_is_refused() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let info = RwLock::new(BrokerInfo { msg_versions: None, mismatched_id: None });
+        let reporting = |broker_id: Option<&str>| Capabilities { broker_id: broker_id.map(ToOwned::to_owned), ..capabilities::capabilities() };
+
+        adopt(&info, reporting(Some("broker.evil.example.com")));
+        let err = check_reported_id(&info.read().unwrap()).unwrap_err();
+        assert!(matches!(&err, SamplyBeamError::BrokerIdMismatch { reported, .. } if reported == "broker.evil.example.com"));
+        assert_eq!(err.code(), "broker_id_mismatch");
+
+        // Once the right broker answers again, messages are let through
+        adopt(&info, reporting(Some("broker.samply.de")));
+        assert!(check_reported_id(&info.read().unwrap()).is_ok());
+        // As are messages to older brokers that do not report their id
+        adopt(&info, reporting(None));
+        assert!(check_reported_id(&info.read().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn older_broker_version_is_rejected() {
+        let err = check_supported(Some(&[MSG_VERSION - 1]), MSG_VERSION).unwrap_err();
+        assert!(matches!(err, SamplyBeamError::UnsupportedMsgVersion { version: MSG_VERSION, .. }));
+        assert!(check_supported(Some(&[MSG_VERSION - 1, MSG_VERSION]), MSG_VERSION).is_ok());
+        assert!(check_supported(None, MSG_VERSION).is_ok());
+    }
+}
@@ -36,7 +36,9 @@ impl GetCertsFromBroker {
         let req = sign_request(body, parts, &self.config, Some(&self.crypto_conf))
             .await
             .map_err(|(_, msg)| SamplyBeamError::SignEncryptError(msg.into()))?;
-        Ok(self.client.execute(req).await?.into())
+        let resp = self.client.execute(req).await?;
+        crate::broker_info::check_broker_tls(&self.config, &resp)?;
+        Ok(resp)
     }
 
     async fn query(&self, path: &str) -> Result<String, SamplyBeamError> {
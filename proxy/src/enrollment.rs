@@ -0,0 +1,151 @@
+use std::time::{Duration, SystemTime};
+
+use beam_lib::ProxyId;
+use serde::Serialize;
+use shared::{config_proxy::Config, config_shared::KeySummary, crypto::{self, CryptoPublicPortion}, errors::SamplyBeamError, http_client::SamplyHttpClient};
+
+/// Machine-readable answer to `--enrollment-status`
+#[derive(Debug, Serialize)]
+pub(crate) struct EnrollmentStatus {
+    proxy_id: ProxyId,
+    /// Whether the private key could be loaded
+    private_key: bool,
+    /// Kind of the loaded private key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<KeySummary>,
+    /// Newest certificate of this proxy matching the private key, if any
+    certificate: Option<CertificateStatus>,
+    /// A private key and a currently valid certificate matching it are present
+    enrolled: bool,
+    /// Why the status could not be determined, e.g. an unreachable broker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CertificateStatus {
+    serial: String,
+    /// Start of the validity window in seconds since the unix epoch
+    valid_since: u64,
+    /// End of the validity window in seconds since the unix epoch
+    valid_until: u64,
+}
+
+impl CertificateStatus {
+    fn from_cert(public: &CryptoPublicPortion) -> Result<Self, SamplyBeamError> {
+        let unix_secs = |time| -> Result<u64, SamplyBeamError> {
+            let time = crypto::asn1_time_to_system_time(time)?;
+            Ok(time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs())
+        };
+        Ok(Self {
+            serial: public.serial()?,
+            valid_since: unix_secs(public.cert.not_before())?,
+            valid_until: unix_secs(public.cert.not_after())?,
+        })
+    }
+
+    fn is_valid_at(&self, now: SystemTime) -> bool {
+        let now = now.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        Duration::from_secs(self.valid_since) <= now && now < Duration::from_secs(self.valid_until)
+    }
+}
+
+impl EnrollmentStatus {
+    fn new(proxy_id: ProxyId, key: Option<KeySummary>, certificate: Option<CertificateStatus>, now: SystemTime) -> Self {
+        let enrolled = key.is_some() && certificate.as_ref().is_some_and(|cert| cert.is_valid_at(now));
+        Self { proxy_id, private_key: key.is_some(), key, certificate, enrolled, error: None }
+    }
+
+    fn undetermined(proxy_id: ProxyId, key: Option<KeySummary>, error: SamplyBeamError) -> Self {
+        Self { proxy_id, private_key: key.is_some(), key, certificate: None, enrolled: false, error: Some(error.to_string()) }
+    }
+
+    /// 0 if enrolled, 1 if not enrolled and 2 if the status could not be determined
+    pub(crate) fn exit_code(&self) -> i32 {
+        match (self.enrolled, &self.error) {
+            (true, _) => 0,
+            (false, None) => 1,
+            (false, Some(_)) => 2,
+        }
+    }
+}
+
+/// Checks the private key and looks up a matching certificate via the broker without retrying
+pub(crate) async fn status(config: &Config, client: &SamplyHttpClient) -> EnrollmentStatus {
+    let proxy_id = config.proxy_id.clone();
+    let Ok(private_crypto) = shared::config_shared::load_private_crypto_for_proxy() else {
+        return EnrollmentStatus::new(proxy_id, None, None, SystemTime::now());
+    };
+    let key = private_crypto.key_summary();
+    let privkey_rsa = private_crypto.privkey_rsa.clone();
+    let lookup = async {
+        crypto::init_cert_getter(crate::crypto::build_cert_getter(config.clone(), client.clone(), private_crypto)?);
+        crypto::init_ca_chain().await?;
+        let mut publics: Vec<_> = crypto::get_all_certs_and_clients_by_cname_as_pemstr(&proxy_id)
+            .await
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|public| crypto::is_cert_from_privkey(&public.cert, &privkey_rsa).unwrap_or(false))
+            .collect();
+        crypto::get_newest_cert(&mut publics)
+            .map(|public| CertificateStatus::from_cert(&public))
+            .transpose()
+    };
+    match lookup.await {
+        Ok(certificate) => EnrollmentStatus::new(proxy_id, Some(key), certificate, SystemTime::now()),
+        Err(e) => EnrollmentStatus::undetermined(proxy_id, Some(key), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy_id() -> ProxyId {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        ProxyId::new("proxy1.broker.samply.de".to_string()).unwrap()
+    }
+
+    fn key() -> Option<KeySummary> {
+        Some(KeySummary { algorithm: "RSA", bits: 2048, format: shared::config_shared::KeyFormat::Pkcs8 })
+    }
+
+    fn certificate(valid_since: u64, valid_until: u64) -> CertificateStatus {
+        CertificateStatus { serial: "44:0e".to_string(), valid_since, valid_until }
+    }
+
+    #[test]
+    fn not_enrolled() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let no_key = EnrollmentStatus::new(proxy_id(), None, None, now);
+        assert!(!no_key.enrolled);
+        assert_eq!(no_key.exit_code(), 1);
+
+        let no_cert = EnrollmentStatus::new(proxy_id(), key(), None, now);
+        assert!(!no_cert.enrolled);
+        assert_eq!(no_cert.exit_code(), 1);
+
+        let expired = EnrollmentStatus::new(proxy_id(), key(), Some(certificate(0, 1000)), now);
+        assert!(!expired.enrolled);
+        assert_eq!(expired.exit_code(), 1);
+
+        let unreachable = EnrollmentStatus::undetermined(proxy_id(), key(), SamplyBeamError::InternalSynchronizationError("broker unreachable".into()));
+        assert_eq!(unreachable.exit_code(), 2);
+        assert!(serde_json::to_value(&unreachable).unwrap()["error"].is_string());
+    }
+
+    #[test]
+    fn fully_enrolled() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let status = EnrollmentStatus::new(proxy_id(), key(), Some(certificate(500, 2000)), now);
+        assert!(status.enrolled);
+        assert_eq!(status.exit_code(), 0);
+        assert_eq!(serde_json::to_value(&status).unwrap(), serde_json::json!({
+            "proxy_id": "proxy1.broker.samply.de",
+            "private_key": true,
+            "key": { "algorithm": "RSA", "bits": 2048, "format": "PKCS#8" },
+            "certificate": { "serial": "44:0e", "valid_since": 500, "valid_until": 2000 },
+            "enrolled": true,
+        }));
+    }
+}
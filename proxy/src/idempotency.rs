@@ -0,0 +1,287 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use beam_lib::AppId;
+use once_cell::sync::Lazy;
+use shared::openssl::sha::sha256;
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+pub(crate) const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+const MAX_KEY_LEN: usize = 255;
+/// Upper bound of cached replies to keep the memory usage of the proxy bounded
+const MAX_CACHED_REPLIES: usize = 10_000;
+
+/// Replies to task submissions, by submitting app and `Idempotency-Key`
+pub(crate) static SUBMISSIONS: Lazy<IdempotencyCache> = Lazy::new(Default::default);
+
+#[derive(Clone)]
+pub(crate) struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl CachedResponse {
+    /// Buffers a response so it can be cached. Returns the response unchanged alongside.
+    pub(crate) async fn buffer(resp: Response) -> Result<(Self, Response), (StatusCode, &'static str)> {
+        let (parts, body) = resp.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX)
+            .await
+            .map_err(|_| (StatusCode::BAD_GATEWAY, "Unable to read server's reply."))?;
+        let cached = Self { status: parts.status, headers: parts.headers.clone(), body: body.clone() };
+        Ok((cached, Response::from_parts(parts, Body::from(body))))
+    }
+}
+
+impl IntoResponse for CachedResponse {
+    fn into_response(self) -> Response {
+        (self.status, self.headers, self.body).into_response()
+    }
+}
+
+pub(crate) struct IdempotencyCache {
+    capacity: usize,
+    entries: Mutex<HashMap<(AppId, String), Entry>>,
+}
+
+struct Entry {
+    /// SHA-256 of the request body the key was first used with
+    body_hash: [u8; 32],
+    state: EntryState,
+}
+
+enum EntryState {
+    /// The first request with this key is still being submitted; the receiver is notified once it finishes
+    InFlight(watch::Receiver<()>),
+    Done(Instant, CachedResponse),
+}
+
+/// Outcome of looking up a key before submitting a request
+pub(crate) enum Lookup<'a> {
+    Cached(CachedResponse),
+    /// Another request with this key is being submitted; look it up again once this resolves
+    InFlight(watch::Receiver<()>),
+    /// The key has been used for a request with a different body
+    Mismatch,
+    /// The request is to be submitted; other requests with this key wait for it
+    Submit(Reservation<'a>),
+}
+
+/// Marks a key as in flight until the reply is stored or the submission is given up by dropping it
+pub(crate) struct Reservation<'a> {
+    cache: &'a IdempotencyCache,
+    key: (AppId, String),
+    // Dropped last to wake the waiting requests
+    _done: watch::Sender<()>,
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::with_capacity(MAX_CACHED_REPLIES)
+    }
+}
+
+impl IdempotencyCache {
+    fn with_capacity(capacity: usize) -> Self {
+        Self { capacity, entries: Default::default() }
+    }
+
+    /// Returns the cached reply for this key if it was stored less than `window` before `now` or reserves the key for this request
+    pub(crate) fn begin(&self, app: &AppId, key: &str, body: &[u8], window: Duration, now: Instant) -> Lookup<'_> {
+        let body_hash = sha256(body);
+        let key = (app.clone(), key.to_string());
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&key) {
+            Some(entry) if entry.body_hash != body_hash && entry.is_live(window, now) => return Lookup::Mismatch,
+            Some(Entry { state: EntryState::InFlight(done), .. }) => return Lookup::InFlight(done.clone()),
+            Some(Entry { state: EntryState::Done(stored, resp), .. }) if now.saturating_duration_since(*stored) < window => {
+                return Lookup::Cached(resp.clone());
+            }
+            _ => {}
+        }
+        let (done, waiting) = watch::channel(());
+        entries.insert(key.clone(), Entry { body_hash, state: EntryState::InFlight(waiting) });
+        Lookup::Submit(Reservation { cache: self, key, _done: done })
+    }
+
+    /// Stores a reply, dropping all replies older than `window` and the oldest one if the cache is full
+    fn insert(&self, key: (AppId, String), resp: CachedResponse, window: Duration, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.is_live(window, now));
+        // The entry of this key is already counted as in flight
+        if entries.len() > self.capacity {
+            warn!("Caching more than {} replies to idempotent requests; forgetting the oldest one", self.capacity);
+            let oldest = entries
+                .iter()
+                .filter_map(|(k, entry)| match entry.state {
+                    EntryState::Done(stored, _) => Some((stored, k)),
+                    EntryState::InFlight(_) => None,
+                })
+                .min_by_key(|(stored, _)| *stored)
+                .map(|(_, k)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        debug!("Caching reply for idempotency key {} of app {}", key.1, key.0);
+        if let Some(entry) = entries.get_mut(&key) {
+            entry.state = EntryState::Done(now, resp);
+        }
+    }
+}
+
+impl Entry {
+    fn is_live(&self, window: Duration, now: Instant) -> bool {
+        match self.state {
+            EntryState::InFlight(_) => true,
+            EntryState::Done(stored, _) => now.saturating_duration_since(stored) < window,
+        }
+    }
+}
+
+impl Reservation<'_> {
+    /// Stores the reply for retries with the same key
+    pub(crate) fn finish(self, resp: CachedResponse, window: Duration, now: Instant) {
+        self.cache.insert(self.key.clone(), resp, window, now);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        // Requests waiting for a failed or abandoned submission submit themselves instead
+        let mut entries = self.cache.entries.lock().unwrap();
+        if matches!(entries.get(&self.key), Some(Entry { state: EntryState::InFlight(_), .. })) {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+/// Extracts the `Idempotency-Key` header if present
+pub(crate) fn key_from_headers(headers: &HeaderMap) -> Result<Option<String>, (StatusCode, &'static str)> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    parse_key(value)
+        .map(Some)
+        .ok_or((StatusCode::BAD_REQUEST, "Invalid Idempotency-Key header"))
+}
+
+fn parse_key(value: &HeaderValue) -> Option<String> {
+    let key = value.to_str().ok()?;
+    (!key.is_empty() && key.len() <= MAX_KEY_LEN).then(|| key.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(body: &'static str) -> CachedResponse {
+        CachedResponse { status: StatusCode::CREATED, headers: HeaderMap::new(), body: Bytes::from_static(body.as_bytes()) }
+    }
+
+    fn cached_body(lookup: Lookup) -> Option<Bytes> {
+        match lookup {
+            Lookup::Cached(resp) => Some(resp.body),
+            _ => None,
+        }
+    }
+
+    fn submit(cache: &IdempotencyCache, app: &AppId, key: &str, body: &str, resp: &'static str, window: Duration, now: Instant) {
+        let Lookup::Submit(reservation) = cache.begin(app, key, body.as_bytes(), window, now) else {
+            panic!("Expected {key} to be submitted");
+        };
+        reservation.finish(reply(resp), window, now);
+    }
+
+    #[test]
+    fn repeated_key_within_and_outside_window() {
+        let cache = IdempotencyCache::default();
+        let app = AppId::new_unchecked("app1.proxy1.broker");
+        let other_app = AppId::new_unchecked("app2.proxy1.broker");
+        let window = Duration::from_secs(600);
+        let now = Instant::now();
+
+        submit(&cache, &app, "key1", "task", "first", window, now);
+        let later = now + Duration::from_secs(60);
+        assert_eq!(cached_body(cache.begin(&app, "key1", b"task", window, later)).unwrap(), "first");
+        assert!(matches!(cache.begin(&app, "key2", b"task", window, later), Lookup::Submit(_)));
+        // Keys are scoped to the submitting app
+        assert!(matches!(cache.begin(&other_app, "key1", b"task", window, later), Lookup::Submit(_)));
+
+        let expired = now + window;
+        submit(&cache, &app, "key1", "task", "second", window, expired);
+        assert_eq!(cached_body(cache.begin(&app, "key1", b"task", window, expired)).unwrap(), "second");
+    }
+
+    #[test]
+    fn key_reused_for_another_body() {
+        let cache = IdempotencyCache::default();
+        let app = AppId::new_unchecked("app1.proxy1.broker");
+        let window = Duration::from_secs(600);
+        let now = Instant::now();
+        submit(&cache, &app, "key1", "task", "first", window, now);
+        assert!(matches!(cache.begin(&app, "key1", b"other task", window, now), Lookup::Mismatch));
+        // Once the reply is forgotten the key can be used for anything
+        assert!(matches!(cache.begin(&app, "key1", b"other task", window, now + window), Lookup::Submit(_)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_retry_waits_for_first_submission() {
+        let cache = IdempotencyCache::default();
+        let app = AppId::new_unchecked("app1.proxy1.broker");
+        let window = Duration::from_secs(600);
+        let now = Instant::now();
+        let Lookup::Submit(first) = cache.begin(&app, "key1", b"task", window, now) else {
+            panic!("The first request must be submitted");
+        };
+        let Lookup::InFlight(mut done) = cache.begin(&app, "key1", b"task", window, now) else {
+            panic!("A retry must wait for the first request");
+        };
+        first.finish(reply("first"), window, now);
+        assert!(done.changed().await.is_err(), "Waiting requests are woken up");
+        assert_eq!(cached_body(cache.begin(&app, "key1", b"task", window, now)).unwrap(), "first");
+
+        // A failed submission is not cached and lets the next request through
+        let Lookup::Submit(failed) = cache.begin(&app, "key2", b"task", window, now) else {
+            panic!("The first request must be submitted");
+        };
+        drop(failed);
+        assert!(matches!(cache.begin(&app, "key2", b"task", window, now), Lookup::Submit(_)));
+    }
+
+    #[test]
+    fn cache_is_bounded() {
+        let cache = IdempotencyCache::with_capacity(2);
+        let app = AppId::new_unchecked("app1.proxy1.broker");
+        let window = Duration::from_secs(600);
+        let now = Instant::now();
+        for (i, key) in ["key1", "key2", "key3"].into_iter().enumerate() {
+            submit(&cache, &app, key, "task", key, window, now + Duration::from_secs(i as u64));
+        }
+        let later = now + Duration::from_secs(10);
+        assert_eq!(cached_body(cache.begin(&app, "key3", b"task", window, later)).unwrap(), "key3");
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        assert!(matches!(cache.begin(&app, "key1", b"task", window, later), Lookup::Submit(_)));
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        let mut headers = HeaderMap::new();
+        assert!(matches!(key_from_headers(&headers), Ok(None)));
+        headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static("abc-123"));
+        assert_eq!(key_from_headers(&headers).ok().flatten().as_deref(), Some("abc-123"));
+        headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static(""));
+        assert!(key_from_headers(&headers).is_err());
+        headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_str(&"a".repeat(MAX_KEY_LEN + 1)).unwrap());
+        assert!(key_from_headers(&headers).is_err());
+    }
+}
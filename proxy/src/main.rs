@@ -6,6 +6,7 @@ use std::time::Duration;
 use axum::http::{header, HeaderValue, StatusCode};
 use beam_lib::AppOrProxyId;
 use futures::future::Ready;
+use rand::Rng;
 use shared::{reqwest, EncryptedMessage, MsgEmpty, PlainMessage};
 use shared::crypto::CryptoPublicPortion;
 use shared::errors::SamplyBeamError;
@@ -19,10 +20,15 @@ use crate::serve_tasks::sign_request;
 
 mod auth;
 mod banner;
+mod broker_info;
 mod crypto;
+mod enrollment;
+mod idempotency;
+mod recipients;
 mod serve;
 mod serve_health;
 mod serve_tasks;
+mod spool;
 #[cfg(feature = "sockets")]
 mod serve_sockets;
 
@@ -35,11 +41,25 @@ pub async fn main() -> anyhow::Result<()> {
     banner::print_banner();
 
     let config = config::CONFIG_PROXY.clone();
+    if let Some(threads) = config.crypto_parallelism {
+        shared::set_crypto_parallelism(threads);
+    }
     let client = http_client::build(
         &config::CONFIG_SHARED.tls_ca_certificates,
         Some(Duration::from_secs(PROXY_TIMEOUT)),
         Some(Duration::from_secs(20)),
     )?;
+    if config.enrollment_status {
+        let status = enrollment::status(&config, &client).await;
+        // A single line so it can be told apart from log output
+        println!("{}", serde_json::to_string(&status)?);
+        std::process::exit(status.exit_code());
+    }
+    let delay = startup_delay(config.startup_jitter, &mut rand::thread_rng());
+    if !delay.is_zero() {
+        info!("Delaying startup by {}ms", delay.as_millis());
+        tokio::time::sleep(delay).await;
+    }
 
     if let Err(err) = retry_notify(|| get_broker_health(&config, &client), |err, dur| {
         warn!("Still trying to reach Broker: {err}. Retrying in {}s", dur.as_secs());
@@ -49,6 +69,8 @@ pub async fn main() -> anyhow::Result<()> {
     } else {
         info!("Connected to Broker: {}", &config.broker_uri);
     }
+    broker_info::refresh(&config, &client).await;
+    broker_info::spawn_refetching(config.clone(), client.clone());
 
     if let Err(err) = retry_notify(|| init_crypto(config.clone(), client.clone()), |err, dur| {
         warn!("Still trying to initialize certificate chain: {err}. Retrying in {}s", dur.as_secs());
@@ -58,12 +80,22 @@ pub async fn main() -> anyhow::Result<()> {
     } else {
         debug!("Certificate chain successfully initialized and validated");
     }
+    serve_health::startup_completed();
     spawn_controller_polling(client.clone(), config.clone());
+    spool::spawn_spool_polling(client.clone(), config.clone());
 
     serve::serve(config, client).await?;
     Ok(())
 }
 
+/// Random delay of less than `max`, cf. `--startup-jitter`
+fn startup_delay(max: Duration, rng: &mut impl Rng) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    max.mul_f64(rng.gen())
+}
+
 fn retry_notify<F, T, Fut, E, Cb>(f: F, on_error: Cb) -> RetryFuture<F, Fut, ExponentialBackoff, Box<dyn Fn(u32, Option<Duration>, &E) -> Ready<()>>>
 where 
     F: FnMut() -> Fut,
@@ -80,6 +112,7 @@ where
 
 async fn init_crypto(config: Config, client: SamplyHttpClient) -> Result<(), SamplyBeamError> {
     let private_crypto_proxy = shared::config_shared::load_private_crypto_for_proxy()?;
+    info!("Loaded private key ({})", private_crypto_proxy.key_summary());
     shared::crypto::init_cert_getter(crypto::build_cert_getter(
         config.clone(),
         client.clone(),
@@ -96,18 +129,22 @@ async fn init_crypto(config: Config, client: SamplyHttpClient) -> Result<(), Sam
                     .ok()
             })
             .collect();
-    let (serial, cname) =
+    let (serial, cname, fingerprint) =
         shared::config_shared::init_public_crypto_for_proxy(private_crypto_proxy).await?;
     if cname != config.proxy_id.to_string() {
         return Err(SamplyBeamError::ConfigurationFailed(format!("Unable to retrieve a certificate matching your Proxy ID. Expected {}, got {}. Please check your configuration", cname, config.proxy_id.to_string())));
     }
 
-    info!("Certificate retrieved for our proxy ID {cname} (serial {serial})");
+    info!("Certificate retrieved for our proxy ID {cname} (serial {serial}, SHA-256 {fingerprint})");
+
+    if !config.prewarm_peers.is_empty() {
+        shared::crypto::prewarm(&config.prewarm_peers).await;
+    }
 
     Ok(())
 }
 
-async fn get_broker_health(
+pub(crate) async fn get_broker_health(
     config: &Config,
     client: &SamplyHttpClient,
 ) -> Result<(), SamplyBeamError> {
@@ -120,6 +157,8 @@ async fn get_broker_health(
         .send()
         .await?;
 
+    broker_info::check_broker_tls(config, &resp)?;
+
     match resp.status() {
         StatusCode::OK => Ok(()),
         _ => Err(SamplyBeamError::InternalSynchronizationError(format!(
@@ -133,6 +172,7 @@ fn spawn_controller_polling(client: SamplyHttpClient, config: Config) {
     const RETRY_INTERVAL: Duration = Duration::from_secs(60);
     tokio::spawn(async move {
         let mut retries_this_min = 0;
+        let mut disconnected = false;
         let mut reset_interval = std::pin::pin!(tokio::time::sleep(Duration::from_secs(60)));
         loop {
             let body = EncryptedMessage::MsgEmpty(MsgEmpty {
@@ -148,6 +188,16 @@ fn spawn_controller_polling(client: SamplyHttpClient, config: Config) {
             // In the future this will poll actual control related tasks
             match client.execute(req).await {
                 Ok(res) => {
+                    if let Err(e) = broker_info::check_broker_tls(&config, &res) {
+                        warn!("Rejecting the broker's response to the control poll: {e}");
+                        tokio::time::sleep(RETRY_INTERVAL).await;
+                        continue;
+                    }
+                    if disconnected {
+                        // The broker might have been replaced by a different version in the meantime
+                        broker_info::refresh(&config, &client).await;
+                        disconnected = false;
+                    }
                     match res.status() {
                         StatusCode::OK => {
                             // Process control task
@@ -172,6 +222,7 @@ fn spawn_controller_polling(client: SamplyHttpClient, config: Config) {
                     debug!("Connection to broker timed out; retrying: {e}");
                 },
                 Err(e) => {
+                    disconnected = true;
                     warn!("Error getting control tasks from broker; retrying in {}s: {e}", RETRY_INTERVAL.as_secs());
                     tokio::time::sleep(RETRY_INTERVAL).await;
                 }
@@ -183,3 +234,23 @@ fn spawn_controller_polling(client: SamplyHttpClient, config: Config) {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn startup_is_jittered_across_instances() {
+        let max = Duration::from_secs(30);
+        let delays: Vec<_> = (0..100)
+            .map(|instance| startup_delay(max, &mut StdRng::seed_from_u64(instance)))
+            .collect();
+        assert!(delays.iter().all(|delay| *delay < max));
+        // Instances spread over the whole window instead of starting together
+        assert!(delays.iter().any(|delay| *delay < max / 4));
+        assert!(delays.iter().any(|delay| *delay > max * 3 / 4));
+        assert_eq!(startup_delay(Duration::ZERO, &mut StdRng::seed_from_u64(0)), Duration::ZERO);
+    }
+}
@@ -0,0 +1,155 @@
+use axum::http::HeaderName;
+use beam_lib::{AppOrProxyId, ProxyId};
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+use shared::{crypto::{self, PartialEncryptionPolicy}, errors::SamplyBeamError};
+
+/// Lists the proxies a message was not sent to under [`PartialEncryptionPolicy::BestEffort`]
+pub(crate) const UNRESOLVED_RECIPIENTS: HeaderName = HeaderName::from_static("unresolved-recipients");
+
+/// Translates the recipients an app puts into `to` into concrete beam ids before a message is accepted.
+/// This allows deployments to expand aliases or groups from an external directory.
+pub(crate) trait RecipientResolver: Send + Sync {
+    fn resolve(&self, recipient: &str) -> Result<Vec<AppOrProxyId>, String>;
+}
+
+/// Accepts only recipients that already are valid beam ids
+pub(crate) struct IdentityResolver;
+
+impl RecipientResolver for IdentityResolver {
+    fn resolve(&self, recipient: &str) -> Result<Vec<AppOrProxyId>, String> {
+        AppOrProxyId::new(recipient)
+            .map(|id| vec![id])
+            .map_err(|e| e.to_string())
+    }
+}
+
+static RECIPIENT_RESOLVER: OnceCell<Box<dyn RecipientResolver>> = OnceCell::new();
+
+#[allow(dead_code)]
+pub(crate) fn init_recipient_resolver<R: RecipientResolver + 'static>(resolver: R) {
+    if RECIPIENT_RESOLVER.set(Box::new(resolver)).is_err() {
+        panic!("Internal error: Tried to initialize recipient resolver twice");
+    }
+}
+
+pub(crate) fn recipient_resolver() -> &'static dyn RecipientResolver {
+    RECIPIENT_RESOLVER.get_or_init(|| Box::new(IdentityResolver)).as_ref()
+}
+
+/// Replaces the `to` field of a json message with the resolved beam ids, dropping duplicates.
+/// Messages without a `to` field are left untouched.
+pub(crate) fn resolve_recipients(msg: &mut Value, resolver: &dyn RecipientResolver) -> Result<(), SamplyBeamError> {
+    let Some(to) = msg.get_mut("to") else {
+        return Ok(());
+    };
+    let recipients = match to {
+        Value::String(recipient) => vec![Value::String(std::mem::take(recipient))],
+        Value::Array(recipients) => std::mem::take(recipients),
+        _ => return Err(SamplyBeamError::RequestValidationFailed("Field \"to\" must contain a list of recipients".into())),
+    };
+    let mut resolved: Vec<AppOrProxyId> = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        let Value::String(recipient) = recipient else {
+            return Err(SamplyBeamError::RequestValidationFailed(format!("Recipient {recipient} is not a string")));
+        };
+        let ids = resolver.resolve(&recipient).map_err(|e| {
+            SamplyBeamError::RequestValidationFailed(format!("Unable to resolve recipient \"{recipient}\": {e}"))
+        })?;
+        for id in ids {
+            if !resolved.contains(&id) {
+                resolved.push(id);
+            }
+        }
+    }
+    *to = resolved.into_iter().map(|id| Value::String(id.to_string())).collect();
+    Ok(())
+}
+
+/// Looks up the certificates of all recipients in the `to` field of a json message and applies `policy` to those without one.
+/// Returns the proxies whose recipients were dropped.
+pub(crate) async fn check_resolvable(msg: &mut Value, policy: PartialEncryptionPolicy) -> Result<Vec<ProxyId>, SamplyBeamError> {
+    let Some(Value::Array(to)) = msg.get("to") else {
+        return Ok(Vec::new());
+    };
+    let recipients: Vec<AppOrProxyId> = to.iter()
+        .filter_map(|recipient| AppOrProxyId::new(recipient.as_str()?).ok())
+        .collect();
+    let unresolved = match crypto::get_proxy_public_keys(&recipients).await {
+        Ok(_) => Vec::new(),
+        Err(SamplyBeamError::InvalidReceivers(proxies)) => proxies,
+        Err(e) => return Err(e),
+    };
+    apply_partial_encryption_policy(msg, unresolved, policy)
+}
+
+fn apply_partial_encryption_policy(msg: &mut Value, unresolved: Vec<ProxyId>, policy: PartialEncryptionPolicy) -> Result<Vec<ProxyId>, SamplyBeamError> {
+    if unresolved.is_empty() {
+        return Ok(unresolved);
+    }
+    let Some(Value::Array(to)) = msg.get_mut("to").filter(|_| policy == PartialEncryptionPolicy::BestEffort) else {
+        return Err(SamplyBeamError::InvalidReceivers(unresolved));
+    };
+    to.retain(|recipient| recipient.as_str()
+        .and_then(|recipient| AppOrProxyId::new(recipient).ok())
+        .is_some_and(|id| !unresolved.contains(&id.proxy_id())));
+    if to.is_empty() {
+        return Err(SamplyBeamError::InvalidReceivers(unresolved));
+    }
+    Ok(unresolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use beam_lib::set_broker_id;
+    use serde_json::json;
+
+    use super::*;
+
+    struct GroupResolver;
+
+    impl RecipientResolver for GroupResolver {
+        fn resolve(&self, recipient: &str) -> Result<Vec<AppOrProxyId>, String> {
+            match recipient {
+                "group:all" => Ok(vec![
+                    AppOrProxyId::new("app1.proxy1.broker.samply.de").unwrap(),
+                    AppOrProxyId::new("app1.proxy2.broker.samply.de").unwrap(),
+                ]),
+                other => IdentityResolver.resolve(other),
+            }
+        }
+    }
+
+    #[test]
+    fn group_alias_is_expanded() {
+        set_broker_id("broker.samply.de".to_string());
+        let mut msg = json!({"to": ["group:all", "app1.proxy2.broker.samply.de"]});
+        resolve_recipients(&mut msg, &GroupResolver).unwrap();
+        assert_eq!(msg["to"], json!(["app1.proxy1.broker.samply.de", "app1.proxy2.broker.samply.de"]));
+
+        let mut msg = json!({"to": ["group:unknown"]});
+        assert!(resolve_recipients(&mut msg, &GroupResolver).is_err());
+    }
+
+    #[test]
+    fn one_unresolvable_recipient() {
+        set_broker_id("broker.samply.de".to_string());
+        let msg = json!({"to": ["app1.proxy1.broker.samply.de", "app1.proxy2.broker.samply.de", "app2.proxy2.broker.samply.de"]});
+        let offline = vec![ProxyId::new_unchecked("proxy2.broker.samply.de")];
+
+        let mut all_or_nothing = msg.clone();
+        let res = apply_partial_encryption_policy(&mut all_or_nothing, offline.clone(), PartialEncryptionPolicy::AllOrNothing);
+        assert!(matches!(res, Err(SamplyBeamError::InvalidReceivers(proxies)) if proxies == offline));
+        assert_eq!(all_or_nothing, msg);
+
+        let mut best_effort = msg.clone();
+        let res = apply_partial_encryption_policy(&mut best_effort, offline.clone(), PartialEncryptionPolicy::BestEffort);
+        assert_eq!(res.unwrap(), offline);
+        assert_eq!(best_effort["to"], json!(["app1.proxy1.broker.samply.de"]));
+
+        // Nothing is sent if no recipient remains
+        let mut only_offline = json!({"to": ["app1.proxy2.broker.samply.de"]});
+        let res = apply_partial_encryption_policy(&mut only_offline, offline.clone(), PartialEncryptionPolicy::BestEffort);
+        assert!(matches!(res, Err(SamplyBeamError::InvalidReceivers(_))));
+    }
+}
@@ -5,6 +5,7 @@ use shared::{
     config, config_proxy, config_shared, errors::SamplyBeamError, http_client::SamplyHttpClient,
 };
 use tokio::net::TcpListener;
+use tower_http::compression::{predicate::{NotForContentType, Predicate, SizeAbove}, CompressionLayer};
 use tracing::{debug, error, info, warn};
 
 use crate::{banner, serve_health, serve_tasks};
@@ -15,12 +16,16 @@ pub(crate) async fn serve(
 ) -> anyhow::Result<()> {
     let router_tasks = serve_tasks::router(&client);
 
-    let router_health = serve_health::router();
+    let router_health = serve_health::router(client.clone());
 
     let app = router_tasks.merge(router_health);
 
     #[cfg(feature = "sockets")]
     let app = app.merge(crate::serve_sockets::router(client));
+    let app = match config.compression_min_size {
+        Some(min_size) => app.layer(compression(min_size)),
+        None => app,
+    };
     // Middleware needs to be set last
     let app = app
         .layer(axum::middleware::from_fn(shared::middleware::log))
@@ -46,3 +51,37 @@ pub(crate) async fn serve(
 
     Ok(())
 }
+
+/// Compresses responses of at least `min_size` bytes. Event streams are left alone so events are not held back,
+/// as are signed tokens, which hardly compress.
+fn compression(min_size: u16) -> CompressionLayer<impl Predicate> {
+    let predicate = SizeAbove::new(min_size)
+        .and(NotForContentType::SSE)
+        .and(NotForContentType::const_new("application/jwt"));
+    CompressionLayer::new().compress_when(predicate)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header, Request}, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn large_responses_are_compressed() {
+        let app = Router::new()
+            .route("/large", get(|| async { "a".repeat(4096) }))
+            .route("/small", get(|| async { "a" }))
+            .layer(compression(1024));
+        let get = |path| {
+            let app = app.clone();
+            async move {
+                let req = Request::get(path).header(header::ACCEPT_ENCODING, "gzip").body(Body::empty()).unwrap();
+                app.oneshot(req).await.unwrap().headers().get(header::CONTENT_ENCODING).cloned()
+            }
+        };
+        assert_eq!(get("/large").await.unwrap(), "gzip");
+        assert!(get("/small").await.is_none());
+    }
+}
@@ -1,9 +1,127 @@
-use axum::{http::StatusCode, routing::get, Router};
+use std::{future::Future, sync::Arc, time::Duration};
 
-pub(crate) fn router() -> Router {
-    Router::new().route("/v1/health", get(handler_health))
+use axum::{extract::State, http::StatusCode, routing::get, Router};
+use once_cell::sync::OnceCell;
+use shared::{config, http_client::SamplyHttpClient};
+use tokio::{sync::Mutex, time::Instant};
+
+/// How long the outcome of a broker health check is reused by `/healthz`
+const BROKER_REACHABLE_TTL: Duration = Duration::from_secs(10);
+
+/// When the startup checks, including certificate prewarming, passed
+static STARTUP_COMPLETED: OnceCell<Instant> = OnceCell::new();
+
+pub(crate) fn startup_completed() {
+    _ = STARTUP_COMPLETED.set(Instant::now());
+}
+
+pub(crate) fn router(client: SamplyHttpClient) -> Router {
+    Router::new()
+        .route("/v1/health", get(handler_health))
+        .route("/healthz", get(handler_healthz))
+        .with_state(Arc::new(ReadinessState {
+            client,
+            broker: CachedCheck::new(BROKER_REACHABLE_TTL),
+        }))
+}
+
+struct ReadinessState {
+    client: SamplyHttpClient,
+    broker: CachedCheck,
 }
 
 async fn handler_health() -> StatusCode {
     StatusCode::OK
 }
+
+// GET /healthz
+async fn handler_healthz(State(state): State<Arc<ReadinessState>>) -> StatusCode {
+    let broker_reachable = state.broker
+        .get_or_check(|| async {
+            crate::get_broker_health(&config::CONFIG_PROXY, &state.client).await.is_ok()
+        })
+        .await;
+    let warmed_up = is_warmed_up(STARTUP_COMPLETED.get().copied(), config::CONFIG_PROXY.readiness_warmup, Instant::now());
+    readiness(shared::crypto::is_crypto_loaded(), broker_reachable, warmed_up)
+}
+
+/// Whether `warmup` has passed since the startup checks completed
+fn is_warmed_up(startup_completed: Option<Instant>, warmup: Duration, now: Instant) -> bool {
+    startup_completed.is_some_and(|completed| now.saturating_duration_since(completed) >= warmup)
+}
+
+fn readiness(crypto_loaded: bool, broker_reachable: bool, warmed_up: bool) -> StatusCode {
+    if crypto_loaded && broker_reachable && warmed_up {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Remembers the outcome of a check for `ttl` so frequent probes stay cheap
+struct CachedCheck {
+    ttl: Duration,
+    last: Mutex<Option<(Instant, bool)>>,
+}
+
+impl CachedCheck {
+    fn new(ttl: Duration) -> Self {
+        Self { ttl, last: Mutex::new(None) }
+    }
+
+    async fn get_or_check<F: Future<Output = bool>>(&self, check: impl FnOnce() -> F) -> bool {
+        // Holding the lock during the check makes concurrent probes wait for a single request
+        let mut last = self.last.lock().await;
+        match *last {
+            Some((checked_at, outcome)) if checked_at.elapsed() < self.ttl => outcome,
+            _ => {
+                let outcome = check().await;
+                *last = Some((Instant::now(), outcome));
+                outcome
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn ready_and_not_ready() {
+        assert_eq!(readiness(true, true, true), StatusCode::OK);
+        assert_eq!(readiness(false, true, true), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(readiness(true, false, true), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(readiness(true, true, false), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn readiness_waits_for_prewarm_and_warmup() {
+        let warmup = Duration::from_secs(10);
+        let now = Instant::now();
+        assert!(!is_warmed_up(None, Duration::ZERO, now), "Not ready before startup checks and prewarming completed");
+        assert!(is_warmed_up(Some(now), Duration::ZERO, now));
+        assert!(!is_warmed_up(Some(now), warmup, now + Duration::from_secs(9)));
+        assert!(is_warmed_up(Some(now), warmup, now + warmup));
+    }
+
+    #[tokio::test]
+    async fn broker_check_is_cached() {
+        let checks = AtomicUsize::new(0);
+        let check = |outcome| {
+            checks.fetch_add(1, Ordering::Relaxed);
+            async move { outcome }
+        };
+        let cache = CachedCheck::new(Duration::from_secs(10));
+        assert!(!cache.get_or_check(|| check(false)).await);
+        assert!(!cache.get_or_check(|| check(true)).await);
+        assert_eq!(checks.load(Ordering::Relaxed), 1);
+
+        let expired = CachedCheck::new(Duration::ZERO);
+        assert!(!expired.get_or_check(|| check(false)).await);
+        assert!(expired.get_or_check(|| check(true)).await);
+        assert_eq!(checks.load(Ordering::Relaxed), 3);
+    }
+}
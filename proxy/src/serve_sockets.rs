@@ -24,7 +24,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use beam_lib::AppOrProxyId;
 use shared::{
-    config, ct_codecs::{self, Base64UrlSafeNoPadding, Decoder as B64Decoder, Encoder as B64Encoder}, expire_map::LazyExpireMap, http_client::SamplyHttpClient, reqwest, MessageType, MsgEmpty, MsgId, MsgSocketRequest, Plain
+    config, config_proxy, ct_codecs::{self, Base64UrlSafeNoPadding, Decoder as B64Decoder, Encoder as B64Encoder}, errors::{ErrorBody, SamplyBeamError}, expire_map::LazyExpireMap, http_client::SamplyHttpClient, metadata, reqwest, MessageType, MsgEmpty, MsgId, MsgSocketRequest, Plain, SOCKET_REQUEST_TTL
 };
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
 use tokio_util::{
@@ -35,7 +35,8 @@ use tracing::{warn, debug};
 
 use crate::{
     auth::AuthenticatedApp,
-    serve_tasks::{forward_request, handler_task, TasksState, validate_and_decrypt, to_server_error},
+    broker_info,
+    serve_tasks::{encrypt_msg, forward_request, handler_task, sign_request, TasksState, validate_and_decrypt, to_server_error},
 };
 
 type MsgSecretMap = Arc<LazyExpireMap<MsgId, SocketEncKey>>;
@@ -52,7 +53,7 @@ pub(crate) fn router(client: SamplyHttpClient) -> Router {
     tokio::spawn(async move {
         loop {
             tokio::time::sleep(TASK_SECRET_CLEANUP_INTERVAL).await;
-            map.retain_expired();
+            map.remove_expired();
         }
     });
 
@@ -84,7 +85,7 @@ async fn get_tasks(
                 .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_response())?
                 .to_string()
             )).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
-            let Ok(ttl) = socket_task.expire.duration_since(SystemTime::now()) else {
+            let Ok(ttl) = socket_task.expire.duration_since(shared::clock::now()) else {
                 continue;
             };
             task_secret_map.insert_for(ttl, socket_task.id, key);
@@ -100,18 +101,21 @@ async fn get_tasks(
 
 async fn create_socket_con(
     AuthenticatedApp(sender): AuthenticatedApp,
-    Path(to): Path<AppOrProxyId>,
+    Path(to): Path<String>,
     Extension(task_secret_map): Extension<MsgSecretMap>,
     state: State<TasksState>,
     mut req: Request,
 ) -> Response {
+    let to = match AppOrProxyId::new(&to) {
+        Ok(to) => to,
+        Err(e) => return socket_error(SamplyBeamError::InvalidBeamId(e)).into_response(),
+    };
     let task_id = MsgId::new();
     let secret = SocketEncKey::generate();
     let Ok(secret_encoded) = secret.to_b64_str() else {
         return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     };
-    const TTL: Duration = Duration::from_secs(60);
-    task_secret_map.insert_for(TTL, task_id.clone(), secret);
+    task_secret_map.insert_for(SOCKET_REQUEST_TTL, task_id.clone(), secret);
     let metadata = req
         .headers_mut()
         .remove("metadata")
@@ -120,44 +124,79 @@ async fn create_socket_con(
     let socket_req = MsgSocketRequest {
         from: AppOrProxyId::App(sender.clone()),
         to: vec![to],
-        expire: SystemTime::now() + TTL,
+        expire: shared::clock::now() + SOCKET_REQUEST_TTL,
         id: task_id,
         secret: Plain::from(secret_encoded),
         metadata
     };
 
-    let Ok(body) = serde_json::to_vec(&socket_req) else {
-        warn!("Failed to serialize MsgSocketRequest");
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-    };
-    let new_req = Request::post("/v1/sockets").body(axum::body::Body::from(body));
-    let post_socket_task_req = match new_req {
-        Ok(req) => req,
-        Err(e) => {
-            warn!("Failed to construct request: {e}");
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    let res = match post_socket_request(socket_req, &state.config, &state.client).await {
+        Ok(res) => res,
+        Err(err) => {
+            warn!("Failed to post socket request: {err}");
+            return socket_error(err).into_response();
         }
     };
 
-    let res =
-        match forward_request(post_socket_task_req, &state.config, &sender, &state.client).await {
-            Ok(res) => res,
-            Err(err) => {
-                warn!("Failed to create post socket request: {err:?}");
-                return err.into_response();
-            }
-        };
-
     if res.status() != StatusCode::CREATED {
         warn!(
             "Failed to post MsgSocketRequest to broker. Statuscode: {}",
             res.status()
         );
-        return (res.status(), "Failed to post MsgSocketRequest to broker").into_response();
+        let body = ErrorBody {
+            code: "broker_rejected",
+            message: format!("Broker rejected the socket request with status {}", res.status()),
+            field: None,
+        };
+        return (res.status(), Json(body)).into_response();
     }
     connect_socket(AuthenticatedApp(sender), state, Extension(task_secret_map), Path(task_id), req).await
 }
 
+/// Encrypts and signs a socket request and posts it to the broker
+async fn post_socket_request(
+    mut socket_req: MsgSocketRequest<Plain>,
+    config: &config_proxy::Config,
+    client: &SamplyHttpClient,
+) -> Result<reqwest::Response, SamplyBeamError> {
+    broker_info::check_broker_id()?;
+    metadata::enforce_reserved_keys(&mut socket_req.metadata, config.reserved_metadata_keys)?;
+    if let Some(defaults) = &config.default_metadata {
+        metadata::merge_default_metadata(&mut socket_req.metadata, defaults, config.default_metadata_overwrite);
+    }
+    metadata::check_metadata(&socket_req.metadata)?;
+    let encrypted = encrypt_msg(socket_req).await?;
+    let (parts, _) = Request::post(format!("{}v1/sockets", config.broker_uri))
+        .header(header::VIA, HeaderValue::from_static(env!("SAMPLY_USER_AGENT")))
+        .body(())
+        .expect("To build request successfully")
+        .into_parts();
+    let req = sign_request(MessageType::MsgSocketRequest(encrypted), parts, config, None)
+        .await
+        .map_err(|(_, e)| SamplyBeamError::SignEncryptError(e.to_string()))?;
+    let resp = client.execute(req).await?;
+    broker_info::check_broker_tls(config, &resp)?;
+    Ok(resp)
+}
+
+/// Turns a rejected socket request into a structured JSON error
+fn socket_error(err: SamplyBeamError) -> (StatusCode, Json<ErrorBody>) {
+    let (status, field) = match &err {
+        SamplyBeamError::InvalidBeamId(_) => (StatusCode::BAD_REQUEST, Some("to")),
+        SamplyBeamError::InvalidReceivers(_) => (StatusCode::FAILED_DEPENDENCY, Some("to")),
+        SamplyBeamError::RequestValidationFailed(_) => (StatusCode::BAD_REQUEST, Some("metadata")),
+        SamplyBeamError::MessageTooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, None),
+        SamplyBeamError::HttpRequestError(e) if e.is_timeout() => (StatusCode::GATEWAY_TIMEOUT, None),
+        SamplyBeamError::HttpRequestError(_) => (StatusCode::BAD_GATEWAY, None),
+        SamplyBeamError::UnsupportedMsgVersion { .. } => (StatusCode::BAD_GATEWAY, None),
+        SamplyBeamError::BrokerIdMismatch { .. } => (StatusCode::BAD_GATEWAY, None),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, None),
+    };
+    let mut body = ErrorBody::from(&err);
+    body.field = field;
+    (status, Json(body))
+}
+
 async fn connect_socket(
     AuthenticatedApp(sender): AuthenticatedApp,
     state: State<TasksState>,
@@ -181,34 +220,42 @@ async fn connect_socket(
         return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     };
     // Try to connect to socket
-    let new_req = Request::get(format!("/v1/sockets/{task_id}")).body(axum::body::Body::from(body));
-    let mut get_socket_con_req = match new_req {
-        Ok(req) => req,
-        Err(e) => {
-            warn!("Failed to construct request: {e}");
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
-    get_socket_con_req.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
-    get_socket_con_req.headers_mut().insert(header::UPGRADE, HeaderValue::from_static("tcp"));
-
-    let mut res = match forward_request(get_socket_con_req, &state.config, &sender, &state.client).await
-    {
-        Ok(res) => res,
-        Err(err) => {
-            warn!("Failed to create socket connect request: {err:?}");
-            return err.into_response();
+    let connect = || async {
+        let mut get_socket_con_req = Request::get(format!("/v1/sockets/{task_id}"))
+            .body(axum::body::Body::from(body.clone()))
+            .expect("To build request successfully");
+        get_socket_con_req.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        get_socket_con_req.headers_mut().insert(header::UPGRADE, HeaderValue::from_static("tcp"));
+
+        let mut res = forward_request(get_socket_con_req, &state.config, &sender, &state.client)
+            .await
+            .map_err(|err| {
+                warn!("Failed to create socket connect request: {err:?}");
+                ConnectError::Forward(err)
+            })?;
+        match res.extensions_mut().remove::<hyper::upgrade::OnUpgrade>() {
+            Some(other_conn) if res.status() == StatusCode::SWITCHING_PROTOCOLS => Ok(other_conn),
+            _ => {
+                let s = res.status();
+                let res = res.text().await.unwrap_or_else(|_| "<Failed to read body>".into());
+                warn!("Failed to create an upgradable connection to the broker. {s}: {res}");
+                Err(ConnectError::Status(s))
+            }
         }
     };
-
-    let broker_conn = match res.extensions_mut().remove::<hyper::upgrade::OnUpgrade>() {
-        Some(other_conn) if res.status() == StatusCode::SWITCHING_PROTOCOLS => other_conn,
-        _ => {
-            let s = res.status();
-            let res = res.text().await.unwrap_or_else(|_| "<Failed to read body>".into());
-            warn!("Failed to create an upgradable connection to the broker. {s}: {res}");
-            return s.into_response();
+    let retries = state.config.socket_connect_retries;
+    let broker_conn = match retry_transient(retries, SOCKET_CONNECT_RETRY_DELAY, ConnectError::is_transient, connect).await {
+        Ok(conn) => conn,
+        Err(e) if retries > 0 && e.is_transient() => {
+            let body = ErrorBody {
+                code: "tunnel_unavailable",
+                message: format!("Unable to establish the socket tunnel after {} attempts, last status: {}", retries + 1, e.status()),
+                field: None,
+            };
+            return (e.status(), Json(body)).into_response();
         }
+        Err(ConnectError::Status(s)) => return s.into_response(),
+        Err(ConnectError::Forward(res)) => return res,
     };
 
     // Connect sockets
@@ -237,6 +284,54 @@ async fn connect_socket(
     ], StatusCode::SWITCHING_PROTOCOLS).into_response()
 }
 
+const SOCKET_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Why connecting to the broker's end of a socket tunnel failed
+enum ConnectError {
+    /// The broker answered with an error status
+    Status(StatusCode),
+    /// The request could not be forwarded to the broker
+    Forward(Response),
+}
+
+impl ConnectError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ConnectError::Status(status) => *status,
+            ConnectError::Forward(res) => res.status(),
+        }
+    }
+
+    /// The peer did not connect before the broker gave up waiting or the broker is briefly unavailable
+    fn is_transient(&self) -> bool {
+        matches!(self.status(), StatusCode::GONE | StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT)
+    }
+}
+
+/// Retries `attempt` up to `retries` times as long as it fails transiently, doubling `delay` after every retry
+async fn retry_transient<T, E, Fut>(
+    retries: u32,
+    mut delay: Duration,
+    is_transient: impl Fn(&E) -> bool,
+    mut attempt: impl FnMut() -> Fut,
+) -> Result<T, E>
+where
+    Fut: std::future::Future<Output = Result<T, E>>,
+{
+    let mut retried = 0;
+    loop {
+        match attempt().await {
+            Err(e) if retried < retries && is_transient(&e) => {
+                retried += 1;
+                debug!("Establishing socket tunnel failed transiently, retry {retried}/{retries} in {delay:?}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 struct SocketEncKey(GenericArray<u8, U32>);
 
@@ -454,12 +549,63 @@ impl<S: AsyncWrite + AsyncRead + Unpin> AsyncWrite for EncryptedSocket<S> {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::Ordering;
+
     use chacha20poly1305::aead::stream::{Decryptor, Encryptor, EncryptorLE31};
     use rand::Rng;
     use tokio::net::{TcpListener, TcpStream};
 
     use super::*;
 
+    #[test]
+    fn socket_errors_are_structured() {
+        let (status, Json(body)) = socket_error(SamplyBeamError::InvalidBeamId(beam_lib::BeamIdError::InvalidNumberOfIdFragments));
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body = serde_json::to_value(body).unwrap();
+        assert_eq!(body["code"], "invalid_beam_id");
+        assert_eq!(body["field"], "to");
+        assert!(body["message"].is_string());
+
+        let proxy = beam_lib::ProxyId::new_unchecked("proxy1.broker");
+        let (status, Json(body)) = socket_error(SamplyBeamError::InvalidReceivers(vec![proxy]));
+        assert_eq!(status, StatusCode::FAILED_DEPENDENCY);
+        assert_eq!(serde_json::to_value(body).unwrap()["code"], "invalid_receivers");
+
+        let (status, Json(body)) = socket_error(SamplyBeamError::MessageTooLarge(1024));
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            serde_json::to_value(body).unwrap(),
+            serde_json::json!({"code": "message_too_large", "message": "Message exceeds the maximum size of 1024 bytes", "field": null})
+        );
+    }
+
+    #[tokio::test]
+    async fn tunnel_is_established_on_second_attempt() {
+        let attempts = AtomicUsize::new(0);
+        let connect = || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(ConnectError::Status(StatusCode::GONE)),
+                _ => Ok("connected"),
+            }
+        };
+        let result = retry_transient(2, Duration::from_millis(1), ConnectError::is_transient, connect).await;
+        assert!(matches!(result, Ok("connected")));
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+
+        // Permanent failures and an exhausted budget are not retried any further
+        let attempts = &AtomicUsize::new(0);
+        let fail = |status| move || async move {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err::<(), _>(ConnectError::Status(status))
+        };
+        let result = retry_transient(2, Duration::from_millis(1), ConnectError::is_transient, fail(StatusCode::UNAUTHORIZED)).await;
+        assert!(matches!(result, Err(ConnectError::Status(StatusCode::UNAUTHORIZED))));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+        let result = retry_transient(2, Duration::from_millis(1), ConnectError::is_transient, fail(StatusCode::GONE)).await;
+        assert!(matches!(result, Err(ConnectError::Status(StatusCode::GONE))));
+        assert_eq!(attempts.load(Ordering::Relaxed), 4);
+    }
+
     #[tokio::test]
     async fn test_encryption() {
         let mut key = GenericArray::default();
@@ -1,28 +1,29 @@
 use std::{
     convert::Infallible,
     str::FromStr,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use axum::{
-    body::Bytes, extract::{FromRef, Request, State}, http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode, Uri}, response::{sse::Event, IntoResponse, Response, Sse}, routing::{any, get, put}, Json, RequestExt, Router
+    body::Bytes, extract::{FromRef, Path, Request, State}, http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri}, response::{sse::Event, IntoResponse, Response, Sse}, routing::{any, delete, get, put}, Json, RequestExt, Router
 };
 use futures::{
     stream::{StreamExt, TryStreamExt},
     Stream, TryFutureExt,
 };
 use httpdate::fmt_http_date;
+use once_cell::sync::Lazy;
 use rsa::{pkcs8::DecodePublicKey, RsaPublicKey};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 use beam_lib::{AppId, AppOrProxyId, ProxyId};
 use shared::{
-    config::{self, CONFIG_PROXY}, config_proxy, config_shared::ConfigCrypto, crypto::{self, CryptoPublicPortion}, crypto_jwt, errors::SamplyBeamError, http_client::SamplyHttpClient, reqwest, sse_event::SseEventType, DecryptableMsg, EncryptableMsg, EncryptedMessage, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, MessageType, Msg, MsgEmpty, MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, PlainMessage
+    capabilities::MSG_VERSION, config::{self, CONFIG_PROXY}, config_proxy, config_shared::ConfigCrypto, crypto::{self, CryptoPublicPortion}, crypto_jwt, envelope, errors::SamplyBeamError, http_client::SamplyHttpClient, metadata, reqwest, sse_event::SseEventType, DecryptableMsg, EncryptableMsg, EncryptedMessage, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, MessageType, Msg, MsgEmpty, MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, PlainMessage
 };
-use tokio::io::BufReader;
+use tokio::{io::BufReader, sync::Semaphore};
 use tracing::{debug, error, info, trace, warn};
 
-use crate::{auth::AuthenticatedApp, PROXY_TIMEOUT};
+use crate::{auth::AuthenticatedApp, broker_info, idempotency::{self, CachedResponse, Lookup}, recipients, spool::Spool, PROXY_TIMEOUT};
 
 #[derive(Clone, FromRef)]
 pub(crate) struct TasksState {
@@ -39,8 +40,9 @@ pub(crate) fn router(client: &SamplyHttpClient) -> Router {
     Router::new()
         // We need both path variants so the server won't send us into a redirect loop (/tasks, /tasks/, ...)
         .route("/v1/tasks", get(handler_task).post(handler_task))
+        .route("/v1/tasks/:task_id", delete(handler_task))
         .route("/v1/tasks/:task_id/results", get(handler_task))
-        .route("/v1/tasks/:task_id/results/:app_id", put(handler_task))
+        .route("/v1/tasks/:task_id/results/:app_id", put(handler_put_result))
         .with_state(state)
 }
 
@@ -60,12 +62,42 @@ const ERR_FAKED_FROM: (StatusCode, &str) = (
     "You are not authorized to send on behalf of this app.",
 );
 
+/// Upper bound of the parse error returned for malformed bodies so that it does not echo large parts of them
+const MAX_PARSE_ERROR_LEN: usize = 200;
+
+/// Reply to a body that is not valid JSON, pointing to where parsing failed
+#[derive(Serialize)]
+struct MalformedJson {
+    code: &'static str,
+    message: String,
+    line: usize,
+    column: usize,
+}
+
+fn malformed_json(e: &serde_json::Error) -> Response {
+    let mut message = e.to_string();
+    if message.len() > MAX_PARSE_ERROR_LEN {
+        let end = (0..=MAX_PARSE_ERROR_LEN).rev().find(|i| message.is_char_boundary(*i)).unwrap_or(0);
+        message.truncate(end);
+        message.push('…');
+    }
+    let body = MalformedJson { code: "invalid_json", message, line: e.line(), column: e.column() };
+    (StatusCode::BAD_REQUEST, Json(body)).into_response()
+}
+
+/// Lets apps declare the content type of a message's body, see [`metadata::annotate_content_type`]
+const BODY_CONTENT_TYPE: HeaderName = HeaderName::from_static("body-content-type");
+
 pub(crate) async fn forward_request(
     mut req: Request<axum::body::Body>,
     config: &config_proxy::Config,
     sender: &AppId,
     client: &SamplyHttpClient,
 ) -> Result<reqwest::Response, Response> {
+    broker_info::check_broker_id().map_err(|e| {
+        warn!("Not forwarding request of {sender}: {e}");
+        (StatusCode::BAD_GATEWAY, e.to_string()).into_response()
+    })?;
     // Create uri to contact broker
     let path = req.uri().path();
     let path_query = req
@@ -82,10 +114,10 @@ pub(crate) async fn forward_request(
         header::VIA,
         HeaderValue::from_static(env!("SAMPLY_USER_AGENT")),
     );
-    let (encrypted_msg, parts) = encrypt_request(req, &sender).await?;
+    let (encrypted_msg, parts, unresolved) = encrypt_request(req, &sender).await?;
     let req = sign_request(encrypted_msg, parts, &config, None).await.map_err(IntoResponse::into_response)?;
     trace!("Requesting: {:?}", req);
-    let resp = client.execute(req).await.map_err(|e| {
+    let mut resp = client.execute(req).await.map_err(|e| {
         if e.is_timeout() {
             debug!("Request to broker timed out after set proxy timeout of {PROXY_TIMEOUT}s");
             (StatusCode::GATEWAY_TIMEOUT, "Request to broker timed out ")
@@ -94,6 +126,14 @@ pub(crate) async fn forward_request(
             (StatusCode::BAD_GATEWAY, "Upstream error; see server logs.")
         }.into_response()
     })?;
+    broker_info::check_broker_tls(config, &resp).map_err(|e| {
+        warn!("Rejecting the broker's response: {e}");
+        (StatusCode::BAD_GATEWAY, "Upstream error; see server logs.").into_response()
+    })?;
+    if !unresolved.is_empty() {
+        let unresolved = unresolved.iter().map(ProxyId::to_string).collect::<Vec<_>>().join(",");
+        resp.headers_mut().insert(recipients::UNRESOLVED_RECIPIENTS, HeaderValue::from_str(&unresolved).expect("Proxy ids are valid header values"));
+    }
     Ok(resp)
 }
 
@@ -118,6 +158,10 @@ pub(crate) async fn handler_task(
         handler_tasks_stream(client, config, sender, req)
             .await
             .into_response()
+    } else if req.method() == Method::POST {
+        handler_post_idempotent(client, config, sender, &headers, req)
+            .await
+            .into_response()
     } else {
         handler_tasks_nostream(client, config, sender, req)
             .await
@@ -125,6 +169,75 @@ pub(crate) async fn handler_task(
     }
 }
 
+/// Submits a message; if the app sent an `Idempotency-Key`, a successful reply is cached and
+/// returned again for retries with the same key instead of submitting the message twice.
+/// Retries arriving while the first request is still being submitted wait for its reply.
+async fn handler_post_idempotent(
+    client: SamplyHttpClient,
+    config: config_proxy::Config,
+    sender: AppId,
+    headers: &HeaderMap,
+    req: Request,
+) -> Result<Response, Response> {
+    let Some(key) = idempotency::key_from_headers(headers).map_err(IntoResponse::into_response)? else {
+        return handler_tasks_nostream(client, config, sender, req).await;
+    };
+    let (parts, body) = req.into_parts();
+    let body = read_body_limited(body, CONFIG_PROXY.max_message_size).await.map_err(|e| {
+        warn!("Unable to read message body from {sender}: {e}");
+        match e {
+            SamplyBeamError::MessageTooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()).into_response(),
+            _ => ERR_BODY.into_response(),
+        }
+    })?;
+    let window = config.idempotency_window;
+    let reservation = loop {
+        match idempotency::SUBMISSIONS.begin(&sender, &key, &body, window, Instant::now()) {
+            Lookup::Cached(cached) => {
+                debug!("Returning cached reply for idempotency key {key} of app {sender}");
+                return Ok(cached.into_response());
+            },
+            Lookup::Mismatch => {
+                warn!("App {sender} reused idempotency key {key} for a different request");
+                return Err((StatusCode::UNPROCESSABLE_ENTITY, "Idempotency-Key has already been used for a different request").into_response());
+            },
+            Lookup::InFlight(mut done) => {
+                debug!("Waiting for the pending request with idempotency key {key} of app {sender}");
+                // Resolves once the pending request has finished or failed
+                _ = done.changed().await;
+            },
+            Lookup::Submit(reservation) => break reservation,
+        }
+    };
+    let req = Request::from_parts(parts, axum::body::Body::from(body));
+    let resp = handler_tasks_nostream(client, config, sender, req).await?;
+    if !resp.status().is_success() {
+        return Ok(resp);
+    }
+    let (cached, resp) = CachedResponse::buffer(resp).await.map_err(IntoResponse::into_response)?;
+    reservation.finish(cached, window, Instant::now());
+    Ok(resp)
+}
+
+// PUT /v1/tasks/:task_id/results/:app_id
+async fn handler_put_result(
+    State(client): State<SamplyHttpClient>,
+    State(config): State<config_proxy::Config>,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    Path((task_id, _)): Path<(MsgId, String)>,
+    headers: HeaderMap,
+    req: Request,
+) -> Response {
+    let spool = Spool::for_app(&config, &sender);
+    let resp = handler_task(State(client), State(config), AuthenticatedApp(sender), headers, req).await;
+    if let Some(spool) = spool.filter(|_| resp.status().is_success()) {
+        if let Err(e) = spool.ack(&task_id) {
+            warn!("Unable to remove task {task_id} from spool: {e}");
+        }
+    }
+    resp
+}
+
 async fn handler_tasks_nostream(
     client: SamplyHttpClient,
     config: config_proxy::Config,
@@ -347,7 +460,7 @@ pub async fn sign_request(
     let digest =
         crypto_jwt::make_extra_fields_digest(&parts.method, &parts.uri, &headers_mut, sig, &from)
             .map_err(|_| ERR_INTERNALCRYPTO)?;
-    let token_with_extended_signature = crypto_jwt::sign_to_jwt(&digest, private_crypto)
+    let token_with_extended_signature = crypto_jwt::sign_to_jwt_with_nonce(&digest, private_crypto)
         .await
         .map_err(|e| {
             error!("Crypto failed: {}", e);
@@ -391,7 +504,12 @@ pub(crate) async fn validate_and_decrypt(json: Value) -> Result<Value, SamplyBea
                 let msg = MsgSigned::<EncryptedMessage>::verify(&signed.jwt)
                     .await?
                     .msg;
-                Ok(serde_json::to_value(decrypt_msg(msg)?).expect("Should serialize fine"))
+                if let Some(content_type) = msg.get_metadata().get(metadata::CONTENT_TYPE_KEY) {
+                    debug!("Message from {} declares body content type {content_type}", msg.get_from());
+                }
+                let own_serials = crypto::get_own_serials().await?;
+                let plain = run_limited(DECRYPT_PERMITS.as_ref(), move || decrypt_msg(msg, &own_serials)).await?;
+                Ok(serde_json::to_value(plain).expect("Should serialize fine"))
             }
             Err(e) => Err(SamplyBeamError::JsonParseError(format!(
                 "Failed to parse broker response as a signed encrypted message. Err is {e}"
@@ -404,23 +522,44 @@ pub(crate) async fn validate_and_decrypt(json: Value) -> Result<Value, SamplyBea
     }
 }
 
-fn decrypt_msg<M: DecryptableMsg>(msg: M) -> Result<M::Output, SamplyBeamError> {
+/// Bounds the number of messages decrypted at the same time, cf. `--max-concurrent-decrypts`
+static DECRYPT_PERMITS: Lazy<Option<Semaphore>> = Lazy::new(|| CONFIG_PROXY.max_concurrent_decrypts.map(Semaphore::new));
+
+/// Runs CPU heavy work off the async runtime, waiting for one of `permits` first if given
+async fn run_limited<T: Send + 'static>(permits: Option<&Semaphore>, work: impl FnOnce() -> T + Send + 'static) -> T {
+    let _permit = match permits {
+        Some(permits) => Some(permits.acquire().await.expect("Semaphore is never closed")),
+        None => None,
+    };
+    tokio::task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+fn decrypt_msg<M: DecryptableMsg>(msg: M, own_serials: &[String]) -> Result<M::Output, SamplyBeamError> {
+    let own_crypto = crypto::get_own_crypto_material();
     msg.decrypt(
         &AppOrProxyId::Proxy(CONFIG_PROXY.proxy_id.to_owned()),
-        &crypto::get_own_crypto_material().privkey_rsa,
+        &own_crypto.privkey_rsa,
+        own_serials,
+        CONFIG_PROXY.assumed_cipher,
     )
 }
 
 async fn encrypt_request(
     mut req: Request,
     sender: &AppId,
-) -> Result<(EncryptedMessage, Parts), Response> {
-    let parts = req.extract_parts().await.unwrap();
-    let body: bytes::Bytes = req.extract().await.map_err(|e| {
-        warn!("Unable to read message body: {e}");
-        ERR_BODY.into_response()
+) -> Result<(EncryptedMessage, Parts, Vec<ProxyId>), Response> {
+    let parts: Parts = req.extract_parts().await.unwrap();
+    let body = read_body_limited(req.into_body(), CONFIG_PROXY.max_message_size).await.map_err(|e| {
+        warn!("Unable to read message body from {sender}: {e}");
+        match e {
+            SamplyBeamError::MessageTooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()).into_response(),
+            _ => ERR_BODY.into_response(),
+        }
     })?;
 
+    let mut unresolved = Vec::new();
     let msg = if body.is_empty() {
         debug!("Body is empty, substituting MsgEmpty.");
         PlainMessage::MsgEmpty(MsgEmpty {
@@ -428,9 +567,57 @@ async fn encrypt_request(
         })
     } else {
         match serde_json::from_slice(&body) {
-            Ok(val) => {
+            Ok(mut val) => {
                 debug!("Body is valid json");
-                val
+                if let Err(e) = recipients::resolve_recipients(&mut val, recipients::recipient_resolver()) {
+                    warn!("Rejecting message from {sender}: {e}");
+                    return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+                }
+                match recipients::check_resolvable(&mut val, CONFIG_PROXY.partial_encryption).await {
+                    Ok(proxies) if proxies.is_empty() => {},
+                    Ok(proxies) => {
+                        warn!("Not sending message from {sender} to recipients on {proxies:?} as their certificates could not be found");
+                        unresolved = proxies;
+                    },
+                    Err(SamplyBeamError::InvalidReceivers(proxies)) => {
+                        return Err((StatusCode::FAILED_DEPENDENCY, Json(proxies)).into_response());
+                    },
+                    Err(e) => {
+                        warn!("Unable to look up certificates of recipients: {e}");
+                        return Err(ERR_INTERNALCRYPTO.into_response());
+                    },
+                }
+                if let Some(metadata) = val.get_mut("metadata") {
+                    if let Err(e) = metadata::enforce_reserved_keys(metadata, CONFIG_PROXY.reserved_metadata_keys) {
+                        warn!("Rejecting message from {sender}: {e}");
+                        return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+                    }
+                }
+                if let (Some(defaults), Some(obj)) = (&CONFIG_PROXY.default_metadata, val.as_object_mut()) {
+                    let metadata = obj.entry("metadata").or_insert(Value::Null);
+                    metadata::merge_default_metadata(metadata, defaults, CONFIG_PROXY.default_metadata_overwrite);
+                }
+                if let (Some(declared), Some(obj)) = (parts.headers.get(BODY_CONTENT_TYPE), val.as_object_mut()) {
+                    let declared = declared.to_str().unwrap_or_default();
+                    let body = obj.get("body").and_then(Value::as_str).map(ToOwned::to_owned);
+                    let metadata = obj.entry("metadata").or_insert(Value::Null);
+                    if let Err(e) = metadata::annotate_content_type(metadata, declared, body.as_deref()) {
+                        warn!("Rejecting message from {sender}: {e}");
+                        return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+                    }
+                    debug!("Message from {sender} declares body content type {declared}");
+                }
+                let msg = PlainMessage::deserialize(&val).map_err(|e| {
+                    warn!("Received Body is not a valid message: {e}");
+                    ERR_BODY.into_response()
+                })?;
+                if CONFIG_PROXY.strict_envelope {
+                    if let Err(e) = envelope::check_unknown_fields(&msg, &val) {
+                        warn!("Rejecting message from {sender}: {e}");
+                        return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+                    }
+                }
+                msg
             }
             Err(e) => {
                 warn!(
@@ -438,7 +625,7 @@ async fn encrypt_request(
                     e,
                     std::str::from_utf8(&body).unwrap_or("(not valid UTF-8)")
                 );
-                return Err(ERR_BODY.into_response());
+                return Err(malformed_json(&e));
             }
         }
     };
@@ -446,21 +633,104 @@ async fn encrypt_request(
     if msg.get_from() != sender {
         return Err(ERR_FAKED_FROM.into_response());
     }
+    if let Err(e) = metadata::check_metadata(msg.get_metadata()) {
+        warn!("Rejecting message from {sender}: {e}");
+        return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+    }
     let body = encrypt_msg(msg).await.map_err(|e| {
         match e {
             SamplyBeamError::InvalidReceivers(proxies) => {
                 (StatusCode::FAILED_DEPENDENCY, Json(proxies)).into_response()
             }
+            e @ SamplyBeamError::UnsupportedMsgVersion { .. } => {
+                warn!("Rejecting message from {sender}: {e}");
+                (StatusCode::BAD_GATEWAY, e.to_string()).into_response()
+            }
             e => {
                 warn!("Encryption failed with: {e}");
                 ERR_INTERNALCRYPTO.into_response()
             }
         }
     })?;
-    Ok((body, parts))
+    Ok((body, parts, unresolved))
+}
+
+/// Reads the body chunk by chunk and aborts as soon as it exceeds `limit` bytes
+async fn read_body_limited(body: axum::body::Body, limit: usize) -> Result<Bytes, SamplyBeamError> {
+    let mut chunks = body.into_data_stream();
+    let mut buf = bytes::BytesMut::new();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| SamplyBeamError::RequestValidationFailed(e.to_string()))?;
+        if buf.len() + chunk.len() > limit {
+            return Err(SamplyBeamError::MessageTooLarge(limit));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
 }
 
-async fn encrypt_msg<M: EncryptableMsg>(msg: M) -> Result<M::Output, SamplyBeamError> {
+pub(crate) async fn encrypt_msg<M: EncryptableMsg>(msg: M) -> Result<M::Output, SamplyBeamError> {
+    broker_info::check_msg_version(MSG_VERSION)?;
     let receivers_keys = crypto::get_proxy_public_keys(msg.get_to()).await?;
-    msg.encrypt(&receivers_keys)
+    msg.encrypt(&receivers_keys, CONFIG_PROXY.emit_cipher)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn oversized_body_is_aborted_early() {
+        let chunks_read = Arc::new(AtomicUsize::new(0));
+        let counter = chunks_read.clone();
+        let body = axum::body::Body::from_stream(futures::stream::repeat_with(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Ok::<_, Infallible>(Bytes::from_static(&[0; 1024]))
+        }).take(1000));
+        let res = read_body_limited(body, 4 * 1024).await;
+        assert!(matches!(res, Err(SamplyBeamError::MessageTooLarge(4096))));
+        assert_eq!(chunks_read.load(Ordering::Relaxed), 5);
+
+        let body = axum::body::Body::from(vec![0; 4 * 1024]);
+        assert_eq!(read_body_limited(body, 4 * 1024).await.unwrap().len(), 4 * 1024);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_decrypts_are_bounded() {
+        let permits = Arc::new(Semaphore::new(2));
+        let (running, max_running) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let burst = (0..16).map(|_| {
+            let (permits, running, max_running) = (permits.clone(), running.clone(), max_running.clone());
+            tokio::spawn(async move {
+                run_limited(Some(&permits), move || {
+                    let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now_running, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }).await
+            })
+        }).collect::<Vec<_>>();
+        for work in burst {
+            work.await.unwrap();
+        }
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn malformed_json_points_to_the_error() {
+        let e = serde_json::from_slice::<Value>(b"{\n  \"to\": [\"app1.proxy1.broker\",]\n}").unwrap_err();
+        let resp = malformed_json(&e);
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_slice(&axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["code"], "invalid_json");
+        assert_eq!((body["line"].as_u64(), body["column"].as_u64()), (Some(2), Some(31)));
+
+        // Long payloads are not echoed back
+        let long = format!("[\"{}\", nope]", "x".repeat(1000));
+        let e = serde_json::from_str::<Vec<bool>>(&long).unwrap_err();
+        let body: Value = serde_json::from_slice(&axum::body::to_bytes(malformed_json(&e).into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert!(body["message"].as_str().unwrap().chars().count() <= MAX_PARSE_ERROR_LEN + 1);
+    }
 }
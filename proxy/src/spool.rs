@@ -0,0 +1,230 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{body::Body, http::{Request, StatusCode}};
+use beam_lib::{AppId, MsgId};
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+use shared::{config_proxy::Config, crypto_jwt, http_client::SamplyHttpClient};
+use tracing::{debug, info, warn};
+
+use crate::serve_tasks::{forward_request, validate_and_decrypt};
+
+/// The spool of `--spool`, shared by the polling task and the handler acknowledging results
+static SPOOL: OnceCell<Spool> = OnceCell::new();
+
+/// Directory into which decrypted tasks for a trusted local app are written as `<task_id>.json`
+pub(crate) struct Spool {
+    dir: PathBuf,
+    ids: Mutex<SpooledIds>,
+}
+
+#[derive(Default)]
+struct SpooledIds {
+    /// Tasks whose file has been written and not yet acknowledged
+    spooled: HashSet<MsgId>,
+    /// Tasks acknowledged recently and when, so a poll that fetched them before does not write them again
+    acked: HashMap<MsgId, Instant>,
+}
+
+impl Spool {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        Self { dir, ids: Default::default() }
+    }
+
+    /// Returns the spool of `app` if spooling has been configured for it
+    pub(crate) fn for_app(config: &Config, app: &AppId) -> Option<&'static Self> {
+        config.spool
+            .as_ref()
+            .filter(|(spool_app, _)| spool_app == app)
+            .map(|(_, dir)| SPOOL.get_or_init(|| Self::new(dir.clone())))
+    }
+
+    fn path(&self, id: &MsgId) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    /// Whether the task has been spooled or acknowledged already, so it need not be decrypted again
+    fn is_known(&self, id: &MsgId) -> bool {
+        let ids = self.ids.lock().unwrap();
+        ids.spooled.contains(id) || ids.acked.contains_key(id)
+    }
+
+    /// Writes the message readable only by the proxy's user unless it has already been spooled or acknowledged.
+    /// Returns true if the message was new.
+    pub(crate) fn store(&self, id: &MsgId, msg: &Value) -> io::Result<bool> {
+        // Held while writing so an acknowledgement can not remove the file in between
+        let mut ids = self.ids.lock().unwrap();
+        if ids.acked.contains_key(id) {
+            return Ok(false);
+        }
+        let path = self.path(id);
+        if path.exists() {
+            ids.spooled.insert(*id);
+            return Ok(false);
+        }
+        // Write to a temporary file first so the app never reads a partially written message
+        let tmp_path = self.dir.join(format!(".{id}.json.tmp"));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(msg)?)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        ids.spooled.insert(*id);
+        Ok(true)
+    }
+
+    /// Removes the message once the app has acknowledged it by submitting a result
+    pub(crate) fn ack(&self, id: &MsgId) -> io::Result<()> {
+        let mut ids = self.ids.lock().unwrap();
+        ids.spooled.remove(id);
+        ids.acked.insert(*id, Instant::now());
+        match fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Forgets acknowledgements older than `since`. Polls started after an acknowledgement no longer return the task.
+    fn forget_acked_before(&self, since: Instant) {
+        self.ids.lock().unwrap().acked.retain(|_, acked_at| *acked_at >= since);
+    }
+}
+
+/// Polls the broker on behalf of the spool app and writes its decrypted tasks to the spool
+pub(crate) fn spawn_spool_polling(client: SamplyHttpClient, config: Config) {
+    const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+    // Tasks stay `todo` until the app submits a result, so the broker answers right away while any of them is still spooled
+    const IDLE_INTERVAL: Duration = Duration::from_secs(5);
+    let Some((app, dir)) = config.spool.clone() else {
+        return;
+    };
+    warn!("Spooling decrypted tasks for {app} as plaintext files to {}", dir.display());
+    let spool = SPOOL.get_or_init(|| Spool::new(dir));
+    tokio::spawn(async move {
+        loop {
+            match poll_once(&client, &config, &app, spool).await {
+                Ok(0) => tokio::time::sleep(IDLE_INTERVAL).await,
+                Ok(_) => {},
+                Err(e) => {
+                    warn!("Unable to spool tasks for {app}; retrying in {}s: {e}", RETRY_INTERVAL.as_secs());
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    });
+}
+
+/// Returns the number of newly spooled tasks
+async fn poll_once(client: &SamplyHttpClient, config: &Config, app: &AppId, spool: &Spool) -> Result<usize, String> {
+    let started = Instant::now();
+    let req = Request::get("/v1/tasks?filter=todo&wait_count=1&wait_time=60s")
+        .body(Body::empty())
+        .expect("To build request successfully");
+    let resp = forward_request(req, config, app, client)
+        .await
+        .map_err(|resp| format!("Got status {} forwarding request", resp.status()))?;
+    if !matches!(resp.status(), StatusCode::OK | StatusCode::PARTIAL_CONTENT) {
+        return Err(format!("Got unexpected status {} from broker", resp.status()));
+    }
+    let body = resp.bytes().await.map_err(|e| e.to_string())?;
+    let Value::Array(signed) = serde_json::from_slice::<Value>(&body).map_err(|e| e.to_string())? else {
+        return Err("Broker did not return a list of tasks".into());
+    };
+    let unknown = skip_known(spool, signed);
+    let Value::Array(tasks) = validate_and_decrypt(Value::Array(unknown)).await.map_err(|e| e.to_string())? else {
+        return Err("Broker did not return a list of tasks".into());
+    };
+    let mut spooled = 0;
+    for task in tasks {
+        let Some(id) = task.get("id").and_then(|id| serde_json::from_value::<MsgId>(id.clone()).ok()) else {
+            warn!("Skipping task without a valid id");
+            continue;
+        };
+        match spool.store(&id, &task) {
+            Ok(true) => {
+                info!("Spooled task {id} for {app}");
+                spooled += 1;
+            },
+            Ok(false) => debug!("Task {id} has already been spooled"),
+            Err(e) => warn!("Unable to spool task {id}: {e}"),
+        }
+    }
+    spool.forget_acked_before(started);
+    Ok(spooled)
+}
+
+/// Drops the signed tasks that have been spooled or acknowledged before they are verified and decrypted once more
+fn skip_known(spool: &Spool, signed: Vec<Value>) -> Vec<Value> {
+    signed
+        .into_iter()
+        .filter(|task| {
+            let id = task.get("jwt").and_then(Value::as_str).and_then(crypto_jwt::unverified_msg_id);
+            !id.is_some_and(|id| spool.is_known(&id))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use serde_json::json;
+    use shared::ct_codecs::{Base64UrlSafeNoPadding, Encoder};
+
+    use super::*;
+
+    #[test]
+    fn spooled_message_is_removed_on_ack() {
+        let dir = std::env::temp_dir().join(format!("beam-spool-{}", MsgId::new()));
+        fs::create_dir(&dir).unwrap();
+        let spool = Spool::new(dir.clone());
+        let id = MsgId::new();
+        let msg = json!({"id": id, "body": "secret"});
+
+        assert!(spool.store(&id, &msg).unwrap());
+        assert!(!spool.store(&id, &msg).unwrap(), "Spooling twice must not rewrite the file");
+        let path = dir.join(format!("{id}.json"));
+        assert_eq!(serde_json::from_slice::<Value>(&fs::read(&path).unwrap()).unwrap(), msg);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        spool.ack(&id).unwrap();
+        assert!(!path.exists());
+        assert!(!spool.store(&id, &msg).unwrap(), "A poll in flight during the ack must not spool the task again");
+        assert!(!path.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn known_tasks_are_skipped_before_decryption() {
+        let dir = std::env::temp_dir().join(format!("beam-spool-{}", MsgId::new()));
+        fs::create_dir(&dir).unwrap();
+        let spool = Spool::new(dir.clone());
+        let signed = |id: &MsgId| {
+            let claims = Base64UrlSafeNoPadding::encode_to_string(json!({"id": id}).to_string()).unwrap();
+            json!({"jwt": format!("e30.{claims}.sig")})
+        };
+        let (spooled, acked, new) = (MsgId::new(), MsgId::new(), MsgId::new());
+        spool.store(&spooled, &json!({"id": spooled})).unwrap();
+        spool.store(&acked, &json!({"id": acked})).unwrap();
+        spool.ack(&acked).unwrap();
+
+        let unknown = skip_known(&spool, vec![signed(&spooled), signed(&acked), signed(&new)]);
+        assert_eq!(unknown, [signed(&new)]);
+        // Acknowledgements are only remembered for polls started before them
+        spool.forget_acked_before(Instant::now());
+        assert_eq!(skip_known(&spool, vec![signed(&acked)]).len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Cipher;
+
+/// Version of the message format spoken by this build
+pub const MSG_VERSION: u32 = 1;
+
+/// Algorithms and formats supported by this build
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Algorithms used for signing messages and wrapping symmetric keys
+    pub key_algs: Vec<String>,
+    pub ciphers: Vec<String>,
+    pub compressors: Vec<String>,
+    pub msg_versions: Vec<u32>,
+    /// Optional features this build has been compiled with
+    pub features: Vec<String>,
+    /// Id of the broker answering, derived from its `--broker-url`. Not reported by older brokers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broker_id: Option<String>,
+}
+
+pub fn capabilities() -> Capabilities {
+    let cipher_name = |cipher: Cipher| serde_json::to_value(cipher)
+        .ok()
+        .and_then(|v| v.as_str().map(ToOwned::to_owned))
+        .expect("Ciphers serialize to strings");
+    let mut features = Vec::new();
+    if cfg!(feature = "sockets") {
+        features.push("sockets".to_string());
+    }
+    Capabilities {
+        key_algs: vec!["RS256".to_string(), "RSA-OAEP-SHA256".to_string()],
+        ciphers: vec![cipher_name(Cipher::XChaCha20Poly1305), cipher_name(Cipher::XChaCha20Poly1305Bound)],
+        // Messages are not compressed
+        compressors: Vec::new(),
+        msg_versions: vec![MSG_VERSION],
+        features,
+        broker_id: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_match_features() {
+        let caps = capabilities();
+        assert_eq!(caps.ciphers, vec!["xchacha20poly1305", "xchacha20poly1305-bound"]);
+        assert!(caps.compressors.is_empty());
+        assert_eq!(caps.features.contains(&"sockets".to_string()), cfg!(feature = "sockets"));
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    cell::RefCell,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// Source of the current time for TTL and expiry checks
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+thread_local! {
+    static CLOCK_OVERRIDE: RefCell<Option<Arc<dyn Clock>>> = const { RefCell::new(None) };
+}
+
+/// Returns the current time of the clock in effect on this thread, which is the system clock unless overridden by [`with_clock`]
+pub fn now() -> SystemTime {
+    CLOCK_OVERRIDE
+        .with(|clock| clock.borrow().as_ref().map(|clock| clock.now()))
+        .unwrap_or_else(|| SystemClock.now())
+}
+
+/// Runs `f` with `clock` as the time source of the current thread
+pub fn with_clock<R>(clock: Arc<dyn Clock>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Arc<dyn Clock>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CLOCK_OVERRIDE.with(|clock| *clock.borrow_mut() = self.0.take());
+        }
+    }
+    let _restore = Restore(CLOCK_OVERRIDE.with(|current| current.borrow_mut().replace(clock)));
+    f()
+}
+
+/// A clock that only moves when advanced explicitly
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+impl MockClock {
+    pub fn new(start: SystemTime) -> Arc<Self> {
+        Arc::new(Self { now: Mutex::new(start) })
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct WithTtl {
+        #[serde(with = "crate::serialize_time")]
+        ttl: SystemTime,
+    }
+
+    #[test]
+    fn mock_clock_drives_ttl() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let clock = MockClock::new(start);
+        with_clock(clock.clone(), || {
+            let msg: WithTtl = serde_json::from_str(r#"{"ttl": "10s"}"#).unwrap();
+            assert_eq!(msg.ttl, start + Duration::from_secs(10));
+            clock.advance(Duration::from_secs(4));
+            assert_eq!(serde_json::to_string(&msg).unwrap(), r#"{"ttl":"6"}"#);
+            clock.advance(Duration::from_secs(7));
+            assert!(msg.ttl < now(), "Message should have expired");
+            assert_eq!(serde_json::to_string(&msg).unwrap(), r#"{"ttl":"0"}"#);
+        });
+        assert!(now() > start + Duration::from_secs(11), "System clock is restored afterwards");
+    }
+}
@@ -1,3 +1,6 @@
+use std::time::Duration;
+
+use fundu::DurationParser;
 use once_cell::sync::{Lazy, OnceCell};
 use tracing::debug;
 
@@ -37,6 +40,16 @@ pub static CONFIG_SHARED: Lazy<config_shared::Config> = Lazy::new(|| {
 
 pub(crate) static CONFIG_SHARED_CRYPTO: OnceCell<ConfigCrypto> = OnceCell::new();
 
+/// Parses durations in CLI arguments, e.g. `90s` or `5m`. Values without a unit are interpreted as seconds.
+pub(crate) fn parse_duration(value: &str) -> Result<Duration, String> {
+    DurationParser::default()
+        .default_unit(fundu::TimeUnit::Second)
+        .parse(value)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|e: fundu::TryFromDurationError| e.to_string())
+}
+
 pub fn prepare_env() {
     for var in ["http_proxy", "https_proxy", "all_proxy", "no_proxy"] {
         for (k, v) in std::env::vars().filter(|(k, _)| k.to_lowercase() == var) {
@@ -1,4 +1,4 @@
-use std::{fs::read_to_string, net::SocketAddr, path::PathBuf};
+use std::{fs::read_to_string, net::SocketAddr, path::PathBuf, time::Duration};
 
 use crate::{
     errors::SamplyBeamError,
@@ -53,6 +53,58 @@ struct CliArgs {
     #[clap(long, env, value_parser)]
     monitoring_api_key: Option<String>,
 
+    /// Time after which a socket connection waiting for its counterpart is dropped, e.g. 60s or 5m
+    #[clap(long, env, value_parser = crate::config::parse_duration, default_value = "60s")]
+    waiter_max_idle: Duration,
+
+    /// Maximum time a socket tunnel may stay open regardless of activity, e.g. 1h. Unlimited if unset.
+    #[clap(long, env, value_parser = crate::config::parse_duration)]
+    socket_max_lifetime: Option<Duration>,
+
+    /// Time after which a socket tunnel without any traffic in either direction is closed, e.g. 10m. Unlimited if unset.
+    #[clap(long, env, value_parser = crate::config::parse_duration)]
+    socket_max_idle: Option<Duration>,
+
+    /// Time after which a socket tunnel is closed if a peer does not accept data written to it, e.g. 30s. Unlimited if unset.
+    #[clap(long, env, value_parser = crate::config::parse_duration)]
+    socket_write_timeout: Option<Duration>,
+
+    /// Maximum number of socket requests and open tunnels between the same sender and recipient. Further socket requests of the pair are rejected. Unlimited if unset.
+    #[clap(long, env, value_parser)]
+    max_sockets_per_pair: Option<usize>,
+
+    /// Deliver tasks from one sender to one recipient in submission order, holding back later tasks until earlier ones have been fetched or expired
+    #[clap(long, env, value_parser)]
+    fifo_per_pair: bool,
+
+    /// Time until a task a recipient reported as `tempfailed` is handed out to it again, e.g. 30s. Redelivered on the next poll if unset.
+    #[clap(long, env, value_parser = crate::config::parse_duration)]
+    nack_redelivery_delay: Option<Duration>,
+
+    /// Double `--nack-redelivery-delay` with every further `tempfailed` result of the same recipient
+    #[clap(long, env, value_parser)]
+    nack_backoff: bool,
+
+    /// Stop handing out a task to a recipient once it has reported it as `tempfailed` this many times. Unlimited if unset.
+    #[clap(long, env, value_parser)]
+    max_delivery_attempts: Option<u32>,
+
+    /// Maximum number of distinct recipients a sender may address within `--distinct-recipients-window`. Messages to further recipients are rejected. Unlimited if unset.
+    #[clap(long, env, value_parser)]
+    max_distinct_recipients_per_sender: Option<usize>,
+
+    /// Maximum number of long-polling requests of one app or proxy waiting at the same time. Further ones are rejected with 429 Too Many Requests. Unlimited if unset.
+    #[clap(long, env, value_parser)]
+    max_long_polls_per_recipient: Option<usize>,
+
+    /// Reject new tasks and results with 507 Insufficient Storage while the stored messages take up this many bytes or more. Unlimited if unset.
+    #[clap(long, env, value_parser)]
+    store_max_bytes: Option<usize>,
+
+    /// Sliding window for `--max-distinct-recipients-per-sender`, e.g. 1h
+    #[clap(long, env, value_parser = crate::config::parse_duration, default_value = "1h")]
+    distinct_recipients_window: Duration,
+
     /// (included for technical reasons)
     #[clap(long, hide(true))]
     test_threads: Option<String>,
@@ -65,6 +117,19 @@ pub struct Config {
     pub pki_token: String,
     pub tls_ca_certificates_dir: Option<PathBuf>,
     pub monitoring_api_key: Option<String>,
+    pub waiter_max_idle: Duration,
+    pub socket_max_lifetime: Option<Duration>,
+    pub socket_max_idle: Option<Duration>,
+    pub socket_write_timeout: Option<Duration>,
+    pub max_sockets_per_pair: Option<usize>,
+    pub max_long_polls_per_recipient: Option<usize>,
+    pub fifo_per_pair: bool,
+    pub nack_redelivery_delay: Option<Duration>,
+    pub nack_backoff: bool,
+    pub max_delivery_attempts: Option<u32>,
+    pub max_distinct_recipients_per_sender: Option<usize>,
+    pub distinct_recipients_window: Duration,
+    pub store_max_bytes: Option<usize>,
 }
 
 impl crate::config::Config for Config {
@@ -90,6 +155,19 @@ impl crate::config::Config for Config {
             pki_token,
             tls_ca_certificates_dir: cli_args.tls_ca_certificates_dir,
             monitoring_api_key: cli_args.monitoring_api_key,
+            waiter_max_idle: cli_args.waiter_max_idle,
+            socket_max_lifetime: cli_args.socket_max_lifetime,
+            socket_max_idle: cli_args.socket_max_idle,
+            socket_write_timeout: cli_args.socket_write_timeout,
+            max_sockets_per_pair: cli_args.max_sockets_per_pair,
+            max_long_polls_per_recipient: cli_args.max_long_polls_per_recipient,
+            fifo_per_pair: cli_args.fifo_per_pair,
+            nack_redelivery_delay: cli_args.nack_redelivery_delay,
+            nack_backoff: cli_args.nack_backoff,
+            max_delivery_attempts: cli_args.max_delivery_attempts,
+            max_distinct_recipients_per_sender: cli_args.max_distinct_recipients_per_sender,
+            distinct_recipients_window: cli_args.distinct_recipients_window,
+            store_max_bytes: cli_args.store_max_bytes,
         };
         Ok(config)
     }
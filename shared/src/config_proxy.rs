@@ -10,14 +10,16 @@ use std::{
     path::{Path, PathBuf},
     process::exit,
     str::FromStr,
+    time::Duration,
 };
 
 use axum::http::HeaderValue;
 use serde::Deserialize;
+use serde_json::{Map, Value};
 use tracing::{debug, info, warn};
 
 use beam_lib::{AppId, ProxyId};
-use crate::errors::SamplyBeamError;
+use crate::{crypto::PartialEncryptionPolicy, errors::SamplyBeamError, metadata::ReservedKeyPolicy, Cipher};
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -27,6 +29,26 @@ pub struct Config {
     pub proxy_id: ProxyId,
     pub api_keys: HashMap<AppId, ApiKey>,
     pub tls_ca_certificates: Vec<reqwest::Certificate>,
+    pub prewarm_peers: Vec<ProxyId>,
+    pub max_message_size: usize,
+    pub spool: Option<(AppId, PathBuf)>,
+    pub pinned_broker_cert_sha256: Option<String>,
+    pub crypto_parallelism: Option<usize>,
+    pub max_concurrent_decrypts: Option<usize>,
+    pub socket_connect_retries: u32,
+    pub readiness_warmup: Duration,
+    pub startup_jitter: Duration,
+    pub broker_info_retry_interval: Duration,
+    pub compression_min_size: Option<u16>,
+    pub reserved_metadata_keys: ReservedKeyPolicy,
+    pub partial_encryption: PartialEncryptionPolicy,
+    pub default_metadata: Option<Map<String, Value>>,
+    pub default_metadata_overwrite: bool,
+    pub assumed_cipher: Option<Cipher>,
+    pub emit_cipher: Cipher,
+    pub strict_envelope: bool,
+    pub idempotency_window: Duration,
+    pub enrollment_status: bool,
 }
 
 pub type ApiKey = String;
@@ -47,6 +69,10 @@ pub struct CliArgs {
     #[clap(long, env, value_parser)]
     pub tls_ca_certificates_dir: Option<PathBuf>,
 
+    /// Fail instead of skipping files in the TLS CA directory that cannot be parsed as certificates
+    #[clap(long, env, value_parser)]
+    pub strict_ca_load: bool,
+
     /// The broker's base URL, e.g. https://broker23.beam.samply.de
     #[clap(long, env, value_parser)]
     pub broker_url: Url,
@@ -63,6 +89,90 @@ pub struct CliArgs {
     #[clap(long, env, value_parser, default_value = "/run/secrets/root.crt.pem")]
     rootcert_file: PathBuf,
 
+    /// Comma separated list of proxy ids whose certificates are fetched at startup, e.g. proxy1.broker23.beam.samply.de
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    pub prewarm_peers: Vec<String>,
+
+    /// Maximum size in bytes of a message submitted by an app. Larger uploads are aborted while reading.
+    #[clap(long, env, value_parser, default_value_t = 10 * 1024 * 1024)]
+    pub max_message_size: usize,
+
+    /// Name of a trusted local app, e.g. app1, whose tasks are decrypted and written to SPOOL_DIR as plaintext files. Requires SPOOL_DIR.
+    #[clap(long, env, value_parser, requires = "spool_dir")]
+    pub spool_app: Option<String>,
+
+    /// Directory to write the spooled tasks of SPOOL_APP to
+    #[clap(long, env, value_parser, requires = "spool_app")]
+    pub spool_dir: Option<PathBuf>,
+
+    /// SHA-256 fingerprint of the broker's TLS certificate, e.g. AB:CD:..; connections to a broker presenting a different certificate are aborted
+    #[clap(long, env, value_parser)]
+    pub pinned_broker_cert_sha256: Option<String>,
+
+    /// Number of threads used to encrypt a message's key for many recipients. Defaults to the number of CPUs.
+    #[clap(long, env, value_parser)]
+    pub crypto_parallelism: Option<usize>,
+
+    /// Maximum number of messages decrypted at the same time; further messages wait for a free slot. Unlimited if unset. Must be at least 1.
+    #[clap(long, env, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_concurrent_decrypts: Option<usize>,
+
+    /// How often to retry connecting a socket tunnel via the broker if the peer does not connect in time or the broker is briefly unavailable. Waits 1s before the first retry, doubling with every further one.
+    #[clap(long, env, value_parser, default_value_t = 0)]
+    pub socket_connect_retries: u32,
+
+    /// Time to wait after all startup checks, including certificate prewarming, have passed before `/healthz` reports the proxy as ready, e.g. 10s
+    #[clap(long, env, value_parser = crate::config::parse_duration, default_value = "0s")]
+    pub readiness_warmup: Duration,
+
+    /// Wait a random time of up to this long before contacting the broker at startup so that many proxies started at once spread their requests, e.g. 30s
+    #[clap(long, env, value_parser = crate::config::parse_duration, default_value = "0s")]
+    pub startup_jitter: Duration,
+
+    /// If the broker's capabilities could not be fetched at startup, wait this long before trying again, doubling the wait after each failed attempt
+    #[clap(long, env, value_parser = crate::config::parse_duration, default_value = "30s")]
+    pub broker_info_retry_interval: Duration,
+
+    /// Compress responses to apps of at least this many bytes with gzip or zstd if the app accepts it. Disabled if unset.
+    #[clap(long, env, value_parser)]
+    pub compression_min_size: Option<u16>,
+
+    /// How to handle metadata keys with the reserved `beam_` prefix in messages from apps: strip them or reject the message
+    #[clap(long, env, value_enum, default_value_t = ReservedKeyPolicy::Strip)]
+    pub reserved_metadata_keys: ReservedKeyPolicy,
+
+    /// What to do if no certificate can be found for some recipients of a message: reject it (all-or-nothing) or send it to the others (best-effort)
+    #[clap(long, env, value_enum, default_value_t = PartialEncryptionPolicy::AllOrNothing)]
+    pub partial_encryption: PartialEncryptionPolicy,
+
+    /// Json object whose keys are added to the metadata of every outgoing message, e.g. {"environment": "staging"}. Keys with the reserved `beam_` prefix are not allowed.
+    #[clap(long, env, value_parser = crate::metadata::parse_default_metadata)]
+    pub default_metadata: Option<Map<String, Value>>,
+
+    /// Let the keys of --default-metadata replace keys of the same name set by apps instead of keeping the app's values
+    #[clap(long, env, value_parser)]
+    pub default_metadata_overwrite: bool,
+
+    /// Cipher to assume for encrypted bodies that do not name one, e.g. xchacha20poly1305 for messages of proxies predating the cipher field. Such bodies are rejected if unset.
+    #[clap(long, env, value_enum)]
+    pub assumed_cipher: Option<Cipher>,
+
+    /// Cipher to encrypt outgoing messages with. Only switch to xchacha20poly1305-bound once every proxy of the federation can decrypt it, as older proxies fail to decrypt such messages.
+    #[clap(long, env, value_enum, default_value = "xchacha20poly1305")]
+    pub emit_cipher: Cipher,
+
+    /// Reject messages from apps whose envelope has fields beam does not know, e.g. because of a typo or a newer client. Unknown keys inside the metadata are always allowed.
+    #[clap(long, env, value_parser)]
+    pub strict_envelope: bool,
+
+    /// How long the reply to a task submission carrying an `Idempotency-Key` header is remembered and returned for retries with the same key
+    #[clap(long, env, value_parser = crate::config::parse_duration, default_value = "10m")]
+    pub idempotency_window: Duration,
+
+    /// Print whether this proxy is enrolled as JSON and exit with 0 if it is, 1 if it is not and 2 if the status could not be determined
+    #[clap(long, env, value_parser)]
+    pub enrollment_status: bool,
+
     /// (included for technical reasons)
     #[clap(long, hide(true))]
     test_threads: Option<String>,
@@ -109,8 +219,30 @@ impl crate::config::Config for Config {
         if api_keys.is_empty() {
             return Err(SamplyBeamError::ConfigurationFailed(format!("No API keys have been defined. Please set environment vars à la {0}_<clientname>_KEY=<key>", APP_PREFIX)));
         }
+        let prewarm_peers = cli_args.prewarm_peers
+            .iter()
+            .map(|peer| ProxyId::new(peer).map_err(|e| SamplyBeamError::ConfigurationFailed(format!(
+                "Invalid Beam ID \"{peer}\" supplied as prewarm peer: {e}"
+            ))))
+            .collect::<Result<_, _>>()?;
+        let spool = match (cli_args.spool_app, cli_args.spool_dir) {
+            (Some(app), Some(dir)) => {
+                let app_id = AppId::new(format!("{app}.{proxy_id}")).map_err(|e| SamplyBeamError::ConfigurationFailed(format!(
+                    "Invalid spool app \"{app}\": {e}"
+                )))?;
+                if !api_keys.contains_key(&app_id) {
+                    return Err(SamplyBeamError::ConfigurationFailed(format!("Spool app {app_id} has no API key configured")));
+                }
+                if !dir.is_dir() {
+                    return Err(SamplyBeamError::ConfigurationFailed(format!("Spool directory {} does not exist", dir.display())));
+                }
+                Some((app_id, dir))
+            }
+            _ => None,
+        };
         let tls_ca_certificates = crate::crypto::load_certificates_from_dir(
             cli_args.tls_ca_certificates_dir,
+            cli_args.strict_ca_load,
         )
         .map_err(|e| {
             SamplyBeamError::ConfigurationFailed(format!(
@@ -125,6 +257,26 @@ impl crate::config::Config for Config {
             proxy_id,
             api_keys,
             tls_ca_certificates,
+            prewarm_peers,
+            max_message_size: cli_args.max_message_size,
+            spool,
+            pinned_broker_cert_sha256: cli_args.pinned_broker_cert_sha256,
+            crypto_parallelism: cli_args.crypto_parallelism,
+            max_concurrent_decrypts: cli_args.max_concurrent_decrypts,
+            socket_connect_retries: cli_args.socket_connect_retries,
+            readiness_warmup: cli_args.readiness_warmup,
+            startup_jitter: cli_args.startup_jitter,
+            broker_info_retry_interval: cli_args.broker_info_retry_interval,
+            compression_min_size: cli_args.compression_min_size,
+            reserved_metadata_keys: cli_args.reserved_metadata_keys,
+            partial_encryption: cli_args.partial_encryption,
+            default_metadata: cli_args.default_metadata,
+            default_metadata_overwrite: cli_args.default_metadata_overwrite,
+            assumed_cipher: cli_args.assumed_cipher,
+            emit_cipher: cli_args.emit_cipher,
+            strict_envelope: cli_args.strict_envelope,
+            idempotency_window: cli_args.idempotency_window,
+            enrollment_status: cli_args.enrollment_status,
         };
         info!("Successfully read config and API keys from CLI and secrets file.");
         Ok(config)
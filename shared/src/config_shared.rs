@@ -15,9 +15,10 @@ use openssl::{
     asn1::Asn1IntegerRef,
     x509::{self, X509},
 };
-use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey, RsaPrivateKey};
-use std::{fs::read_to_string, path::PathBuf, rc::Rc, sync::Arc};
-use tracing::{debug, info};
+use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey, traits::PublicKeyParts, RsaPrivateKey};
+use serde::Serialize;
+use std::{fmt::Display, fs::read_to_string, path::PathBuf, rc::Rc, sync::Arc, time::Duration};
+use tracing::{debug, info, warn};
 
 pub(crate) const CLAP_FOOTER: &str = "For proxy support, environment variables HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY (and their lower-case variants) are supported. Usually, you want to set HTTP_PROXY *and* HTTPS_PROXY or set ALL_PROXY if both values are the same.\n\nFor updates and detailed usage instructions, visit https://github.com/samply/beam";
 
@@ -33,6 +34,10 @@ struct CliArgs {
     #[clap(long, env, value_parser)]
     tls_ca_certificates_dir: Option<PathBuf>,
 
+    /// Fail instead of skipping files in the TLS CA directory that cannot be parsed as certificates
+    #[clap(long, env, value_parser)]
+    strict_ca_load: bool,
+
     /// samply.pki: Path to own secret key
     #[clap(long, env, value_parser, default_value = "/run/secrets/privkey.pem")]
     privkey_file: PathBuf,
@@ -41,6 +46,50 @@ struct CliArgs {
     #[clap(long, env, value_parser, default_value = "/run/secrets/root.crt.pem")]
     rootcert_file: PathBuf,
 
+    /// Maximum nesting depth of JSON arrays and objects in a message's metadata
+    #[clap(long, env, value_parser, default_value_t = crate::metadata::DEFAULT_MAX_METADATA_DEPTH)]
+    max_metadata_depth: usize,
+
+    /// Maximum number of keys in a message's metadata, counting keys of nested objects as well
+    #[clap(long, env, value_parser, default_value_t = crate::metadata::DEFAULT_MAX_METADATA_KEYS)]
+    max_metadata_keys: usize,
+
+    /// Maximum number of valid certificates kept in the certificate cache. Least recently used ones are evicted and fetched again on demand. Unbounded if unset.
+    #[clap(long, env, value_parser)]
+    cert_cache_max_entries: Option<usize>,
+
+    /// Reject messages whose signed creation time is older than this when they are submitted, independent of their expiry. Disabled if unset.
+    #[clap(long, env, value_parser = crate::config::parse_duration)]
+    max_message_age_on_submit: Option<Duration>,
+
+    /// Minimum size in bits of RSA keys, both of this component's own key and of peer certificates
+    #[clap(long, env, value_parser, default_value_t = 2048)]
+    min_rsa_bits: u32,
+
+    /// Tolerated clock difference between proxies and broker when checking a message's creation time
+    #[clap(long, env, value_parser = crate::config::parse_duration, default_value = "30s")]
+    clock_skew_tolerance: Duration,
+
+    /// How long the broker remembers request nonces to reject replayed requests. Requests signed longer ago are rejected.
+    #[clap(long, env, value_parser = crate::config::parse_duration, default_value = "5m")]
+    dedup_window: Duration,
+
+    /// Reject requests without a nonce instead of accepting them without replay protection. Enable once all proxies send nonces.
+    #[clap(long, env, value_parser)]
+    require_nonce: bool,
+
+    /// Log only the first rejected request per reason within this window and count the others, e.g. 1m. By default, every rejection is logged.
+    #[clap(long, env, value_parser = crate::config::parse_duration, default_value = "0s")]
+    rejection_log_window: Duration,
+
+    /// Accept ids in messages (from, to, reply_to) with surrounding whitespace or a trailing dot and normalize them instead of rejecting them
+    #[clap(long, env, value_parser)]
+    lenient_ids: bool,
+
+    /// DEVELOPMENT ONLY: Trust self-signed peer certificates whose common name is a valid ProxyId. Only honored in debug builds.
+    #[clap(long, env, hide(true))]
+    dev_accept_self_signed: bool,
+
     // TODO: The following arguments have been added for compatibility reasons with the proxy config. Find another way to merge configs.
     /// (included for technical reasons)
     #[clap(long, env, value_parser)]
@@ -54,6 +103,10 @@ struct CliArgs {
     #[clap(action)]
     examples: Option<String>,
 
+    /// (included for technical reasons)
+    #[clap(long, env, hide(true))]
+    enrollment_status: bool,
+
     /// (included for technical reasons)
     #[clap(long, hide(true))]
     test_threads: Option<String>,
@@ -65,29 +118,94 @@ pub struct Config {
     pub broker_domain: String,
     pub root_cert: X509,
     pub tls_ca_certificates: Vec<Certificate>,
+    pub max_metadata_depth: usize,
+    pub max_metadata_keys: usize,
+    pub cert_cache_max_entries: Option<usize>,
+    pub max_message_age_on_submit: Option<Duration>,
+    pub clock_skew_tolerance: Duration,
+    pub dedup_window: Duration,
+    pub require_nonce: bool,
+    pub rejection_log_window: Duration,
+    pub min_rsa_bits: u32,
+    pub dev_accept_self_signed: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct ConfigCrypto {
     pub privkey_rs256: RS256KeyPair,
     pub privkey_rsa: RsaPrivateKey,
+    pub privkey_format: KeyFormat,
     pub public: Option<CryptoPublicPortion>,
 }
 
+impl ConfigCrypto {
+    /// Describes the private key without revealing it
+    pub fn key_summary(&self) -> KeySummary {
+        KeySummary { algorithm: "RSA", bits: self.privkey_rsa.size() * 8, format: self.privkey_format }
+    }
+}
+
+/// Encoding of a PEM private key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum KeyFormat {
+    #[serde(rename = "PKCS#1")]
+    Pkcs1,
+    #[serde(rename = "PKCS#8")]
+    Pkcs8,
+}
+
+/// Kind of private key in use, for diagnostics
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeySummary {
+    pub algorithm: &'static str,
+    pub bits: usize,
+    pub format: KeyFormat,
+}
+
+impl Display for KeySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = match self.format {
+            KeyFormat::Pkcs1 => "PKCS#1",
+            KeyFormat::Pkcs8 => "PKCS#8",
+        };
+        write!(f, "{} {} {format}", self.algorithm, self.bits)
+    }
+}
+
+/// Parses a PEM RSA private key in PKCS#1 or PKCS#8 encoding
+fn parse_private_key(pem: &str) -> Result<(RsaPrivateKey, KeyFormat), SamplyBeamError> {
+    RsaPrivateKey::from_pkcs1_pem(pem)
+        .map(|key| (key, KeyFormat::Pkcs1))
+        .or_else(|_| RsaPrivateKey::from_pkcs8_pem(pem).map(|key| (key, KeyFormat::Pkcs8)))
+        .map_err(|e| {
+            SamplyBeamError::ConfigurationFailed(format!(
+                "Unable to interpret private key PEM as an RSA key in PKCS#1 or PKCS#8 format: {}",
+                e
+            ))
+        })
+}
+
 impl crate::config::Config for Config {
     fn load() -> Result<Self, SamplyBeamError> {
         let cli_args = CliArgs::parse();
         beam_lib::set_broker_id(cli_args.broker_url.host().unwrap().to_string());
+        beam_lib::set_lenient_ids(cli_args.lenient_ids);
 
         let root_cert = crypto::load_certificates_from_file(cli_args.rootcert_file)?;
-        let broker_domain = cli_args.broker_url.host();
-        if false {
-            todo!() // TODO Tobias: Check if matches certificate, and fail
+        // Whether the broker's certificate matches this domain is checked on every response, see crypto::check_broker_certificate
+        let broker_domain = cli_args.broker_url.host().unwrap().to_string();
+        if cli_args.dev_accept_self_signed {
+            if !cfg!(debug_assertions) {
+                return Err(SamplyBeamError::ConfigurationFailed(
+                    "--dev-accept-self-signed is only available in debug builds and must never be used in production".into(),
+                ));
+            }
+            warn!("!!! --dev-accept-self-signed is set: self-signed peer certificates are trusted. This is INSECURE and meant for local development only !!!");
         }
-        let broker_domain = broker_domain.unwrap().to_string();
         let tls_ca_certificates_dir = cli_args.tls_ca_certificates_dir;
         let tls_ca_certificates = crate::crypto::load_certificates_from_dir(
             tls_ca_certificates_dir.clone(),
+            cli_args.strict_ca_load,
         )
         .map_err(|e| {
             SamplyBeamError::ConfigurationFailed(format!(
@@ -100,6 +218,16 @@ impl crate::config::Config for Config {
             tls_ca_certificates_dir,
             root_cert,
             tls_ca_certificates,
+            max_metadata_depth: cli_args.max_metadata_depth,
+            max_metadata_keys: cli_args.max_metadata_keys,
+            cert_cache_max_entries: cli_args.cert_cache_max_entries,
+            max_message_age_on_submit: cli_args.max_message_age_on_submit,
+            clock_skew_tolerance: cli_args.clock_skew_tolerance,
+            dedup_window: cli_args.dedup_window,
+            require_nonce: cli_args.require_nonce,
+            rejection_log_window: cli_args.rejection_log_window,
+            min_rsa_bits: cli_args.min_rsa_bits,
+            dev_accept_self_signed: cli_args.dev_accept_self_signed,
         })
     }
 }
@@ -117,7 +245,7 @@ fn get_enrollment_msg(proxy_id: &Option<String>) -> String {
 
 pub async fn init_public_crypto_for_proxy(
     private_config: ConfigCrypto,
-) -> Result<(String, String), SamplyBeamError> {
+) -> Result<(String, String, String), SamplyBeamError> {
     let cli_args = CliArgs::parse();
     let crypto = load_public_crypto_for_proxy(&cli_args, private_config).await?;
 
@@ -131,7 +259,7 @@ pub async fn init_public_crypto_for_proxy(
     if CONFIG_SHARED_CRYPTO.set(crypto).is_err() {
         panic!("Tried to initialize crypto twice (init_public_crypto_for_proxy())");
     }
-    Ok((cert_info.serial, cert_info.common_name))
+    Ok((cert_info.serial, cert_info.common_name, cert_info.fingerprint_sha256))
 }
 
 pub fn load_private_crypto_for_proxy() -> Result<ConfigCrypto, SamplyBeamError> {
@@ -144,17 +272,16 @@ pub fn load_private_crypto_for_proxy() -> Result<ConfigCrypto, SamplyBeamError>
                 e,
                 get_enrollment_msg(&cli_args.proxy_id)
             ))
-        })?
-        .trim()
-        .to_string();
-    let privkey_rsa = RsaPrivateKey::from_pkcs1_pem(&privkey_pem)
-        .or_else(|_| RsaPrivateKey::from_pkcs8_pem(&privkey_pem))
-        .map_err(|e| {
-            SamplyBeamError::ConfigurationFailed(format!(
-                "Unable to interpret private key PEM as PKCS#1 or PKCS#8: {}",
-                e
-            ))
         })?;
+    let privkey_pem = String::from_utf8(crypto::normalize_pem(privkey_pem.as_bytes()))
+        .expect("Removing ASCII characters keeps the key valid UTF-8");
+    let (privkey_rsa, privkey_format) = parse_private_key(&privkey_pem)?;
+    crypto::check_private_key_size(&privkey_rsa, cli_args.min_rsa_bits).map_err(|e| {
+        SamplyBeamError::ConfigurationFailed(format!(
+            "Refusing to use private key from file {}: {e}",
+            cli_args.privkey_file.to_string_lossy()
+        ))
+    })?;
     let privkey_rs256 = RS256KeyPair::from_pem(&privkey_pem).map_err(|e| {
         SamplyBeamError::ConfigurationFailed(format!(
             "Unable to interpret private key PEM as PKCS#1 or PKCS#8: {}",
@@ -164,6 +291,7 @@ pub fn load_private_crypto_for_proxy() -> Result<ConfigCrypto, SamplyBeamError>
     Ok(ConfigCrypto {
         privkey_rs256,
         privkey_rsa,
+        privkey_format,
         public: None,
     })
 }
@@ -183,6 +311,7 @@ async fn load_public_crypto_for_proxy(
                 .ok()
         })
         .collect();
+    crypto::check_unique_serials(&publics)?;
     let public = crypto::get_best_own_certificate(publics, &config.privkey_rsa).ok_or(
         SamplyBeamError::SignEncryptError(
             "Unable to choose valid, newest certificate for this proxy".into(),
@@ -194,7 +323,7 @@ async fn load_public_crypto_for_proxy(
     Ok(config)
 }
 
-fn asn_str_to_vault_str(asn: &Asn1IntegerRef) -> Result<String, SamplyBeamError> {
+pub(crate) fn asn_str_to_vault_str(asn: &Asn1IntegerRef) -> Result<String, SamplyBeamError> {
     let mut a = asn
         .to_bn()
         .map_err(|e| {
@@ -223,7 +352,7 @@ mod test {
         bn::BigNum,
     };
 
-    use super::asn_str_to_vault_str;
+    use super::*;
 
     #[test]
     fn hex_str() {
@@ -232,4 +361,23 @@ mod test {
         let expected = "44:0e:0d:94:f3:69:66:39:11:17:bc:9f:86:7d:84:f0:c4:8c:fc:b7";
         assert_eq!(expected, asn_str_to_vault_str(&input).unwrap());
     }
+
+    #[test]
+    fn key_summary() {
+        let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+        let pkcs1 = String::from_utf8(rsa.private_key_to_pem().unwrap()).unwrap();
+        let pkcs8 = String::from_utf8(openssl::pkey::PKey::from_rsa(rsa).unwrap().private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let summary = |pem: &str| {
+            let (privkey_rsa, privkey_format) = parse_private_key(pem).unwrap();
+            let privkey_rs256 = RS256KeyPair::from_pem(pem).unwrap();
+            ConfigCrypto { privkey_rs256, privkey_rsa, privkey_format, public: None }.key_summary()
+        };
+        assert_eq!(summary(&pkcs1), KeySummary { algorithm: "RSA", bits: 2048, format: KeyFormat::Pkcs1 });
+        assert_eq!(summary(&pkcs8).to_string(), "RSA 2048 PKCS#8");
+
+        // Beam signs with RS256, so Ed25519 keys are refused when loading
+        let ed25519 = openssl::pkey::PKey::generate_ed25519().unwrap().private_key_to_pem_pkcs8().unwrap();
+        let err = parse_private_key(std::str::from_utf8(&ed25519).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("RSA key"), "{err}");
+    }
 }
@@ -15,14 +15,14 @@ use rsa::{
 use sha2::{Digest, Sha256};
 use std::{
     borrow::BorrowMut,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     error::Error,
     fs::read_to_string,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
     time::{Duration, SystemTime},
 };
-use tokio::{sync::{mpsc, oneshot, RwLock}, time::Instant};
+use tokio::{sync::{mpsc, oneshot, RwLock, RwLockWriteGuard}, time::Instant};
 use tracing::{debug, error, info, warn};
 
 use beam_lib::{AppOrProxyId, ProxyId};
@@ -31,17 +31,21 @@ use crate::{
     config_shared::ConfigCrypto,
     crypto,
     errors::{CertificateInvalidReason, SamplyBeamError},
-    EncryptedMsgTaskRequest, MsgTaskRequest,
+    EncryptedMsgTaskRequest, MsgTaskRequest, RecipientKey,
 };
 
 type Serial = String;
 
+/// Called with the peer and its public portion whenever one of its certificates is (re)loaded into the cache
+pub type CertRefreshHook = Arc<dyn Fn(&ProxyId, &CryptoPublicPortion) + Send + Sync>;
+
 pub(crate) struct ProxyCertInfo {
     pub(crate) proxy_name: String,
     pub(crate) valid_since: String,
     pub(crate) valid_until: String,
     pub(crate) common_name: String,
     pub(crate) serial: String,
+    pub(crate) fingerprint_sha256: String,
 }
 
 impl TryFrom<&X509> for ProxyCertInfo {
@@ -77,6 +81,7 @@ impl TryFrom<&X509> for ProxyCertInfo {
                 .to_hex_str()
                 .map_err(|_e| SERIALERR)?
                 .to_string(),
+            fingerprint_sha256: fingerprint_sha256(&cert.to_der()?),
         };
         Ok(certinfo)
     }
@@ -109,6 +114,56 @@ pub struct CertificateCache {
     update_trigger: mpsc::UnboundedSender<oneshot::Sender<Result<CertificateCacheUpdate, SamplyBeamError>>>,
     root_cert: Option<X509>, // Might not be available at initialization time
     im_cert: Option<X509>,   // Might not be available at initialization time
+    /// Maximum number of valid certificates to keep, unbounded if None
+    max_entries: Option<usize>,
+    /// Trust self-signed peer certificates (development only, see `--dev-accept-self-signed`)
+    accept_self_signed: bool,
+    /// Order in which the valid certificates were last looked up. Locked separately as lookups only hold a read lock on the cache.
+    lru: std::sync::Mutex<LruOrder>,
+    /// Serials whose certificates were evicted. They are only fetched again when they are looked up.
+    /// Serials no longer listed by [`GetCerts::certificate_list_via_network`] are forgotten on the next update.
+    evicted: HashSet<Serial>,
+    refresh_hooks: Vec<CertRefreshHook>,
+    /// Certificates (re)loaded since the refresh hooks last ran
+    refreshed: Vec<CryptoPublicPortion>,
+}
+
+/// Tracks the order of lookups of certificates so the least recently used one can be found in logarithmic time
+#[derive(Default)]
+struct LruOrder {
+    use_counter: u64,
+    /// The value of `use_counter` at the last lookup of each serial
+    last_used: HashMap<Serial, u64>,
+    by_last_use: BTreeMap<u64, Serial>,
+}
+
+impl LruOrder {
+    fn len(&self) -> usize {
+        self.last_used.len()
+    }
+
+    /// Marks `serial` as used most recently, starting to track it if `insert` is set
+    fn touch(&mut self, serial: &str, insert: bool) {
+        let previous = match self.last_used.get_mut(serial) {
+            Some(last_used) => Some(std::mem::replace(last_used, self.use_counter)),
+            None if insert => {
+                self.last_used.insert(serial.to_string(), self.use_counter);
+                None
+            }
+            None => return,
+        };
+        if let Some(previous) = previous {
+            self.by_last_use.remove(&previous);
+        }
+        self.by_last_use.insert(self.use_counter, serial.to_string());
+        self.use_counter += 1;
+    }
+
+    fn pop_least_recently_used(&mut self) -> Option<Serial> {
+        let (_, serial) = self.by_last_use.pop_first()?;
+        self.last_used.remove(&serial);
+        Some(serial)
+    }
 }
 
 #[async_trait]
@@ -120,6 +175,25 @@ pub trait GetCerts: Sync + Send {
     async fn on_timer(&self, _cache: &mut CertificateCache) -> CertificateCacheUpdate { CertificateCacheUpdate::UnChanged }
     async fn on_cert_expired(&self, _expired_cert: X509) {}
     async fn get_crl(&self) -> Result<Option<X509Crl>, SamplyBeamError> { Ok(None) }
+    /// Fetches the certificates of the given peers ahead of time and reports for which peers this succeeded
+    async fn prewarm(&self, peers: &[ProxyId]) -> Vec<(ProxyId, Result<(), SamplyBeamError>)> {
+        if let Err(e) = CertificateCache::update_certificates().await {
+            warn!("Unable to update certificates before prewarming: {e}");
+        }
+        let report = CERT_CACHE.read().await.prewarm_report(peers);
+        log_prewarm_report(&report);
+        report
+    }
+}
+
+fn log_prewarm_report(report: &[(ProxyId, Result<(), SamplyBeamError>)]) {
+    let failed = report.iter().filter(|(_, res)| res.is_err()).count();
+    info!("Prewarmed certificates: {} ok, {failed} failed", report.len() - failed);
+    for (peer, res) in report {
+        if let Err(e) = res {
+            warn!("Unable to prewarm certificate of {peer}: {e}");
+        }
+    }
 }
 
 impl CertificateCache {
@@ -132,6 +206,102 @@ impl CertificateCache {
             update_trigger,
             root_cert: None,
             im_cert: None,
+            max_entries: None,
+            accept_self_signed: false,
+            lru: Default::default(),
+            evicted: HashSet::new(),
+            refresh_hooks: Vec::new(),
+            refreshed: Vec::new(),
+        }
+    }
+
+    /// Registers a hook that is called whenever a peer's certificate is (re)loaded into this cache.
+    /// Hooks run after the cache update that loaded the certificate has released the cache's lock.
+    /// They still run on the task updating the cache, so longer running reactions should be handed off to a separate task.
+    pub fn on_refresh(&mut self, hook: CertRefreshHook) {
+        self.refresh_hooks.push(hook);
+    }
+
+    fn record_refresh(&mut self, cn: &ProxyId, cert: &X509) {
+        if self.refresh_hooks.is_empty() {
+            return;
+        }
+        let Some(pubkey) = cert.public_key()
+            .and_then(|key| key.public_key_to_pem())
+            .ok()
+            .and_then(|pem| String::from_utf8(pem).ok())
+        else {
+            warn!("Unable to extract the public key of the refreshed certificate of {cn}");
+            return;
+        };
+        self.refreshed.push(CryptoPublicPortion { beam_id: cn.clone(), cert: cert.clone(), pubkey });
+    }
+
+    /// Releases the lock on the cache and then runs the refresh hooks for the certificates (re)loaded while it was held
+    fn run_refresh_hooks(mut cache: RwLockWriteGuard<'_, CertificateCache>) {
+        let refreshed = std::mem::take(&mut cache.refreshed);
+        let hooks = cache.refresh_hooks.clone();
+        drop(cache);
+        for public in &refreshed {
+            for hook in &hooks {
+                hook(&public.beam_id, public);
+            }
+        }
+    }
+
+    /// Marks a certificate as recently used so it is evicted last
+    fn touch(&self, serial: &str) {
+        self.lru.lock().unwrap().touch(serial, false);
+    }
+
+    fn insert_valid(&mut self, serial: Serial, cn: &ProxyId, cert: X509) {
+        let serials = self.cn_to_serial.entry(cn.clone()).or_default();
+        if !serials.contains(&serial) {
+            serials.push(serial.clone());
+        }
+        self.evicted.remove(&serial);
+        self.lru.get_mut().unwrap().touch(&serial, true);
+        self.record_refresh(cn, &cert);
+        self.serial_to_x509.insert(serial, CertificateCacheEntry::Valid(cert));
+        self.evict_least_recently_used();
+    }
+
+    /// Drops the least recently used certificates until at most `max_entries` valid ones remain.
+    /// Callers that are still using a certificate hold their own reference to it, so eviction does not affect them.
+    fn evict_least_recently_used(&mut self) {
+        let Some(max_entries) = self.max_entries else {
+            return;
+        };
+        let lru = self.lru.get_mut().unwrap();
+        while lru.len() > max_entries {
+            let Some(serial) = lru.pop_least_recently_used() else {
+                return;
+            };
+            debug!("Evicting certificate {serial} from cache");
+            self.serial_to_x509.remove(&serial);
+            self.evicted.insert(serial);
+        }
+    }
+
+    /// Forgets evicted certificates that are no longer listed, e.g. because they expired or were revoked,
+    /// so that the serials of evicted certificates do not pile up
+    fn forget_unlisted_evicted(&mut self, listed: &[String]) {
+        if self.evicted.is_empty() {
+            return;
+        }
+        let listed: HashSet<&String> = listed.iter().collect();
+        self.evicted.retain(|serial| listed.contains(serial));
+        let (serial_to_x509, evicted) = (&self.serial_to_x509, &self.evicted);
+        self.cn_to_serial.retain(|_, serials| {
+            serials.retain(|serial| serial_to_x509.contains_key(serial) || evicted.contains(serial));
+            !serials.is_empty()
+        });
+    }
+
+    /// Allows evicted certificates of the given peer to be fetched again
+    fn restore_evicted(&mut self, cname: &ProxyId) {
+        for serial in self.cn_to_serial.get(cname).into_iter().flatten() {
+            self.evicted.remove(serial);
         }
     }
 
@@ -204,7 +374,7 @@ impl CertificateCache {
         // TODO: What if multiple certs are found?
         let mut result = get_all_certs_from_cache_by_cname(cname).await; // Drop Read Locks
         if result.iter().filter(|cert| matches!(cert, CertificateCacheEntry::Valid(_))).count() == 0 {
-            
+            CERT_CACHE.write().await.restore_evicted(cname);
             // requires write lock.
             Self::update_certificates().await.unwrap_or_else(|e| {
                 warn!("Updating certificates failed: {}", e);
@@ -227,9 +397,11 @@ impl CertificateCache {
             // TODO: Do smart caching: Return reference to existing certificate that exists only once in memory.
             let cache = CERT_CACHE.read().await;
             if let Some(CertificateCacheEntry::Valid(cert)) = cache.serial_to_x509.get(serial) {
+                cache.touch(serial);
                 return Some(cert.clone());
             }
         }
+        CERT_CACHE.write().await.evicted.remove(serial);
         Self::update_certificates().await.unwrap_or_else(|e| {
             // requires write lock.
             warn!("Updating certificates failed: {}", e);
@@ -272,6 +444,29 @@ impl CertificateCache {
         }
     }
 
+    /// Checks for every peer whether the cache holds a currently valid certificate
+    fn prewarm_report(&self, peers: &[ProxyId]) -> Vec<(ProxyId, Result<(), SamplyBeamError>)> {
+        peers.iter().map(|peer| {
+            let entries = self.cn_to_serial
+                .get(peer)
+                .into_iter()
+                .flatten()
+                .filter_map(|serial| self.serial_to_x509.get(serial))
+                .collect::<Vec<_>>();
+            let has_valid = entries.iter().any(|entry| matches!(entry, CertificateCacheEntry::Valid(cert) if x509_date_valid(cert).unwrap_or(false)));
+            let result = if has_valid {
+                Ok(())
+            } else if let Some(CertificateCacheEntry::Invalid(reason)) = entries.iter().find(|entry| matches!(entry, CertificateCacheEntry::Invalid(_))) {
+                Err(SamplyBeamError::CertificateError(reason.clone()))
+            } else if entries.is_empty() {
+                Err(SamplyBeamError::InvalidReceivers(vec![peer.clone()]))
+            } else {
+                Err(SamplyBeamError::CertificateError(CertificateInvalidReason::InvalidDate))
+            };
+            (peer.clone(), result)
+        }).collect()
+    }
+
     fn invalidate_revoked_certs(&mut self, crl: &X509Crl) -> usize {
         let mut revoked_certs = 0;
         self.serial_to_x509.values_mut().for_each(|cert_entry| {
@@ -295,9 +490,10 @@ impl CertificateCache {
             .map(|crl| self.invalidate_revoked_certs(crl))
             .unwrap_or_default();
         debug!("Revoked {revoked_certs} certificates from cache.");
+        self.forget_unlisted_evicted(&certificate_list);
         let new_certificate_serials: Vec<&String> = certificate_list
             .iter()
-            .filter(|serial| !self.serial_to_x509.contains_key(*serial))
+            .filter(|serial| !self.serial_to_x509.contains_key(*serial) && !self.evicted.contains(*serial))
             .collect();
         debug!(
             "Received {} certificates ({} of which were new).",
@@ -355,12 +551,13 @@ impl CertificateCache {
             let err = {
                 if commonnames.is_empty() {
                     Some(CertificateInvalidReason::NoCommonName)
-                } else if let Err(e) = verify_cert(
+                } else if let Err(e) = verify_peer_cert(
                     &opensslcert,
                     &self
                         .im_cert
                         .as_ref()
                         .expect("No intermediate CA cert found"),
+                    self.accept_self_signed,
                 ) {
                     Some(e)
                 } else {
@@ -375,15 +572,7 @@ impl CertificateCache {
                 let cn = commonnames
                     .first()
                     .expect("Internal error: common names empty; this should not happen");
-                self.serial_to_x509
-                    .insert(serial.clone(), CertificateCacheEntry::Valid(opensslcert));
-                match self.cn_to_serial.get_mut(cn) {
-                    Some(serials) => serials.push(serial.clone()),
-                    None => {
-                        let new = vec![serial.clone()];
-                        self.cn_to_serial.insert(cn.clone(), new);
-                    }
-                };
+                self.insert_valid(serial.clone(), cn, opensslcert);
                 debug!("Added certificate {} for cname {}", serial, cn);
                 new_count += 1;
             }
@@ -460,12 +649,13 @@ async fn get_all_certs_from_cache_by_cname(cname: &ProxyId) -> Vec<CertificateCa
                                     warn!("Found invalid x509 certificate -- even unable to parse it.");
                                     continue;
                                 };
-                                warn!("Found x509 certificate with invalid date: CN={}, serial={}", info.common_name, info.serial);
+                                warn!("Found x509 certificate with invalid date: CN={}, serial={}, SHA-256={}", info.common_name, info.serial, info.fingerprint_sha256);
                             } else {
                                 debug!(
                                     "Certificate with serial {} successfully retrieved.",
                                     serial
                                 );
+                                cache.touch(serial);
                                 result.push(CertificateCacheEntry::Valid(x509.clone()));
                             }
                         }
@@ -487,6 +677,8 @@ async fn get_all_certs_from_cache_by_cname(cname: &ProxyId) -> Vec<CertificateCa
 pub async fn init_ca_chain() -> Result<(), SamplyBeamError> {
     let mut cache = CERT_CACHE.write().await;
     cache.set_root_cert(&config::CONFIG_SHARED.root_cert);
+    cache.max_entries = config::CONFIG_SHARED.cert_cache_max_entries;
+    cache.accept_self_signed = config::CONFIG_SHARED.dev_accept_self_signed;
     cache.set_im_cert().await?;
     Ok(())
 }
@@ -509,6 +701,16 @@ pub async fn get_serial_list() -> Vec<String> {
         .collect()
 }
 
+/// Fetches the certificates of the given peers ahead of time, see [`GetCerts::prewarm`]
+pub async fn prewarm(peers: &[ProxyId]) -> Vec<(ProxyId, Result<(), SamplyBeamError>)> {
+    CERT_GETTER.get().unwrap().prewarm(peers).await
+}
+
+/// Registers a hook on the certificate cache, see [`CertificateCache::on_refresh`]
+pub async fn on_refresh(hook: CertRefreshHook) {
+    CERT_CACHE.write().await.on_refresh(hook);
+}
+
 pub async fn get_im_cert() -> Result<String, SamplyBeamError> {
     CERT_GETTER.get().unwrap().im_certificate_as_pem().await
 }
@@ -546,6 +748,7 @@ pub(crate) static CERT_CACHE: Lazy<Arc<RwLock<CertificateCache>>> = Lazy::new(||
                 // Note: This currently only updates the Cache on the broker as the default implementation of `GetCerts` does no update the cache 
                 update = CERT_GETTER.get().unwrap().on_timer(&mut locked_cache).await;
             }
+            CertificateCache::run_refresh_hooks(locked_cache);
             if let CertificateCacheUpdate::Updated(count) = update {
                 info!("Added {count} new certificates.");
                 if let Err(e) = tx_newcerts.send(()).await {
@@ -584,6 +787,94 @@ pub struct CryptoPublicPortion {
     pub pubkey: String,
 }
 
+impl CryptoPublicPortion {
+    /// Serial of the certificate in the format used by the CA, e.g. `44:0e:0d:..`
+    pub fn serial(&self) -> Result<String, SamplyBeamError> {
+        crate::config_shared::asn_str_to_vault_str(self.cert.serial_number())
+    }
+
+    pub fn fingerprint_sha256(&self) -> String {
+        fingerprint_sha256(&self.cert.to_der().expect("Encoding a parsed certificate as DER should never fail"))
+    }
+}
+
+/// Formats the SHA-256 hash of a DER encoded certificate as colon separated uppercase hex, e.g. `AB:CD:...`
+pub fn fingerprint_sha256(cert_der: &[u8]) -> String {
+    Sha256::digest(cert_der).iter().map(|b| format!("{b:02X}")).join(":")
+}
+
+/// Checks that a DER encoded certificate was issued for `domain`, either as a DNS subject alternative name or, if it has none, as its common name.
+/// Wildcards are only accepted as the complete left-most label, e.g. `*.samply.de`.
+pub fn check_cert_matches_domain(cert_der: &[u8], domain: &str) -> Result<(), SamplyBeamError> {
+    let cert = X509::from_der(cert_der)?;
+    if let Ok(ip) = domain.parse::<std::net::IpAddr>() {
+        let octets = match ip {
+            std::net::IpAddr::V4(ip) => ip.octets().to_vec(),
+            std::net::IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let matched = cert
+            .subject_alt_names()
+            .is_some_and(|names| names.iter().any(|name| name.ipaddress() == Some(&octets[..])));
+        if !matched {
+            return Err(SamplyBeamError::ConfigurationFailed(format!(
+                "The broker's certificate was not issued for the configured broker address {domain}"
+            )));
+        }
+        return Ok(());
+    }
+    let matches = |name: &str| match name.strip_prefix("*.") {
+        Some(suffix) => domain
+            .split_once('.')
+            .is_some_and(|(_, rest)| rest.eq_ignore_ascii_case(suffix)),
+        None => name.eq_ignore_ascii_case(domain),
+    };
+    let dns_names: Vec<String> = cert
+        .subject_alt_names()
+        .map(|names| names.iter().filter_map(|name| name.dnsname().map(ToOwned::to_owned)).collect())
+        .unwrap_or_default();
+    let matched = if dns_names.is_empty() {
+        cert.subject_name()
+            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+            .filter_map(|cn| cn.data().to_string().ok())
+            .any(|cn| matches(&cn))
+    } else {
+        dns_names.iter().any(|name| matches(name))
+    };
+    if !matched {
+        return Err(SamplyBeamError::ConfigurationFailed(format!(
+            "The broker's certificate was not issued for the configured broker domain {domain}"
+        )));
+    }
+    Ok(())
+}
+
+/// Checks a certificate against a pinned SHA-256 fingerprint. Case and colons in `pinned` are ignored.
+pub fn check_pinned_fingerprint(pinned: &str, cert_der: &[u8]) -> Result<(), SamplyBeamError> {
+    let normalize = |fp: &str| fp.replace(':', "").to_ascii_uppercase();
+    let actual = fingerprint_sha256(cert_der);
+    if normalize(pinned) != normalize(&actual) {
+        return Err(SamplyBeamError::CertificateError(CertificateInvalidReason::Other(format!(
+            "Certificate fingerprint {actual} does not match the pinned fingerprint {pinned}"
+        ))));
+    }
+    Ok(())
+}
+
+/// Checks the TLS certificate the broker presented on a response: it has to be issued for `domain` and match `pinned` if set.
+/// Without TLS there is nothing to check unless a certificate is pinned.
+pub fn check_broker_certificate(cert_der: Option<&[u8]>, domain: &str, pinned: Option<&str>) -> Result<(), SamplyBeamError> {
+    match (cert_der, pinned) {
+        (Some(cert), pinned) => {
+            check_cert_matches_domain(cert, domain)?;
+            pinned.map_or(Ok(()), |pinned| check_pinned_fingerprint(pinned, cert))
+        }
+        (None, Some(_)) => Err(SamplyBeamError::ConfigurationFailed(
+            "A broker certificate is pinned but the broker did not present a TLS certificate".into()
+        )),
+        (None, None) => Ok(()),
+    }
+}
+
 pub async fn get_all_certs_and_clients_by_cname_as_pemstr(
     cname: &ProxyId,
 ) -> Vec<Result<CryptoPublicPortion, CertificateInvalidReason>> {
@@ -623,6 +914,7 @@ pub async fn get_newest_certs_for_cnames_as_pemstr(
 }
 
 fn extract_x509(cert: &X509) -> Result<CryptoPublicPortion, CertificateInvalidReason> {
+    check_cert_key_size(cert, config::CONFIG_SHARED.min_rsa_bits)?;
     // Public key
     let pubkey = cert
         .public_key()
@@ -683,6 +975,26 @@ pub fn verify_cert(
     }
 }
 
+/// Verify a peer certificate against the intermediate CA. If `accept_self_signed` is set,
+/// a certificate that is validly signed by its own key is accepted as well.
+fn verify_peer_cert(
+    certificate: &X509,
+    im_cert: &X509,
+    accept_self_signed: bool,
+) -> Result<(), CertificateInvalidReason> {
+    match verify_cert(certificate, im_cert) {
+        Err(e) if accept_self_signed => {
+            if verify_cert(certificate, certificate).is_ok() {
+                warn!("Accepting self-signed certificate for {:?} because --dev-accept-self-signed is set.", certificate.subject_name());
+                Ok(())
+            } else {
+                Err(e)
+            }
+        }
+        res => res,
+    }
+}
+
 pub(crate) fn hash(data: &[u8]) -> Result<[u8; 32], SamplyBeamError> {
     let mut hasher = Sha256::new();
     hasher.update(&data);
@@ -694,6 +1006,28 @@ pub(crate) fn hash(data: &[u8]) -> Result<[u8; 32], SamplyBeamError> {
 pub fn get_own_crypto_material() -> &'static ConfigCrypto {
     config::CONFIG_SHARED_CRYPTO.get().unwrap()
 }
+
+/// Serials of our own valid certificates that have the key of the certificate loaded at startup.
+/// Re-signing a certificate with the same key gives it a new serial, which senders then wrap message keys for.
+pub async fn get_own_serials() -> Result<Vec<String>, SamplyBeamError> {
+    let own = get_own_crypto_material().public.as_ref().expect("Own certificate to be loaded at startup");
+    let own_key = own.cert.public_key()?.public_key_to_der()?;
+    let mut serials = Vec::new();
+    for public in get_all_certs_and_clients_by_cname_as_pemstr(&own.beam_id).await.into_iter().flatten() {
+        if public.cert.public_key().and_then(|key| key.public_key_to_der()).is_ok_and(|key| key == own_key) {
+            serials.push(public.serial()?);
+        }
+    }
+    let startup_serial = own.serial()?;
+    if !serials.contains(&startup_serial) {
+        serials.push(startup_serial);
+    }
+    Ok(serials)
+}
+
+pub fn is_crypto_loaded() -> bool {
+    config::CONFIG_SHARED_CRYPTO.get().is_some()
+}
 /* Utility Functions */
 
 /// Extracts the pem-encoded public key from a x509 certificate
@@ -747,6 +1081,20 @@ pub fn x509_date_valid(cert: &X509) -> Result<bool, ErrorStack> {
     return Ok(expirydate > now && now > startdate);
 }
 
+/// Strips a UTF-8 byte order mark and surrounding whitespace and converts CRLF line endings to LF,
+/// so PEM files written on Windows parse on all platforms.
+pub fn normalize_pem(content: &[u8]) -> Vec<u8> {
+    let content = content.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(content).trim_ascii();
+    let mut normalized = Vec::with_capacity(content.len() + 1);
+    for (i, byte) in content.iter().enumerate() {
+        if *byte != b'\r' || content.get(i + 1) != Some(&b'\n') {
+            normalized.push(*byte);
+        }
+    }
+    normalized.push(b'\n');
+    normalized
+}
+
 pub fn load_certificates_from_file(ca_file: PathBuf) -> Result<X509, SamplyBeamError> {
     let file = ca_file.as_path();
     let content = std::fs::read(file).map_err(|e| {
@@ -756,7 +1104,7 @@ pub fn load_certificates_from_file(ca_file: PathBuf) -> Result<X509, SamplyBeamE
             e
         ))
     })?;
-    let cert = X509::from_pem(&content).map_err(|e| {
+    let cert = X509::from_pem(&normalize_pem(&content)).map_err(|e| {
         SamplyBeamError::ConfigurationFailed(format!(
             "Unable to read certificate from file {}: {}",
             file.to_string_lossy(),
@@ -766,15 +1114,23 @@ pub fn load_certificates_from_file(ca_file: PathBuf) -> Result<X509, SamplyBeamE
     cert
 }
 
-pub fn load_certificates_from_dir(ca_dir: Option<PathBuf>) -> Result<Vec<reqwest::Certificate>, std::io::Error> {
+/// Loads all certificates from `ca_dir`. Unparsable files are skipped with a warning unless `strict` is set,
+/// in which case they are an error.
+pub fn load_certificates_from_dir(ca_dir: Option<PathBuf>, strict: bool) -> Result<Vec<reqwest::Certificate>, std::io::Error> {
     let mut result = Vec::new();
     if let Some(ca_dir) = ca_dir {
         for file in ca_dir.read_dir()? {
             //.map_err(|e| SamplyBeamError::ConfigurationFailed(format!("Unable to read from TLS CA directory {}: {}", ca_dir.to_string_lossy(), e)))
             let path = file?.path();
             let content = std::fs::read(&path)?;
-            let cert = reqwest::Certificate::from_pem(&content);
+            let cert = reqwest::Certificate::from_pem(&normalize_pem(&content));
             if let Err(e) = cert {
+                if strict {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Unable to read certificate from file {}: {}", path.to_string_lossy(), e),
+                    ));
+                }
                 warn!(
                     "Unable to read certificate from file {}: {}",
                     path.to_string_lossy(),
@@ -788,6 +1144,25 @@ pub fn load_certificates_from_dir(ca_dir: Option<PathBuf>) -> Result<Vec<reqwest
     Ok(result)
 }
 
+/// Rejects RSA keys with a modulus shorter than `min_bits`
+fn check_rsa_key_size(bits: u32, min_bits: u32) -> Result<(), CertificateInvalidReason> {
+    if bits < min_bits {
+        return Err(CertificateInvalidReason::KeyTooSmall { bits, min_bits });
+    }
+    Ok(())
+}
+
+/// Rejects private keys with a modulus shorter than `min_bits`
+pub fn check_private_key_size(key: &RsaPrivateKey, min_bits: u32) -> Result<(), CertificateInvalidReason> {
+    check_rsa_key_size(key.n().bits() as u32, min_bits)
+}
+
+/// Rejects certificates whose public key is shorter than `min_bits`
+pub fn check_cert_key_size(cert: &X509, min_bits: u32) -> Result<(), CertificateInvalidReason> {
+    let key = cert.public_key().map_err(|_| CertificateInvalidReason::InvalidPublicKey)?;
+    check_rsa_key_size(key.bits(), min_bits)
+}
+
 /// Checks whether or not a x509 certificate matches a private key by comparing the (public) modulus
 pub fn is_cert_from_privkey(cert: &X509, key: &RsaPrivateKey) -> Result<bool, ErrorStack> {
     let cert_rsa = cert.public_key()?.rsa()?;
@@ -864,6 +1239,21 @@ pub(crate) fn get_best_own_certificate(
     get_newest_cert(&mut publics)
 }
 
+/// Fails if two of `publics` share a serial, as choosing the key to decrypt a message for would be ambiguous
+pub fn check_unique_serials(publics: &[CryptoPublicPortion]) -> Result<(), SamplyBeamError> {
+    let mut seen = HashSet::with_capacity(publics.len());
+    for public in publics {
+        let serial = public.serial()?;
+        if !seen.insert(serial.clone()) {
+            return Err(SamplyBeamError::ConfigurationFailed(format!(
+                "Found more than one certificate with serial {serial} for {}. Please revoke one of them in the central CA.",
+                public.beam_id
+            )));
+        }
+    }
+    Ok(())
+}
+
 /// Selecs the best fitting certificate from a vector of certs according to:
 /// 1) Is the current date in the valid date range?
 /// 2) Select the newest of the remaining
@@ -875,9 +1265,19 @@ pub fn get_best_other_certificate(
     get_newest_cert(&mut publics)
 }
 
+/// What to do if the certificates of only some recipients of a message can be found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PartialEncryptionPolicy {
+    /// Reject the message
+    #[default]
+    AllOrNothing,
+    /// Send the message to the remaining recipients only
+    BestEffort,
+}
+
 pub async fn get_proxy_public_keys(
     receivers: impl IntoIterator<Item = &AppOrProxyId>,
-) -> Result<Vec<RsaPublicKey>, SamplyBeamError> {
+) -> Result<Vec<RecipientKey>, SamplyBeamError> {
     let proxy_receivers: Vec<ProxyId> = receivers
         .into_iter()
         .map(|app_or_proxy| app_or_proxy.proxy_id())
@@ -887,8 +1287,11 @@ pub async fn get_proxy_public_keys(
     let (receivers_keys, proxies_with_invalid_certs): (Vec<_>, Vec<_>) = receivers_crypto_bundle
         .into_iter()
         .map(|crypt_publ_res| {
-            crypt_publ_res.and_then(|crypto|
-                rsa::RsaPublicKey::from_public_key_pem(&crypto.pubkey).map_err(|_| crypto.beam_id))
+            crypt_publ_res.and_then(|crypto| {
+                let key = rsa::RsaPublicKey::from_public_key_pem(&crypto.pubkey).map_err(|_| crypto.beam_id.clone())?;
+                let serial = crypto.serial().map_err(|_| crypto.beam_id)?;
+                Ok(RecipientKey { serial, key })
+            })
         })
         .partition_result();
     if proxies_with_invalid_certs.is_empty() {
@@ -906,8 +1309,10 @@ mod tests {
 
     fn build_x509(ttl: Duration) -> X509 {
         let mut builder = X509::builder().unwrap();
-        let duration = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap() + ttl;
-        let not_after = Asn1Time::from_unix(duration.as_secs() as i64).unwrap();
+        let now = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        let not_before = Asn1Time::from_unix((now - Duration::from_secs(60)).as_secs() as i64).unwrap();
+        let not_after = Asn1Time::from_unix((now + ttl).as_secs() as i64).unwrap();
+        builder.set_not_before(&not_before).unwrap();
         builder.set_not_after(&not_after).unwrap();
         builder.build()
     }
@@ -945,6 +1350,12 @@ mod tests {
             cn_to_serial: Default::default(),
             im_cert: None,
             root_cert: None,
+            max_entries: None,
+            accept_self_signed: false,
+            lru: Default::default(),
+            evicted: Default::default(),
+            refresh_hooks: Default::default(),
+            refreshed: Default::default(),
         };
         let cache = Arc::new(RwLock::new(cert_cache));
         let (_tx, mut rx) = mpsc::channel(1);
@@ -973,4 +1384,245 @@ mod tests {
         assert!(matches!(cache.serial_to_x509.get("3"), Some(&CertificateCacheEntry::Invalid(CertificateInvalidReason::Revoked))), "Certificate was not revoked");
         assert_eq!(cache.serial_to_x509.values().filter(|cert| matches!(cert, CertificateCacheEntry::Valid(..))).count(), 3, "No other certs have been invalidated");
     }
+
+    #[test]
+    fn test_prewarm_report() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let valid = ProxyId::new("proxy1.broker.samply.de").unwrap();
+        let revoked = ProxyId::new("proxy2.broker.samply.de").unwrap();
+        let unknown = ProxyId::new("proxy3.broker.samply.de").unwrap();
+        let mut cache = CertificateCache::new(mpsc::unbounded_channel().0);
+        cache.serial_to_x509 = [
+            ("1".to_string(), CertificateCacheEntry::Valid(build_x509(Duration::from_secs(60)))),
+            ("2".to_string(), CertificateCacheEntry::Invalid(CertificateInvalidReason::Revoked)),
+        ].into();
+        cache.cn_to_serial = [
+            (valid.clone(), vec!["1".to_string()]),
+            (revoked.clone(), vec!["2".to_string()]),
+        ].into();
+
+        let report = cache.prewarm_report(&[valid.clone(), revoked.clone(), unknown.clone()]);
+        assert_eq!(report.len(), 3);
+        assert!(matches!(&report[0], (id, Ok(())) if id == &valid));
+        assert!(matches!(&report[1], (id, Err(SamplyBeamError::CertificateError(CertificateInvalidReason::Revoked))) if id == &revoked));
+        assert!(matches!(&report[2], (id, Err(SamplyBeamError::InvalidReceivers(ids))) if id == &unknown && ids == &vec![unknown.clone()]));
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let proxies: Vec<_> = (1..=3).map(|i| ProxyId::new(format!("proxy{i}.broker.samply.de")).unwrap()).collect();
+        let mut cache = CertificateCache::new(mpsc::unbounded_channel().0);
+        cache.max_entries = Some(2);
+        cache.insert_valid("1".to_string(), &proxies[0], build_x509(Duration::from_secs(60)));
+        cache.insert_valid("2".to_string(), &proxies[1], build_x509(Duration::from_secs(60)));
+        cache.touch("1");
+        cache.insert_valid("3".to_string(), &proxies[2], build_x509(Duration::from_secs(60)));
+
+        assert!(cache.serial_to_x509.contains_key("1"));
+        assert!(!cache.serial_to_x509.contains_key("2"), "Least recently used certificate was not evicted");
+        assert!(cache.serial_to_x509.contains_key("3"));
+        assert!(cache.evicted.contains("2"));
+
+        cache.restore_evicted(&proxies[1]);
+        assert!(cache.evicted.is_empty());
+        assert_eq!(cache.lru.get_mut().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_unlisted_evicted_serials_are_forgotten() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let proxies: Vec<_> = (1..=3).map(|i| ProxyId::new(format!("proxy{i}.broker.samply.de")).unwrap()).collect();
+        let mut cache = CertificateCache::new(mpsc::unbounded_channel().0);
+        cache.max_entries = Some(1);
+        for (i, proxy) in proxies.iter().enumerate() {
+            cache.insert_valid(i.to_string(), proxy, build_x509(Duration::from_secs(60)));
+        }
+        assert_eq!(cache.evicted, HashSet::from(["0".to_string(), "1".to_string()]));
+
+        // Serial 0 is no longer listed, e.g. because it expired
+        cache.forget_unlisted_evicted(&["1".to_string(), "2".to_string()]);
+        assert_eq!(cache.evicted, HashSet::from(["1".to_string()]));
+        assert!(!cache.cn_to_serial.contains_key(&proxies[0]));
+        assert_eq!(cache.cn_to_serial[&proxies[1]], vec!["1".to_string()]);
+        assert_eq!(cache.cn_to_serial[&proxies[2]], vec!["2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_hook_fires() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let proxy = ProxyId::new("refreshed.broker.samply.de").unwrap();
+        let cache = Arc::new(RwLock::new(CertificateCache::new(mpsc::unbounded_channel().0)));
+        let refreshed = Arc::new(AtomicU64::new(0));
+        let (counter, watched, unlocked) = (refreshed.clone(), proxy.clone(), cache.clone());
+        cache.write().await.on_refresh(Arc::new(move |cn, public| {
+            // Hooks run once the cache is no longer locked
+            if cn == &watched && public.pubkey.contains("PUBLIC KEY") && unlocked.try_read().is_ok() {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+
+        let key = openssl::pkey::PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_pubkey(&key).unwrap();
+        let cert = builder.build();
+        let mut locked = cache.write().await;
+        locked.insert_valid("1".to_string(), &proxy, cert.clone());
+        locked.insert_valid("1".to_string(), &proxy, cert);
+        assert_eq!(refreshed.load(Ordering::Relaxed), 0);
+        CertificateCache::run_refresh_hooks(locked);
+        assert_eq!(refreshed.load(Ordering::Relaxed), 2);
+
+        // Each (re)load is only reported once
+        CertificateCache::run_refresh_hooks(cache.write().await);
+        assert_eq!(refreshed.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_min_rsa_key_size() {
+        for (bits, accepted) in [(1024, false), (2048, true)] {
+            let rsa = openssl::rsa::Rsa::generate(bits).unwrap();
+            let private = <RsaPrivateKey as rsa::pkcs1::DecodeRsaPrivateKey>::from_pkcs1_der(&rsa.private_key_to_der().unwrap()).unwrap();
+            let mut builder = X509::builder().unwrap();
+            builder.set_pubkey(&openssl::pkey::PKey::from_rsa(rsa).unwrap()).unwrap();
+            let cert = builder.build();
+
+            assert_eq!(check_private_key_size(&private, 2048).is_ok(), accepted);
+            let res = check_cert_key_size(&cert, 2048);
+            assert_eq!(res.is_ok(), accepted);
+            if let Err(e) = res {
+                assert_eq!(e.to_string(), "RSA key has 1024 bits but at least 2048 are required");
+            }
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_and_pinning() {
+        const FINGERPRINT: &str = "F7:A9:A9:1C:35:A1:47:D5:E7:EC:BC:C8:FC:CB:30:29:B9:F5:A5:DB:AF:89:C7:0A:F0:B1:F2:97:5A:72:0C:7C";
+        let der = X509::from_pem(CERT_TO_REVOKE).unwrap().to_der().unwrap();
+        assert_eq!(fingerprint_sha256(&der), FINGERPRINT);
+        assert!(check_pinned_fingerprint(FINGERPRINT, &der).is_ok());
+        assert!(check_pinned_fingerprint(&FINGERPRINT.replace(':', "").to_lowercase(), &der).is_ok());
+        let other = FINGERPRINT.replace("F7:A9", "00:00");
+        assert!(check_pinned_fingerprint(&other, &der).is_err());
+    }
+
+    fn build_x509_for(cn: &str, dns_names: &[&str]) -> X509 {
+        build_x509_with_serial(cn, dns_names, 0)
+    }
+
+    fn build_x509_with_serial(cn: &str, dns_names: &[&str], serial: u32) -> X509 {
+        let mut name = openssl::x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(openssl::nid::Nid::COMMONNAME, cn).unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_serial_number(&Asn1Integer::from_bn(&BigNum::from_u32(serial).unwrap()).unwrap()).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        if !dns_names.is_empty() {
+            let mut san = openssl::x509::extension::SubjectAlternativeName::new();
+            for dns in dns_names {
+                san.dns(dns);
+            }
+            let san = san.build(&builder.x509v3_context(None, None)).unwrap();
+            builder.append_extension(san).unwrap();
+        }
+        // DER encoding requires a signed certificate
+        let group = openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1).unwrap();
+        let key = openssl::pkey::PKey::from_ec_key(openssl::ec::EcKey::generate(&group).unwrap()).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.sign(&key, openssl::hash::MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn test_duplicate_serials_are_rejected() {
+        let public = |serial| CryptoPublicPortion {
+            beam_id: ProxyId::new_unchecked("proxy1.broker"),
+            cert: build_x509_with_serial("proxy1.broker", &[], serial),
+            pubkey: String::new(),
+        };
+        assert!(check_unique_serials(&[public(1), public(2)]).is_ok());
+        let err = check_unique_serials(&[public(1), public(2), public(2)]).unwrap_err();
+        assert!(matches!(&err, SamplyBeamError::ConfigurationFailed(msg) if msg.contains("serial 02")), "{err}");
+    }
+
+    #[test]
+    fn test_broker_cert_matches_domain() {
+        let cert = build_x509_for("broker.samply.de", &[]).to_der().unwrap();
+        assert!(check_cert_matches_domain(&cert, "broker.samply.de").is_ok());
+        assert!(check_cert_matches_domain(&cert, "evil.example.com").is_err());
+
+        // Subject alternative names take precedence over the common name
+        let cert = build_x509_for("beam", &["*.samply.de", "beam.example.com"]).to_der().unwrap();
+        assert!(check_cert_matches_domain(&cert, "broker.samply.de").is_ok());
+        assert!(check_cert_matches_domain(&cert, "beam.example.com").is_ok());
+        assert!(check_cert_matches_domain(&cert, "beam").is_err());
+        assert!(matches!(
+            check_cert_matches_domain(&cert, "deep.broker.samply.de"),
+            Err(SamplyBeamError::ConfigurationFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_broker_certificate_is_checked_on_every_response() {
+        let cert = build_x509_for("broker.samply.de", &[]).to_der().unwrap();
+        let fingerprint = fingerprint_sha256(&cert);
+        assert!(check_broker_certificate(Some(&cert), "broker.samply.de", None).is_ok());
+        assert!(check_broker_certificate(Some(&cert), "broker.samply.de", Some(&fingerprint)).is_ok());
+        assert!(check_broker_certificate(Some(&cert), "evil.example.com", Some(&fingerprint)).is_err());
+        let other = build_x509_for("broker.samply.de", &[]).to_der().unwrap();
+        assert!(check_broker_certificate(Some(&other), "broker.samply.de", Some(&fingerprint)).is_err());
+        assert!(check_broker_certificate(None, "broker.samply.de", None).is_ok());
+        assert!(check_broker_certificate(None, "broker.samply.de", Some(&fingerprint)).is_err());
+    }
+
+    #[test]
+    fn test_self_signed_rejected_by_default() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(!CertificateCache::new(tx).accept_self_signed);
+
+        let im_cert = build_x509_for("Samply.Beam Intermediate CA", &[]);
+        let self_signed = build_x509_for("proxy1.broker", &[]);
+        assert!(matches!(
+            verify_peer_cert(&self_signed, &im_cert, false),
+            Err(CertificateInvalidReason::InvalidPublicKey)
+        ));
+        assert!(verify_peer_cert(&self_signed, &im_cert, true).is_ok());
+    }
+
+    #[test]
+    fn test_strict_ca_load() {
+        let dir = std::env::temp_dir().join(format!("beam-cacerts-{}", crate::MsgId::new()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("valid.pem"), CERT_TO_REVOKE).unwrap();
+        std::fs::write(dir.join("corrupt.pem"), b"-----BEGIN CERTIFICATE-----\nnot base64!\n-----END CERTIFICATE-----").unwrap();
+
+        assert_eq!(load_certificates_from_dir(Some(dir.clone()), false).unwrap().len(), 1);
+        let err = load_certificates_from_dir(Some(dir.clone()), true).unwrap_err();
+        assert!(err.to_string().contains("corrupt.pem"), "{err}");
+
+        std::fs::remove_file(dir.join("corrupt.pem")).unwrap();
+        assert_eq!(load_certificates_from_dir(Some(dir.clone()), true).unwrap().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_windows_authored_pem() {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let pem = rsa::pkcs1::EncodeRsaPrivateKey::to_pkcs1_pem(&key, rsa::pkcs8::LineEnding::CRLF).unwrap();
+        let bom_pem = [b"\xEF\xBB\xBF".as_slice(), pem.as_bytes()].concat();
+        let decode = |pem: &[u8]| <RsaPrivateKey as rsa::pkcs1::DecodeRsaPrivateKey>::from_pkcs1_pem(std::str::from_utf8(pem).unwrap());
+        assert!(decode(&bom_pem).is_err());
+        assert!(jwt_simple::prelude::RS256KeyPair::from_pem(std::str::from_utf8(&bom_pem).unwrap()).is_err());
+        let normalized = normalize_pem(&bom_pem);
+        assert!(!normalized.contains(&b'\r'));
+        assert_eq!(decode(&normalized).unwrap(), key);
+        assert!(jwt_simple::prelude::RS256KeyPair::from_pem(std::str::from_utf8(&normalized).unwrap()).is_ok());
+
+        let crlf = String::from_utf8(CERT_TO_REVOKE.to_vec()).unwrap().replace('\n', "\r\n");
+        let bom_crlf = [b"\xEF\xBB\xBF".as_slice(), crlf.as_bytes(), b"\r\n"].concat();
+        assert_eq!(normalize_pem(&bom_crlf), [CERT_TO_REVOKE, b"\n"].concat());
+    }
 }
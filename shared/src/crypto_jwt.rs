@@ -1,4 +1,4 @@
-use std::net::{SocketAddr, IpAddr};
+use std::{collections::{HashSet, VecDeque}, net::{SocketAddr, IpAddr}, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
 
 use beam_lib::{AppOrProxyId, ProxyId};
 use crate::{
@@ -6,18 +6,18 @@ use crate::{
     config_shared::ConfigCrypto,
     crypto::{self, CryptoPublicPortion},
     errors::{CertificateInvalidReason, SamplyBeamError},
-    Msg, MsgEmpty, MsgId, MsgSigned,
+    metadata, Msg, MsgEmpty, MsgId, MsgSigned,
 };
 use axum::{async_trait, body::HttpBody, extract::{{FromRequest, ConnectInfo, FromRequestParts}, Request}, http::{header, request::Parts, uri::PathAndQuery, HeaderMap, HeaderName, Method, StatusCode, Uri}, BoxError, RequestExt};
 use jwt_simple::{
     claims::JWTClaims,
     prelude::{
-        Base64, Base64UrlSafeNoPadding, Claims, Duration, KeyMetadata, RS256KeyPair,
+        Base64, Base64UrlSafeNoPadding, Claims, Clock, Duration, KeyMetadata, RS256KeyPair,
         RS256PublicKey, RSAKeyPairLike, RSAPublicKeyLike, Token, VerificationOptions,
     },
     reexports::ct_codecs::Decoder,
 };
-use once_cell::unsync::Lazy;
+use once_cell::{sync::Lazy as SyncLazy, unsync::Lazy};
 use openssl::base64;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
@@ -25,6 +25,18 @@ use tracing::{debug, error, warn, Span, info_span};
 
 const ERR_SIG: (StatusCode, &str) = (StatusCode::UNAUTHORIZED, "Signature could not be verified");
 // const ERR_CERT: (StatusCode, &str) = (StatusCode::BAD_REQUEST, "Unable to retrieve matching certificate.");
+const ERR_REPLAY: (StatusCode, &str) = (
+    StatusCode::UNAUTHORIZED,
+    "Request has already been seen or is too old.",
+);
+const ERR_METADATA: (StatusCode, &str) = (
+    StatusCode::BAD_REQUEST,
+    "Message metadata is nested too deeply or has too many keys.",
+);
+const ERR_STALE: (StatusCode, &str) = (
+    StatusCode::BAD_REQUEST,
+    "Message was created too long ago or in the future.",
+);
 const ERR_FROM: (StatusCode, &str) = (
     StatusCode::BAD_REQUEST,
     "\"from\" field in message does not match your certificate.",
@@ -69,6 +81,7 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
     let metadata = Token::decode_metadata(token).map_err(|e| {
         SamplyBeamError::RequestValidationFailed(format!("Unable to decode JWT metadata: {}", e))
     })?;
+    let signer = claimed_signer(token)?;
     let public = if let Some(serial) = metadata.key_id() {
         crypto::get_cert_and_client_by_serial_as_pemstr(serial)
             .await
@@ -82,22 +95,7 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
     } else {
         // if it does not have a serial in the metadata try to get it by reading the from field in the body
         // this happens, e.g. during proxy initialization before a certificate (serial) is received
-        let data = token
-            .splitn(3, ".")
-            .nth(1)
-            .ok_or(SamplyBeamError::RequestValidationFailed(
-                "Invalid JWT in header".to_string(),
-            ))?;
-        let data = Base64UrlSafeNoPadding::decode_to_vec(data, None).map_err(|e| {
-            warn!("Failed to b64decode {data:?}. Err: {e}");
-            SamplyBeamError::RequestValidationFailed("Invalid JWT in header".to_string())
-        })?;
-        let json = serde_json::from_slice::<JWTClaims<HeaderClaim>>(&data).map_err(|e| {
-            warn!("Failed to decode {data:?} to JwtClaims<HeaderClaims>. Err: {e}");
-            SamplyBeamError::RequestValidationFailed("Invalid JWT body in header".to_string())
-        })?;
-        let proxy_id: ProxyId = json.custom.from.proxy_id();
-        let mut certs = crypto::get_all_certs_and_clients_by_cname_as_pemstr(&proxy_id)
+        let mut certs = crypto::get_all_certs_and_clients_by_cname_as_pemstr(&signer)
             .await
             .into_iter()
             .flatten()
@@ -107,6 +105,7 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
             CertificateInvalidReason::NoCommonName,
         ))?
     };
+    check_key_id(metadata.key_id(), &public.serial()?, &public.beam_id, &signer)?;
     let pubkey = RS256PublicKey::from_pem(&public.pubkey).map_err(|e| {
         SamplyBeamError::SignEncryptError(format!("Unable to initialize public key: {}", e))
     })?;
@@ -121,6 +120,66 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
     Ok((public, pubkey, content))
 }
 
+/// Reads the proxy that claims to have signed the token from the `from` field of its (not yet verified) claims
+fn claimed_signer(token: &str) -> Result<ProxyId, SamplyBeamError> {
+    #[derive(Deserialize)]
+    struct FromClaim {
+        // Header tokens abbreviate the field, see HeaderClaim
+        #[serde(alias = "f")]
+        from: AppOrProxyId,
+    }
+    let data = token
+        .splitn(3, ".")
+        .nth(1)
+        .ok_or(SamplyBeamError::RequestValidationFailed(
+            "Invalid JWT in header".to_string(),
+        ))?;
+    let data = Base64UrlSafeNoPadding::decode_to_vec(data, None).map_err(|e| {
+        warn!("Failed to b64decode {data:?}. Err: {e}");
+        SamplyBeamError::RequestValidationFailed("Invalid JWT in header".to_string())
+    })?;
+    let json = serde_json::from_slice::<FromClaim>(&data).map_err(|e| {
+        warn!("Failed to read the sender of JWT {data:?}. Err: {e}");
+        SamplyBeamError::RequestValidationFailed("Invalid JWT body in header".to_string())
+    })?;
+    Ok(json.from.proxy_id())
+}
+
+/// Reads the id of the message in a token without verifying it, e.g. to skip messages that have been processed before.
+/// Never trust the result for anything else.
+pub fn unverified_msg_id(token: &str) -> Option<MsgId> {
+    #[derive(Deserialize)]
+    struct IdClaim {
+        id: MsgId,
+    }
+    let data = token.split('.').nth(1)?;
+    let data = Base64UrlSafeNoPadding::decode_to_vec(data, None).ok()?;
+    serde_json::from_slice::<IdClaim>(&data).ok().map(|claim| claim.id)
+}
+
+/// Rejects tokens whose `kid` is not the serial of the certificate used to verify them
+/// or whose certificate does not belong to the claimed signer.
+fn check_key_id(kid: Option<&str>, cert_serial: &str, cert_owner: &ProxyId, signer: &ProxyId) -> Result<(), SamplyBeamError> {
+    if kid.is_some_and(|kid| kid != cert_serial) {
+        return Err(SamplyBeamError::RequestValidationFailed(format!(
+            "Key id {} does not match the serial {cert_serial} of the signing certificate",
+            kid.unwrap_or_default()
+        )));
+    }
+    check_cert_owner(&signer.clone().into(), cert_owner)
+}
+
+/// Rejects messages whose `from` does not belong to the proxy named in the common name of the certificate they are signed with,
+/// so that a valid certificate of one proxy cannot sign messages on behalf of another
+fn check_cert_owner(from: &AppOrProxyId, cert_owner: &ProxyId) -> Result<(), SamplyBeamError> {
+    if from.proxy_id() != *cert_owner {
+        return Err(SamplyBeamError::SignEncryptError(format!(
+            "Message from {from} is signed with the certificate of {cert_owner}"
+        )));
+    }
+    Ok(())
+}
+
 pub const JWT_VERIFICATION_OPTIONS: Lazy<VerificationOptions> = Lazy::new(|| VerificationOptions {
     accept_future: true,
     max_token_length: Some(1024 * 1024 * 100), //100MB
@@ -139,12 +198,12 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
     let token_with_extended_signature = req.headers
         .get(header::AUTHORIZATION)
         .ok_or_else(|| {
-            warn!(%ip, "Missing Authorization header");
+            crate::warn_rejected!("missing_authorization", %ip, "Missing Authorization header");
             ERR_SIG
         })?
         .to_str()
         .map_err(|e| {
-            warn!(%ip, "Unable to parse existing Authorization header: {e}");
+            crate::warn_rejected!("invalid_authorization", %ip, "Unable to parse existing Authorization header: {e}");
             ERR_SIG
         })?;
     let token_with_extended_signature =
@@ -154,12 +213,17 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
         extract_jwt::<HeaderClaim>(token_with_extended_signature)
             .await
             .map_err(|e| {
-                warn!(%ip, "Unable to extract header JWT: {e}. The full JWT was: {token_with_extended_signature}");
+                crate::warn_rejected!("invalid_header_token", %ip, "Unable to extract header JWT: {e}. The full JWT was: {token_with_extended_signature}");
                 ERR_SIG
             })?;
 
     Span::current().record("from", header_claims.custom.from.hide_broker());
 
+    if let Err(e) = SEEN_NONCES.check(header_claims.nonce.as_deref(), header_claims.issued_at, crate::clock::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().into()) {
+        crate::warn_rejected!("replay", %ip, "Rejecting request of {}: {e}", header_claims.custom.from);
+        return Err(ERR_REPLAY);
+    }
+
     // Check extra digest
 
     let custom = header_claims.custom;
@@ -167,22 +231,23 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
     let sender_claimed = custom.from;
 
     // Check if short token matches the long token
-    let msg = pubkey
+    let body_claims = pubkey
         .verify_token::<M>(
             token_without_extended_signature,
             Some(JWT_VERIFICATION_OPTIONS.clone()),
         )
         .map_err(|e| {
-            warn!(
+            crate::warn_rejected!(
+                "invalid_body_token",
                 "Unable to verify short token {}: {}",
                 token_without_extended_signature, e
             );
             ERR_SIG
-        })?
-        .custom;
+        })?;
+    let msg = body_claims.custom;
 
     let Some((_, sig)) = token_without_extended_signature.rsplit_once('.') else {
-        warn!("Cannot split signature from body token");
+        crate::warn_rejected!("invalid_body_token", "Cannot split signature from body token");
         return Err(ERR_SIG);
     };
     let sender_actual = msg.get_from();
@@ -197,7 +262,8 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
             .sig;
 
     if digest_actual != digest_claimed {
-        warn!(
+        crate::warn_rejected!(
+            "digest_mismatch",
             "Digests did not match: expected {}, received {}",
             digest_claimed, digest_actual
         );
@@ -205,7 +271,8 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
     }
 
     if sender_actual.to_owned() != sender_claimed {
-        warn!(
+        crate::warn_rejected!(
+            "sender_mismatch",
             "Sender did not match: expected {}, received {}",
             sender_claimed, sender_actual
         );
@@ -213,13 +280,23 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
     }
 
     // Check if Messages' "from" attribute can be signed by the proxy
-    if !msg.get_from().can_be_signed_by(&proxy_public_info.beam_id) {
-        warn!(
-            "Received messages' \"from\" attribute which should not have been signed by the proxy."
-        );
+    if let Err(e) = check_cert_owner(msg.get_from(), &proxy_public_info.beam_id) {
+        crate::warn_rejected!("forbidden_sender", "Rejecting message: {e}");
         return Err(ERR_FROM);
     }
-    // TODO: Check if Date header makes sense (replay attacks)
+
+    if let Some(max_age) = config::CONFIG_SHARED.max_message_age_on_submit {
+        let now = Duration::from(crate::clock::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default());
+        if let Err(e) = check_message_age(body_claims.issued_at, now, max_age.into(), config::CONFIG_SHARED.clock_skew_tolerance.into()) {
+            crate::warn_rejected!("stale", "Rejecting message from {sender_actual}: {e}");
+            return Err(ERR_STALE);
+        }
+    }
+
+    if let Err(e) = metadata::check_metadata(msg.get_metadata()) {
+        crate::warn_rejected!("invalid_metadata", "Rejecting message from {sender_actual}: {e}");
+        return Err(ERR_METADATA);
+    }
 
     let msg_signed = MsgSigned {
         msg,
@@ -232,8 +309,29 @@ pub async fn sign_to_jwt(
     input: impl Serialize,
     crypto_conf: Option<&ConfigCrypto>,
 ) -> Result<String, SamplyBeamError> {
+    sign_claims(to_claims(input)?, crypto_conf)
+}
+
+/// Like [`sign_to_jwt`] but includes a random nonce so that the broker can detect replayed requests
+pub async fn sign_to_jwt_with_nonce(
+    input: impl Serialize,
+    crypto_conf: Option<&ConfigCrypto>,
+) -> Result<String, SamplyBeamError> {
+    let mut claims = to_claims(input)?;
+    claims.create_nonce();
+    sign_claims(claims, crypto_conf)
+}
+
+fn to_claims(input: impl Serialize) -> Result<JWTClaims<Value>, SamplyBeamError> {
     let json = serde_json::to_value(input)
         .map_err(|e| SamplyBeamError::SignEncryptError(format!("Serialization failed: {}", e)))?;
+    Ok(Claims::with_custom_claims::<Value>(json, Duration::from_hours(1))) // TODO: Make variable
+}
+
+fn sign_claims(
+    claims: JWTClaims<Value>,
+    crypto_conf: Option<&ConfigCrypto>,
+) -> Result<String, SamplyBeamError> {
     let privkey = if let Some(ConfigCrypto { privkey_rs256, .. }) = crypto_conf {
         privkey_rs256
     } else {
@@ -243,8 +341,6 @@ pub async fn sign_to_jwt(
             .privkey_rs256
     };
 
-    let claims = Claims::with_custom_claims::<Value>(json, Duration::from_hours(1)); // TODO: Make variable
-
     let token = privkey
         .sign(claims)
         .map_err(|e| SamplyBeamError::SignEncryptError(format!("Unable to sign JWT: {}", e)))?;
@@ -252,6 +348,87 @@ pub async fn sign_to_jwt(
     Ok(token)
 }
 
+/// Upper bound of remembered nonces to keep the memory usage of the broker bounded
+const MAX_TRACKED_NONCES: usize = 1_000_000;
+
+/// Requests whose header token was issued longer ago than `--dedup-window` are rejected, so their nonces only need to be remembered for this long
+static SEEN_NONCES: SyncLazy<NonceCache> = SyncLazy::new(|| NonceCache::new(config::CONFIG_SHARED.dedup_window.into(), config::CONFIG_SHARED.clock_skew_tolerance.into(), MAX_TRACKED_NONCES, config::CONFIG_SHARED.require_nonce));
+
+/// Remembers the nonces of recently seen requests to reject replays
+struct NonceCache {
+    window: Duration,
+    /// Tolerated clock difference to the token's issuer, cf. `--clock-skew-tolerance`
+    skew: Duration,
+    capacity: usize,
+    /// Reject tokens without a nonce, cf. `--require-nonce`. Otherwise they are accepted without replay protection and counted.
+    require: bool,
+    without_nonce: AtomicU64,
+    seen: Mutex<SeenNonces>,
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashSet<String>,
+    by_expiry: VecDeque<(Duration, String)>,
+}
+
+impl NonceCache {
+    fn new(window: Duration, skew: Duration, capacity: usize, require: bool) -> Self {
+        Self { window, skew, capacity, require, without_nonce: AtomicU64::new(0), seen: Default::default() }
+    }
+
+    fn check(&self, nonce: Option<&str>, issued_at: Option<Duration>, now: Duration) -> Result<(), SamplyBeamError> {
+        let reject = |reason: &str| Err(SamplyBeamError::RequestValidationFailed(reason.to_string()));
+        let (Some(nonce), Some(issued_at)) = (nonce, issued_at) else {
+            if self.require {
+                return reject("Request token carries no nonce or issue time");
+            }
+            // Proxies predating nonces send none; log at 1, 2, 4, ... such requests to not flood the log
+            let count = self.without_nonce.fetch_add(1, Ordering::Relaxed) + 1;
+            if count.is_power_of_two() {
+                warn!("Accepted {count} requests without a nonce so far, which are not protected against replays. Upgrade all proxies and start the broker with --require-nonce.");
+            }
+            return Ok(());
+        };
+        if issued_at + self.window + self.skew < now {
+            return reject("Request token is too old");
+        }
+        let mut guard = self.seen.lock().unwrap();
+        let SeenNonces { nonces, by_expiry } = &mut *guard;
+        while by_expiry.front().is_some_and(|(expiry, _)| *expiry < now) {
+            let (_, expired) = by_expiry.pop_front().expect("Checked above");
+            nonces.remove(&expired);
+        }
+        if nonces.contains(nonce) {
+            return reject("Request has been replayed");
+        }
+        if nonces.len() >= self.capacity {
+            warn!("Tracking more than {} nonces; forgetting the oldest ones", self.capacity);
+            if let Some((_, oldest)) = by_expiry.pop_front() {
+                nonces.remove(&oldest);
+            }
+        }
+        // Remember the nonce for as long as the token passes the age check, which includes tokens from the future
+        by_expiry.push_back((issued_at.max(now) + self.window + self.skew, nonce.to_string()));
+        nonces.insert(nonce.to_string());
+        Ok(())
+    }
+}
+
+/// Checks that a message was created at most `max_age` before `now`, allowing for `skew` of clock difference in either direction
+fn check_message_age(issued_at: Option<Duration>, now: Duration, max_age: Duration, skew: Duration) -> Result<(), SamplyBeamError> {
+    let Some(issued_at) = issued_at else {
+        return Err(SamplyBeamError::RequestValidationFailed("Message carries no creation time".to_string()));
+    };
+    if issued_at + max_age + skew < now {
+        return Err(SamplyBeamError::RequestValidationFailed("Message was created too long ago".to_string()));
+    }
+    if issued_at > now + skew {
+        return Err(SamplyBeamError::RequestValidationFailed("Message was created in the future".to_string()));
+    }
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct HeaderClaim {
     #[serde(rename = "s")] //safes 2 bytes
@@ -312,3 +489,128 @@ async fn get_ip(parts: &mut Parts) -> IpAddr {
         .and_then(|v| v.parse().ok())
         .unwrap_or(source_ip)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replayed_nonce_is_rejected() {
+        let cache = NonceCache::new(Duration::from_mins(5), Duration::from_secs(0), 10, true);
+        let now = Clock::now_since_epoch();
+        assert!(cache.check(Some("nonce1"), Some(now), now).is_ok());
+        assert!(cache.check(Some("nonce1"), Some(now), now + Duration::from_secs(1)).is_err());
+        assert!(cache.check(Some("nonce2"), Some(now), now + Duration::from_secs(1)).is_ok());
+        // Missing nonces and tokens older than the window are rejected as well
+        assert!(cache.check(None, Some(now), now).is_err());
+        assert!(cache.check(Some("nonce3"), Some(now), now + Duration::from_mins(6)).is_err());
+    }
+
+    #[test]
+    fn test_nonce_of_token_from_slow_clock() {
+        let cache = NonceCache::new(Duration::from_mins(5), Duration::from_secs(30), 10, true);
+        let now = Clock::now_since_epoch();
+        // The issuer's clock is slightly behind ours
+        let behind = now - Duration::from_mins(5) - Duration::from_secs(10);
+        assert!(cache.check(Some("a"), Some(behind), now).is_ok());
+        // Its nonce is remembered for as long as the token is accepted
+        assert!(cache.check(Some("a"), Some(behind), now + Duration::from_secs(15)).is_err());
+        assert!(cache.check(Some("b"), Some(now - Duration::from_mins(6)), now).is_err());
+    }
+
+    #[test]
+    fn test_nonce_less_tokens_during_rollout() {
+        let cache = NonceCache::new(Duration::from_mins(5), Duration::from_secs(0), 10, false);
+        let now = Clock::now_since_epoch();
+        // Tokens of proxies predating nonces are accepted and counted
+        assert!(cache.check(None, None, now).is_ok());
+        assert!(cache.check(None, None, now).is_ok());
+        assert_eq!(cache.without_nonce.load(Ordering::Relaxed), 2);
+        // Tokens with a nonce are still protected against replays
+        assert!(cache.check(Some("nonce1"), Some(now), now).is_ok());
+        assert!(cache.check(Some("nonce1"), Some(now), now).is_err());
+    }
+
+    #[test]
+    fn test_nonce_cache_is_bounded() {
+        let cache = NonceCache::new(Duration::from_mins(5), Duration::from_secs(0), 2, true);
+        let now = Clock::now_since_epoch();
+        for nonce in ["a", "b", "c"] {
+            assert!(cache.check(Some(nonce), Some(now), now).is_ok());
+        }
+        assert_eq!(cache.seen.lock().unwrap().nonces.len(), 2);
+        // Once expired nonces are forgotten
+        let later = now + Duration::from_mins(6);
+        assert!(cache.check(Some("d"), Some(later), later).is_ok());
+        assert_eq!(cache.seen.lock().unwrap().nonces.len(), 1);
+    }
+
+    #[test]
+    fn test_nonces_expire_after_their_window() {
+        let cache = NonceCache::new(Duration::from_secs(30), Duration::from_secs(0), 10, true);
+        let now = Clock::now_since_epoch();
+        assert!(cache.check(Some("a"), Some(now), now).is_ok());
+        let later = now + Duration::from_secs(31);
+        assert!(cache.check(Some("b"), Some(later), later).is_ok());
+        assert_eq!(cache.seen.lock().unwrap().nonces.len(), 1);
+        // Tokens older than the window are rejected instead
+        assert!(cache.check(Some("c"), Some(now), later).is_err());
+    }
+
+    #[test]
+    fn test_message_age_on_submit() {
+        let now = Clock::now_since_epoch();
+        let max_age = Duration::from_mins(5);
+        let skew = Duration::from_secs(30);
+        assert!(check_message_age(Some(now), now, max_age, skew).is_ok());
+        // Creation times slightly off are tolerated within the skew
+        assert!(check_message_age(Some(now - max_age - Duration::from_secs(10)), now, max_age, skew).is_ok());
+        assert!(check_message_age(Some(now + Duration::from_secs(10)), now, max_age, skew).is_ok());
+        // Stale messages are rejected regardless of their expiry
+        assert!(check_message_age(Some(now - Duration::from_mins(6)), now, max_age, skew).is_err());
+        assert!(check_message_age(Some(now + Duration::from_mins(1)), now, max_age, skew).is_err());
+        assert!(check_message_age(None, now, max_age, skew).is_err());
+    }
+
+    #[test]
+    fn test_key_id_must_match_signer_cert() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let proxy1 = ProxyId::new("proxy1.broker.samply.de".to_string()).unwrap();
+        let proxy2 = ProxyId::new("proxy2.broker.samply.de".to_string()).unwrap();
+        let serial = "44:0e:0d:94";
+
+        assert!(check_key_id(Some(serial), serial, &proxy1, &proxy1).is_ok());
+        assert!(check_key_id(Some("44:0e:0d:95"), serial, &proxy1, &proxy1).is_err());
+        // The kid resolves to a valid certificate, but of another proxy
+        assert!(check_key_id(Some(serial), serial, &proxy2, &proxy1).is_err());
+        // Without a kid the signer's certificate is looked up by its name
+        assert!(check_key_id(None, serial, &proxy1, &proxy1).is_ok());
+        assert!(check_key_id(None, serial, &proxy2, &proxy1).is_err());
+    }
+
+    #[test]
+    fn test_from_must_match_cert_common_name() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let proxy1 = ProxyId::new("proxy1.broker.samply.de".to_string()).unwrap();
+        let from = |id: &str| AppOrProxyId::new(id).unwrap();
+
+        assert!(check_cert_owner(&from("app1.proxy1.broker.samply.de"), &proxy1).is_ok());
+        assert!(check_cert_owner(&from("proxy1.broker.samply.de"), &proxy1).is_ok());
+        let err = check_cert_owner(&from("app1.proxy2.broker.samply.de"), &proxy1).unwrap_err();
+        assert!(matches!(err, SamplyBeamError::SignEncryptError(_)), "{err}");
+        // Proxies whose name merely ends with the signer's are other proxies
+        assert!(check_cert_owner(&from("app1.xproxy1.broker.samply.de"), &proxy1).is_err());
+    }
+
+    #[test]
+    fn test_claimed_signer() {
+        use jwt_simple::reexports::ct_codecs::Encoder;
+
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let encode = |claims: &str| format!("e30.{}.sig", Base64UrlSafeNoPadding::encode_to_string(claims).unwrap());
+        let expected = ProxyId::new("proxy1.broker.samply.de".to_string()).unwrap();
+        assert_eq!(claimed_signer(&encode(r#"{"from":"app1.proxy1.broker.samply.de","to":[]}"#)).unwrap(), expected);
+        assert_eq!(claimed_signer(&encode(r#"{"f":"proxy1.broker.samply.de","s":"abc"}"#)).unwrap(), expected);
+        assert!(claimed_signer(&encode(r#"{"to":[]}"#)).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::Value;
+
+use crate::{MessageType, PlainMessage};
+
+// The envelopes below only list the fields of the messages apps send to their proxy.
+// Their types are checked when parsing the message itself; `deny_unknown_fields` does not work with the flattened bodies there.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct TaskRequestEnvelope {
+    id: Option<IgnoredAny>,
+    from: Option<IgnoredAny>,
+    to: Option<IgnoredAny>,
+    body: Option<IgnoredAny>,
+    ttl: Option<IgnoredAny>,
+    failure_strategy: Option<IgnoredAny>,
+    metadata: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct TaskResultEnvelope {
+    from: Option<IgnoredAny>,
+    to: Option<IgnoredAny>,
+    task: Option<IgnoredAny>,
+    status: Option<IgnoredAny>,
+    body: Option<IgnoredAny>,
+    metadata: Option<IgnoredAny>,
+}
+
+#[cfg(feature = "sockets")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct SocketRequestEnvelope {
+    from: Option<IgnoredAny>,
+    to: Option<IgnoredAny>,
+    ttl: Option<IgnoredAny>,
+    id: Option<IgnoredAny>,
+    secret: Option<IgnoredAny>,
+    metadata: Option<IgnoredAny>,
+}
+
+/// Rejects fields of the message envelope that are not part of its schema, cf. `--strict-envelope`.
+/// `json` is the message `msg` was parsed from. Fields inside the metadata are not checked.
+pub fn check_unknown_fields(msg: &PlainMessage, json: &Value) -> Result<(), serde_json::Error> {
+    match msg {
+        MessageType::MsgTaskRequest(_) => TaskRequestEnvelope::deserialize(json).map(drop),
+        MessageType::MsgTaskResult(_) => TaskResultEnvelope::deserialize(json).map(drop),
+        #[cfg(feature = "sockets")]
+        MessageType::MsgSocketRequest(_) => SocketRequestEnvelope::deserialize(json).map(drop),
+        // Denies unknown fields itself
+        MessageType::MsgEmpty(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn task_with(extra: Option<(&str, Value)>) -> Value {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let mut task = json!({
+            "id": "70c0aa90-bfcf-4312-a6af-42cbd57dc0b8",
+            "from": "app1.proxy1.broker.samply.de",
+            "to": ["app1.proxy2.broker.samply.de"],
+            "body": "Hello",
+            "ttl": "60s",
+            "failure_strategy": "discard",
+            "metadata": { "unknown_to_beam": true },
+        });
+        if let Some((key, value)) = extra {
+            task[key] = value;
+        }
+        task
+    }
+
+    #[test]
+    fn extra_envelope_field() {
+        let json = task_with(Some(("priority", json!("high"))));
+        // Lenient mode parses the message as before
+        let msg: PlainMessage = serde_json::from_value(json.clone()).unwrap();
+        assert!(matches!(msg, MessageType::MsgTaskRequest(_)));
+        // Strict mode rejects it, naming the field
+        let err = check_unknown_fields(&msg, &json).unwrap_err();
+        assert!(err.to_string().contains("priority"), "{err}");
+
+        // Known fields and anything inside the metadata are accepted in both modes
+        let json = task_with(None);
+        let msg: PlainMessage = serde_json::from_value(json.clone()).unwrap();
+        assert!(check_unknown_fields(&msg, &json).is_ok());
+    }
+}
@@ -2,6 +2,7 @@ use std::{net::AddrParseError, str::Utf8Error, string::FromUtf8Error};
 
 use openssl::error::ErrorStack;
 use reqwest::StatusCode;
+use serde::Serialize;
 use tokio::time::error::Elapsed;
 use beam_lib::ProxyId;
 
@@ -52,7 +53,72 @@ pub enum SamplyBeamError {
     #[error("Timeout executing HTTP request: {0}")]
     HttpTimeoutError(Elapsed),
     #[error("Invalid receivers: {0:?}")]
-    InvalidReceivers(Vec<ProxyId>)
+    InvalidReceivers(Vec<ProxyId>),
+    #[error("Message exceeds the maximum size of {0} bytes")]
+    MessageTooLarge(usize),
+    #[error("Broker does not support message version {version}, only {supported:?}")]
+    UnsupportedMsgVersion { version: u32, supported: Vec<u32> },
+    #[error("Broker reports id {reported} instead of {expected}; refusing to talk to a broker of another federation")]
+    BrokerIdMismatch { expected: String, reported: String },
+}
+
+impl SamplyBeamError {
+    /// A stable identifier of the error kind that clients can match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            SamplyBeamError::BindAddr(_) => "invalid_bind_address",
+            SamplyBeamError::WrongBrokerUri(_) => "invalid_broker_uri",
+            SamplyBeamError::RequestValidationFailed(_) => "validation_failed",
+            SamplyBeamError::InvalidPath => "invalid_path",
+            SamplyBeamError::InvalidClientIdString(_) => "invalid_client_id",
+            SamplyBeamError::JsonParseError(_) => "invalid_json",
+            SamplyBeamError::DecryptError(_) => "decryption_failed",
+            SamplyBeamError::SignEncryptError(_) => "encryption_failed",
+            SamplyBeamError::VaultSealed => "vault_sealed",
+            SamplyBeamError::VaultUnreachable(_) => "vault_unreachable",
+            SamplyBeamError::VaultNotInitialized => "vault_not_initialized",
+            SamplyBeamError::VaultRedirectError(..) => "vault_redirect",
+            SamplyBeamError::VaultOtherError(_) => "vault_error",
+            SamplyBeamError::ConfigurationFailed(_) => "configuration_failed",
+            SamplyBeamError::InternalSynchronizationError(_) => "internal_error",
+            SamplyBeamError::HttpRequestError(_) => "upstream_request_failed",
+            SamplyBeamError::HttpProxyProblem(_) => "http_proxy_error",
+            SamplyBeamError::InvalidBeamId(_) => "invalid_beam_id",
+            SamplyBeamError::HttpParseError(_) => "invalid_http_response",
+            SamplyBeamError::CertificateError(_) => "invalid_certificate",
+            SamplyBeamError::HttpTimeoutError(_) => "upstream_timeout",
+            SamplyBeamError::InvalidReceivers(_) => "invalid_receivers",
+            SamplyBeamError::MessageTooLarge(_) => "message_too_large",
+            SamplyBeamError::UnsupportedMsgVersion { .. } => "unsupported_msg_version",
+            SamplyBeamError::BrokerIdMismatch { .. } => "broker_id_mismatch",
+        }
+    }
+}
+
+/// JSON body describing why a request was rejected
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    /// The field of the request that caused the error, if any
+    pub field: Option<&'static str>,
+}
+
+impl ErrorBody {
+    pub fn with_field(mut self, field: &'static str) -> Self {
+        self.field = Some(field);
+        self
+    }
+}
+
+impl From<&SamplyBeamError> for ErrorBody {
+    fn from(e: &SamplyBeamError) -> Self {
+        Self {
+            code: e.code(),
+            message: e.to_string(),
+            field: None,
+        }
+    }
 }
 
 impl From<AddrParseError> for SamplyBeamError {
@@ -87,6 +153,8 @@ pub enum CertificateInvalidReason {
     InvalidDate,
     #[error("Problem with the certificate's public key")]
     InvalidPublicKey,
+    #[error("RSA key has {bits} bits but at least {min_bits} are required")]
+    KeyTooSmall { bits: u32, min_bits: u32 },
     #[error("Internal error: {0}")]
     InternalError(String),
     #[error("Not disclosed: Broker considers this certificate invalid")]
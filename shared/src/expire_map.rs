@@ -43,7 +43,31 @@ impl<K: Hash + Eq, V> LazyExpireMap<K, V> {
         self.map.insert(key, (value, instant.into())).map(|(v, _)| v)
     }
 
-    pub fn retain_expired(&self) {
-        self.map.retain(|_, v| v.1 < Instant::now())
+    /// Drops all entries whose expiry has passed
+    pub fn remove_expired(&self) {
+        let now = Instant::now();
+        self.map.retain(|_, v| v.1 > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn abandoned_waiter_is_evicted() {
+        let map = LazyExpireMap::default();
+        let (abandoned_tx, abandoned_rx) = oneshot::channel::<()>();
+        let (tx, _rx) = oneshot::channel::<()>();
+        map.insert_for(Duration::from_millis(10), 1, abandoned_tx);
+        map.insert_for(Duration::from_secs(60), 2, tx);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        map.remove_expired();
+        assert_eq!(map.len(), 1);
+        assert!(map.get(&2).is_some());
+        // Evicting the sender wakes up whoever waited on it
+        assert!(abandoned_rx.await.is_err());
     }
 }
@@ -17,7 +17,8 @@ pub fn build(
     timeout: Option<Duration>,
     keepalive: Option<Duration>,
 ) -> Result<SamplyHttpClient, SamplyBeamError> {
-    let mut builder = Client::builder().tcp_keepalive(keepalive);
+    // Exposes the peer certificate so it can be checked against a pinned fingerprint
+    let mut builder = Client::builder().tcp_keepalive(keepalive).tls_info(true);
     if let Some(to) = timeout {
         builder = builder.connect_timeout(to);
     }
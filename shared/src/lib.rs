@@ -3,7 +3,7 @@
 use axum::async_trait;
 use beam_lib::{AppId, AppOrProxyId, ProxyId, FailureStrategy, WorkStatus};
 use chacha20poly1305::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
     XChaCha20Poly1305, XNonce,
 };
 use crypto_jwt::extract_jwt;
@@ -19,7 +19,7 @@ use tracing::debug;
 use std::{
     fmt::{Debug, Display},
     ops::Deref,
-    time::{Duration, Instant, SystemTime}, net::SocketAddr, error::Error,
+    time::{Duration, Instant, SystemTime}, net::SocketAddr, error::Error, sync::atomic::{AtomicUsize, Ordering},
 };
 
 use rand::Rng;
@@ -39,11 +39,16 @@ pub type MsgId = beam_lib::MsgId;
 pub type MsgType = String;
 pub type TaskResponse = String;
 
+pub mod capabilities;
+pub mod clock;
 pub mod crypto;
 pub mod crypto_jwt;
+pub mod envelope;
 pub mod errors;
 pub mod serde_helpers;
 pub mod logger;
+pub mod metadata;
+pub mod rejections;
 mod traits;
 #[cfg(test)]
 mod serializing_compatibility_test;
@@ -159,6 +164,16 @@ impl EncryptableMsg for PlainMessage {
             Self::MsgSocketRequest(m) => m.get_plain(),
         }
     }
+
+    fn get_id(&self) -> Option<&MsgId> {
+        match self {
+            Self::MsgTaskRequest(m) => m.get_id(),
+            Self::MsgTaskResult(m) => m.get_id(),
+            Self::MsgEmpty(_) => None,
+            #[cfg(feature = "sockets")]
+            Self::MsgSocketRequest(m) => EncryptableMsg::get_id(m),
+        }
+    }
 }
 
 
@@ -184,6 +199,16 @@ impl DecryptableMsg for EncryptedMessage {
             Self::MsgSocketRequest(m) => m.get_encryption(),
         }
     }
+
+    fn get_id(&self) -> Option<&MsgId> {
+        match self {
+            Self::MsgTaskRequest(m) => m.get_id(),
+            Self::MsgTaskResult(m) => m.get_id(),
+            Self::MsgEmpty(_) => None,
+            #[cfg(feature = "sockets")]
+            Self::MsgSocketRequest(m) => DecryptableMsg::get_id(m),
+        }
+    }
 }
 
 impl<T: MsgState> Msg for MessageType<T> {
@@ -226,21 +251,26 @@ pub trait DecryptableMsg: Msg + Serialize + Sized {
 
     fn get_encryption(&self) -> Option<&Encrypted>;
     fn convert_self(self, body: String) -> Self::Output;
+    /// Id the ciphertext is bound to, see [`Cipher::XChaCha20Poly1305Bound`]
+    fn get_id(&self) -> Option<&MsgId>;
 
-    /// Decrypts an encrypted message. Caution: can panic.
+    /// Decrypts an encrypted message. `my_serials` are the serials of the certificates matching `my_priv_key`;
+    /// after a certificate was re-signed with the same key, the sender may have wrapped the key for any of them.
+    /// Bodies that do not name their cipher are decrypted with `assumed_cipher`, see [`Encrypted::cipher`]. Caution: can panic.
     #[allow(clippy::or_fun_call)]
     fn decrypt(
         self,
         my_id: &AppOrProxyId,
         my_priv_key: &RsaPrivateKey,
+        my_serials: &[String],
+        assumed_cipher: Option<Cipher>,
     ) -> Result<Self::Output, SamplyBeamError> {
-        let Some(Encrypted {
-            encrypted,
-            encryption_keys,
-        }) = self.get_encryption() else {
+        let Some(encryption) = self.get_encryption() else {
             // We have something that is not encryptable
             return Ok(self.convert_self(String::new()));
         };
+        let cipher = encryption.cipher(assumed_cipher)?;
+        let Encrypted { encrypted, encryption_keys, .. } = encryption;
 
         let to_array_index: usize = self
             .get_to()
@@ -260,12 +290,25 @@ pub trait DecryptableMsg: Msg + Serialize + Sized {
                 "Decryption error: This client cannot be found in 'to' list".into(),
             ))?;
         let encrypted_decryption_key = &encryption_keys[to_array_index];
+        let (symmetric_key, aad) = match cipher {
+            Cipher::XChaCha20Poly1305 => (my_priv_key.decrypt(Oaep::new::<sha2::Sha256>(), encrypted_decryption_key)?, Vec::new()),
+            Cipher::XChaCha20Poly1305Bound => {
+                // The label does not verify for the serials the key was not wrapped for
+                let key = my_serials
+                    .iter()
+                    .find_map(|serial| my_priv_key.decrypt(
+                        Oaep::new_with_label::<sha2::Sha256, _>(key_label(self.get_id(), serial)),
+                        encrypted_decryption_key,
+                    ).ok())
+                    .ok_or(SamplyBeamError::SignEncryptError(
+                        "Decryption error: The key was not wrapped for any of our certificates".into(),
+                    ))?;
+                (key, content_aad(self.get_id()))
+            }
+        };
 
         // Cryptographic Operations
-        let cipher_engine = XChaCha20Poly1305::new_from_slice(&my_priv_key.decrypt(
-            Oaep::new::<sha2::Sha256>(),
-            &encrypted_decryption_key,
-        )?)
+        let cipher_engine = XChaCha20Poly1305::new_from_slice(&symmetric_key)
         .map_err(|e| {
             SamplyBeamError::SignEncryptError(format!(
                 "Decryption error: Cannot initialize stream cipher because {}",
@@ -276,7 +319,7 @@ pub trait DecryptableMsg: Msg + Serialize + Sized {
         let ciphertext = &encrypted[24..];
         let plaintext = String::from_utf8(
             cipher_engine
-                .decrypt(&nonce, ciphertext.as_ref())
+                .decrypt(&nonce, Payload { msg: ciphertext, aad: &aad })
                 .map_err(|e| {
                     SamplyBeamError::SignEncryptError(format!(
                         "Decryption error: Cannot decrypt payload because {}",
@@ -296,16 +339,93 @@ pub trait DecryptableMsg: Msg + Serialize + Sized {
     }
 }
 
+/// Number of threads used to wrap the symmetric key for many recipients. 0 means one per available CPU.
+static CRYPTO_PARALLELISM: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_crypto_parallelism(threads: usize) {
+    CRYPTO_PARALLELISM.store(threads, Ordering::Relaxed);
+}
+
+fn crypto_parallelism() -> usize {
+    match CRYPTO_PARALLELISM.load(Ordering::Relaxed) {
+        0 => std::thread::available_parallelism().map(usize::from).unwrap_or(1),
+        threads => threads,
+    }
+}
+
+/// A recipient's public key along with the serial of the certificate it was taken from
+#[derive(Debug, Clone)]
+pub struct RecipientKey {
+    pub serial: String,
+    pub key: RsaPublicKey,
+}
+
+/// OAEP label binding a wrapped key to the message and the recipient's certificate
+fn key_label(msg_id: Option<&MsgId>, serial: &str) -> String {
+    format!("{}/{serial}", msg_id.map(ToString::to_string).unwrap_or_default())
+}
+
+/// Additional authenticated data binding the payload to the message
+fn content_aad(msg_id: Option<&MsgId>) -> Vec<u8> {
+    msg_id.map(|id| id.to_string().into_bytes()).unwrap_or_default()
+}
+
+/// Encrypts `symmetric_key` for every recipient, spreading the work over up to `parallelism` threads.
+/// With [`Cipher::XChaCha20Poly1305Bound`], each wrapped key is bound to `msg_id` and the recipient's certificate serial.
+/// The wrapped keys are returned in the order of `receivers`.
+fn wrap_symmetric_key(
+    receivers: &[RecipientKey],
+    symmetric_key: &[u8],
+    cipher: Cipher,
+    msg_id: Option<&MsgId>,
+    parallelism: usize,
+) -> Result<Vec<Vec<u8>>, rsa::Error> {
+    let wrap = |keys: &[RecipientKey]| {
+        let mut rng = rand::thread_rng();
+        keys.iter()
+            .map(|recipient| {
+                let padding = match cipher {
+                    Cipher::XChaCha20Poly1305 => Oaep::new::<sha2::Sha256>(),
+                    Cipher::XChaCha20Poly1305Bound => Oaep::new_with_label::<sha2::Sha256, _>(key_label(msg_id, &recipient.serial)),
+                };
+                recipient.key.encrypt(&mut rng, padding, symmetric_key)
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    // Spawning threads does not pay off for a handful of recipients
+    const MIN_KEYS_PER_THREAD: usize = 4;
+    let threads = parallelism.min(receivers.len() / MIN_KEYS_PER_THREAD);
+    if threads <= 1 {
+        return wrap(receivers);
+    }
+    let chunk_size = receivers.len().div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = receivers
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || wrap(chunk)))
+            .collect();
+        let mut wrapped = Vec::with_capacity(receivers.len());
+        for handle in handles {
+            wrapped.extend(handle.join().expect("Key wrapping thread panicked")?);
+        }
+        Ok(wrapped)
+    })
+}
+
 pub trait EncryptableMsg: Msg + Serialize + Sized {
     type Output: Msg;
 
     fn convert_self(self, body: Encrypted) -> Self::Output;
     fn get_plain(&self) -> &Plain;
+    /// Id the ciphertext is bound to, see [`Cipher::XChaCha20Poly1305Bound`]
+    fn get_id(&self) -> Option<&MsgId>;
 
+    /// Encrypts the message for `receivers` with `cipher`. Only proxies that know [`Cipher::XChaCha20Poly1305Bound`] can decrypt it.
     #[allow(clippy::or_fun_call)]
     fn encrypt(
         self,
-        receivers_public_keys: &Vec<RsaPublicKey>,
+        receivers: &[RecipientKey],
+        cipher: Cipher,
     ) -> Result<Self::Output, SamplyBeamError> {
         // Generate Symmetric Key and Nonce
         let mut rng = rand::thread_rng();
@@ -313,30 +433,24 @@ pub trait EncryptableMsg: Msg + Serialize + Sized {
         let nonce = XChaCha20Poly1305::generate_nonce(&mut rng);
 
         // Encrypt symmetric key with receivers' public keys
-        let Ok(encrypted_keys) = receivers_public_keys
-            .iter()
-            .map(|key| {
-                key.encrypt(
-                    &mut rng,
-                    Oaep::new::<sha2::Sha256>(),
-                    symmetric_key.as_slice(),
-                )
-            })
-            .collect()
-        else {
+        let Ok(encrypted_keys) = wrap_symmetric_key(receivers, symmetric_key.as_slice(), cipher, self.get_id(), crypto_parallelism()) else {
             return Err(SamplyBeamError::SignEncryptError(
                 "Encryption error: Cannot encrypt symmetric key".into(),
             ));
         };
 
         // Encrypt fields content
-        let cipher = XChaCha20Poly1305::new(&symmetric_key);
+        let cipher_engine = XChaCha20Poly1305::new(&symmetric_key);
 
         // I cant believe there is no better way
         let default = String::new();
         let plaintext = self.get_plain().body.as_ref().unwrap_or(&default);
 
-        let mut ciphertext = cipher.encrypt(&nonce, plaintext.as_ref()).or(Err(
+        let aad = match cipher {
+            Cipher::XChaCha20Poly1305 => Vec::new(),
+            Cipher::XChaCha20Poly1305Bound => content_aad(self.get_id()),
+        };
+        let mut ciphertext = cipher_engine.encrypt(&nonce, Payload { msg: plaintext.as_ref(), aad: &aad }).or(Err(
             SamplyBeamError::SignEncryptError("Encryption error: Can not encrypt data.".into()),
         ))?;
 
@@ -345,6 +459,7 @@ pub trait EncryptableMsg: Msg + Serialize + Sized {
         nonce_and_ciphertext.append(&mut ciphertext);
 
         Ok(self.convert_self(Encrypted {
+            cipher: Some(cipher),
             encrypted: nonce_and_ciphertext,
             encryption_keys: encrypted_keys,
         }))
@@ -406,8 +521,27 @@ pub trait MsgState: Serialize + Eq + PartialEq + Default {
     }
 }
 
+/// Cipher used to encrypt the payload of a message.
+/// It is part of the signed message so tampering with it invalidates the signature.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum Cipher {
+    // Messages of proxies predating the cipher field are encrypted this way
+    #[serde(rename = "xchacha20poly1305")]
+    #[value(name = "xchacha20poly1305")]
+    XChaCha20Poly1305,
+    /// Additionally binds the payload to the message id (as AEAD associated data) and every wrapped key
+    /// to the message id and the recipient's certificate serial (as OAEP label), so a ciphertext can't be
+    /// passed off as belonging to another message or recipient.
+    #[serde(rename = "xchacha20poly1305-bound")]
+    #[value(name = "xchacha20poly1305-bound")]
+    XChaCha20Poly1305Bound,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
 pub struct Encrypted {
+    /// Missing in bodies of proxies predating this field, see [`Encrypted::cipher`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cipher: Option<Cipher>,
     #[serde(with = "serde_base64" )]
     pub encrypted: Vec<u8>,
     #[serde(with = "serde_base64::nested" )]
@@ -417,12 +551,23 @@ pub struct Encrypted {
 impl Debug for Encrypted {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Encrypted")
+            .field("cipher", &self.cipher)
             .field("encrypted len", &self.encrypted.len())
             .field("encryption_key_count", &self.encryption_keys.len())
             .finish()
     }
 }
 
+impl Encrypted {
+    /// The cipher the body is encrypted with. Bodies that do not name one are assumed to use `assumed`
+    /// or rejected if it is `None`, so that decryption never silently guesses.
+    pub fn cipher(&self, assumed: Option<Cipher>) -> Result<Cipher, SamplyBeamError> {
+        self.cipher.or(assumed).ok_or(SamplyBeamError::DecryptError(
+            "Encrypted body does not name its cipher and no cipher to assume for such bodies is configured",
+        ))
+    }
+}
+
 impl MsgState for Encrypted {}
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
@@ -502,6 +647,10 @@ impl EncryptableMsg for MsgTaskRequest {
     fn get_plain(&self) -> &Plain {
         &self.body
     }
+
+    fn get_id(&self) -> Option<&MsgId> {
+        Some(&self.id)
+    }
 }
 
 impl DecryptableMsg for MsgTaskRequest<Encrypted> {
@@ -532,6 +681,10 @@ impl DecryptableMsg for MsgTaskRequest<Encrypted> {
     fn get_encryption(&self) -> Option<&Encrypted> {
         Some(&self.body)
     }
+
+    fn get_id(&self) -> Option<&MsgId> {
+        Some(&self.id)
+    }
 }
 
 pub type EncryptedMsgTaskRequest = MsgTaskRequest<Encrypted>;
@@ -576,6 +729,10 @@ impl DecryptableMsg for MsgTaskResult<Encrypted> {
     fn get_encryption(&self) -> Option<&Encrypted> {
         Some(&self.body)
     }
+
+    fn get_id(&self) -> Option<&MsgId> {
+        Some(&self.task)
+    }
 }
 
 impl EncryptableMsg for MsgTaskResult<Plain> {
@@ -585,6 +742,10 @@ impl EncryptableMsg for MsgTaskResult<Plain> {
         &self.body
     }
 
+    fn get_id(&self) -> Option<&MsgId> {
+        Some(&self.task)
+    }
+
     fn convert_self(self, body: Encrypted) -> Self::Output {
         let Self {
             from,
@@ -737,6 +898,43 @@ mod tests {
 
     use super::*;
 
+    /// Generating keys with openssl is a lot faster than with rsa in debug builds
+    fn fast_private_key() -> RsaPrivateKey {
+        use rsa::pkcs1::DecodeRsaPrivateKey;
+        let key = openssl::rsa::Rsa::generate(2048).unwrap();
+        RsaPrivateKey::from_pkcs1_der(&key.private_key_to_der().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parallel_key_wrapping_preserves_order() {
+        let privates = [fast_private_key(), fast_private_key(), fast_private_key()];
+        let publics: Vec<_> = (0..20)
+            .map(|i| RecipientKey { serial: format!("{i:02x}"), key: RsaPublicKey::from(&privates[i % privates.len()]) })
+            .collect();
+        let symmetric_key = [42; 32];
+        let id = MsgId::new();
+        let wrapped = wrap_symmetric_key(&publics, &symmetric_key, Cipher::XChaCha20Poly1305Bound, Some(&id), 4).unwrap();
+        assert_eq!(wrapped.len(), publics.len());
+        for (i, wrapped) in wrapped.iter().enumerate() {
+            let padding = Oaep::new_with_label::<sha2::Sha256, _>(key_label(Some(&id), &publics[i].serial));
+            let unwrapped = privates[i % privates.len()].decrypt(padding, wrapped).unwrap();
+            assert_eq!(unwrapped, symmetric_key);
+        }
+    }
+
+    /// Run with `cargo test --release -p shared -- --ignored --nocapture bench_key_wrapping`
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_key_wrapping() {
+        let public = RecipientKey { serial: "01".to_string(), key: RsaPublicKey::from(&fast_private_key()) };
+        let publics = vec![public; 100];
+        for parallelism in [1, crypto_parallelism()] {
+            let start = Instant::now();
+            wrap_symmetric_key(&publics, &[42; 32], Cipher::XChaCha20Poly1305Bound, None, parallelism).unwrap();
+            println!("Wrapping for 100 recipients with {parallelism} threads took {:?}", start.elapsed());
+        }
+    }
+
     #[test]
     fn encrypt_decrypt_task() {
         //Create Task
@@ -765,22 +963,22 @@ mod tests {
             .expect("Failed to generate private key for proxy 1");
         let p2_private = RsaPrivateKey::new(&mut rng, rsa_length)
             .expect("Failed to generate private key for proxy 2");
-        let p1_public = RsaPublicKey::from(&p1_private);
-        let p2_public = RsaPublicKey::from(&p2_private);
+        let p1_public = RecipientKey { serial: "01".to_string(), key: RsaPublicKey::from(&p1_private) };
+        let p2_public = RecipientKey { serial: "02".to_string(), key: RsaPublicKey::from(&p2_private) };
 
         // Encrypt Message
         let receivers_public_keys = vec![p1_public, p2_public];
         let msg_encr = msg
             .clone()
-            .encrypt(&receivers_public_keys)
+            .encrypt(&receivers_public_keys, Cipher::XChaCha20Poly1305Bound)
             .expect("Could not encrypt message");
         // Decrypt for both proxies
         let msg_p1_decr = msg_encr
             .clone()
-            .decrypt(&p1_id, &p1_private)
+            .decrypt(&p1_id, &p1_private, &["01".to_string()], None)
             .expect("Cannot decrypt message");
         let msg_p2_decr = msg_encr
-            .decrypt(&p2_id, &p2_private)
+            .decrypt(&p2_id, &p2_private, &["02".to_string()], None)
             .expect("Cannot decrypt message");
 
         assert_eq!(msg_p1_decr, msg_p2_decr);
@@ -811,26 +1009,156 @@ mod tests {
             .expect("Failed to generate private key for proxy 1");
         let p2_private = RsaPrivateKey::new(&mut rng, rsa_length)
             .expect("Failed to generate private key for proxy 2");
-        let p1_public = RsaPublicKey::from(&p1_private);
-        let p2_public = RsaPublicKey::from(&p2_private);
+        let p1_public = RecipientKey { serial: "01".to_string(), key: RsaPublicKey::from(&p1_private) };
+        let p2_public = RecipientKey { serial: "02".to_string(), key: RsaPublicKey::from(&p2_private) };
 
         // Encrypt Message
         let receivers_public_keys = vec![p1_public, p2_public];
         let msg_encr = msg
             .clone()
-            .encrypt(&receivers_public_keys)
+            .encrypt(&receivers_public_keys, Cipher::XChaCha20Poly1305Bound)
             .expect("Could not encrypt message");
         // Decrypt for both proxies
         let msg_p1_decr = msg_encr
             .clone()
-            .decrypt(&p1_id, &p1_private)
+            .decrypt(&p1_id, &p1_private, &["01".to_string()], None)
             .expect("Cannot decrypt message");
         let msg_p2_decr = msg_encr
             .clone()
-            .decrypt(&p2_id, &p2_private)
+            .decrypt(&p2_id, &p2_private, &["02".to_string()], None)
             .expect("Cannot decrypt message");
 
         assert_eq!(msg_p1_decr, msg_p2_decr);
         assert_eq!(msg, msg_p1_decr);
     }
+
+    #[test]
+    fn ciphertext_is_bound_to_recipient_and_message() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let p1_id = AppOrProxyId::App(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let p1_private = fast_private_key();
+        let msg = MsgTaskRequest::new(p1_id.clone(), vec![p1_id.clone()], "Testbody".into(), FailureStrategy::Discard, json!(null));
+        let msg_encr = msg
+            .encrypt(&[RecipientKey { serial: "01".to_string(), key: RsaPublicKey::from(&p1_private) }], Cipher::XChaCha20Poly1305Bound)
+            .unwrap();
+        assert_eq!(msg_encr.body.cipher, Some(Cipher::XChaCha20Poly1305Bound));
+        assert!(msg_encr.clone().decrypt(&p1_id, &p1_private, &["01".to_string()], None).is_ok());
+
+        // The key was wrapped for another certificate
+        assert!(msg_encr.clone().decrypt(&p1_id, &p1_private, &["02".to_string()], None).is_err());
+
+        // The payload was encrypted for another message
+        let mut replayed = msg_encr;
+        replayed.id = MsgId::new();
+        assert!(replayed.decrypt(&p1_id, &p1_private, &["01".to_string()], None).is_err());
+    }
+
+    #[test]
+    fn unbound_cipher_is_readable_by_older_proxies() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let p1_id = AppOrProxyId::App(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let p1_private = fast_private_key();
+        let msg = MsgTaskRequest::new(p1_id.clone(), vec![p1_id.clone()], "Testbody".into(), FailureStrategy::Discard, json!(null));
+        let msg_encr = msg
+            .clone()
+            .encrypt(&[RecipientKey { serial: "01".to_string(), key: RsaPublicKey::from(&p1_private) }], Cipher::XChaCha20Poly1305)
+            .unwrap();
+        assert_eq!(msg_encr.body.cipher, Some(Cipher::XChaCha20Poly1305));
+
+        // Older proxies ignore the cipher field and decrypt without any binding
+        let mut legacy = serde_json::to_value(&msg_encr).unwrap();
+        legacy.as_object_mut().unwrap().remove("cipher");
+        let legacy: EncryptedMsgTaskRequest = serde_json::from_str(&legacy.to_string()).unwrap();
+        let msg_decr = legacy.decrypt(&p1_id, &p1_private, &[], Some(Cipher::XChaCha20Poly1305)).unwrap();
+        assert_eq!(msg, msg_decr);
+    }
+
+    #[test]
+    fn key_wrapped_for_renewed_certificate_decrypts() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let p1_id = AppOrProxyId::App(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let p1_private = fast_private_key();
+        let msg = MsgTaskRequest::new(p1_id.clone(), vec![p1_id.clone()], "Testbody".into(), FailureStrategy::Discard, json!(null));
+        // The certificate with serial 01 was re-signed with the same key as serial 02, which senders now pick
+        let msg_encr = msg
+            .clone()
+            .encrypt(&[RecipientKey { serial: "02".to_string(), key: RsaPublicKey::from(&p1_private) }], Cipher::XChaCha20Poly1305Bound)
+            .unwrap();
+        assert!(msg_encr.clone().decrypt(&p1_id, &p1_private, &["01".to_string()], None).is_err());
+        let msg_decr = msg_encr.decrypt(&p1_id, &p1_private, &["01".to_string(), "02".to_string()], None).unwrap();
+        assert_eq!(msg, msg_decr);
+    }
+
+    #[test]
+    fn bodies_must_name_their_cipher() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let p1_id = AppOrProxyId::App(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let p1_private = fast_private_key();
+        let msg = MsgTaskRequest::new(p1_id.clone(), vec![p1_id.clone()], "Testbody".into(), FailureStrategy::Discard, json!(null));
+        let identified = msg
+            .encrypt(&[RecipientKey { serial: "01".to_string(), key: RsaPublicKey::from(&p1_private) }], Cipher::XChaCha20Poly1305Bound)
+            .unwrap();
+        assert!(identified.clone().decrypt(&p1_id, &p1_private, &["01".to_string()], None).is_ok());
+
+        // Bodies of older proxies lack the field
+        let mut unidentified = serde_json::to_value(&identified).unwrap();
+        assert!(unidentified.as_object_mut().unwrap().remove("cipher").is_some());
+        let unidentified: EncryptedMsgTaskRequest = serde_json::from_str(&unidentified.to_string()).unwrap();
+        assert_eq!(unidentified.body.cipher, None);
+        let err = unidentified.clone().decrypt(&p1_id, &p1_private, &["01".to_string()], None).unwrap_err();
+        assert!(matches!(err, SamplyBeamError::DecryptError(_)), "{err}");
+        assert!(unidentified.decrypt(&p1_id, &p1_private, &["01".to_string()], Some(Cipher::XChaCha20Poly1305Bound)).is_ok());
+    }
+
+    /// Signs a task with a fresh key, returning the token and the key to verify it with
+    async fn signed_task(to: &[&str], metadata: Value) -> (String, jwt_simple::prelude::RS256PublicKey) {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let p1_id = AppOrProxyId::App(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let to = to.iter().map(|to| AppOrProxyId::App(AppId::new(*to).unwrap())).collect();
+        let msg = MsgTaskRequest::new(p1_id, to, "Testbody".into(), FailureStrategy::Discard, metadata);
+
+        let privkey_rsa = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let privkey_rs256 = jwt_simple::prelude::RS256KeyPair::from_der(
+            rsa::pkcs1::EncodeRsaPrivateKey::to_pkcs1_der(&privkey_rsa).unwrap().as_bytes()
+        ).unwrap();
+        let pubkey = privkey_rs256.public_key();
+        let crypto = config_shared::ConfigCrypto { privkey_rs256, privkey_rsa: privkey_rsa.clone(), privkey_format: config_shared::KeyFormat::Pkcs1, public: None };
+        let msg_encr = msg.encrypt(&[RecipientKey { serial: "01".to_string(), key: RsaPublicKey::from(&privkey_rsa) }], Cipher::XChaCha20Poly1305Bound).unwrap();
+        let jwt = crypto_jwt::sign_to_jwt(&msg_encr, Some(&crypto)).await.unwrap();
+        assert!(pubkey.verify_token::<Value>(&jwt, None).is_ok());
+        (jwt, pubkey)
+    }
+
+    /// Replaces `from` with `to` in the claims of a signed token without signing it again
+    fn tamper_with_claims(jwt: &str, from: &str, to: &str) -> String {
+        use ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder};
+
+        let mut parts = jwt.split('.').map(ToOwned::to_owned).collect::<Vec<_>>();
+        let claims = String::from_utf8(Base64UrlSafeNoPadding::decode_to_vec(&parts[1], None).unwrap()).unwrap();
+        assert!(claims.contains(from));
+        parts[1] = Base64UrlSafeNoPadding::encode_to_string(claims.replace(from, to)).unwrap();
+        parts.join(".")
+    }
+
+    #[tokio::test]
+    async fn tampering_with_cipher_invalidates_signature() {
+        let (jwt, pubkey) = signed_task(&["app.proxy1.broker.samply.de"], json!(null)).await;
+        // Declare a different cipher after signing
+        let tampered_jwt = tamper_with_claims(&jwt, "\"cipher\":\"xchacha20poly1305-bound\"", "\"cipher\":\"none\"");
+        assert!(pubkey.verify_token::<Value>(&tampered_jwt, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn tampering_with_metadata_invalidates_signature() {
+        let (jwt, pubkey) = signed_task(&["app.proxy1.broker.samply.de"], json!({"priority": "low"})).await;
+        let tampered_jwt = tamper_with_claims(&jwt, "\"priority\":\"low\"", "\"priority\":\"high\"");
+        assert!(pubkey.verify_token::<Value>(&tampered_jwt, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn removing_a_recipient_invalidates_signature() {
+        let (jwt, pubkey) = signed_task(&["app.proxy1.broker.samply.de", "app.proxy2.broker.samply.de"], json!(null)).await;
+        let tampered_jwt = tamper_with_claims(&jwt, ",\"app.proxy2.broker.samply.de\"", "");
+        assert!(pubkey.verify_token::<Value>(&tampered_jwt, None).is_err());
+    }
 }
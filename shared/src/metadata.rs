@@ -0,0 +1,294 @@
+use serde_json::{Map, Value};
+
+use crate::errors::SamplyBeamError;
+
+/// Default for the maximum nesting depth of a message's metadata
+pub const DEFAULT_MAX_METADATA_DEPTH: usize = 32;
+/// Default for the maximum number of keys in a message's metadata, counting nested objects' keys as well
+pub const DEFAULT_MAX_METADATA_KEYS: usize = 128;
+
+/// Limits on the shape of a message's metadata, cf. `--max-metadata-depth` and `--max-metadata-keys`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataLimits {
+    pub max_depth: usize,
+    pub max_keys: usize,
+}
+
+impl Default for MetadataLimits {
+    fn default() -> Self {
+        Self { max_depth: DEFAULT_MAX_METADATA_DEPTH, max_keys: DEFAULT_MAX_METADATA_KEYS }
+    }
+}
+
+impl MetadataLimits {
+    /// The limits the proxy and broker were started with
+    pub fn configured() -> Self {
+        let config = &crate::config::CONFIG_SHARED;
+        Self { max_depth: config.max_metadata_depth, max_keys: config.max_metadata_keys }
+    }
+
+    pub fn check(&self, metadata: &Value) -> Result<(), SamplyBeamError> {
+        check_metadata_depth(metadata, self.max_depth)?;
+        check_metadata_keys(metadata, self.max_keys)
+    }
+}
+
+/// Returns how deeply arrays and objects are nested in `value`. Scalars have a depth of 0.
+pub fn nesting_depth(value: &Value) -> usize {
+    // Walk the tree iteratively so that malicious payloads cannot exhaust the stack
+    let mut max_depth = 0;
+    let mut stack = vec![(value, 0)];
+    while let Some((value, depth)) = stack.pop() {
+        match value {
+            Value::Array(arr) => stack.extend(arr.iter().map(|child| (child, depth + 1))),
+            Value::Object(obj) => stack.extend(obj.values().map(|child| (child, depth + 1))),
+            _ => continue,
+        }
+        max_depth = max_depth.max(depth + 1);
+    }
+    max_depth
+}
+
+/// Counts the keys of all objects in `value`, including nested ones
+pub fn key_count(value: &Value) -> usize {
+    let mut count = 0;
+    let mut stack = vec![value];
+    while let Some(value) = stack.pop() {
+        match value {
+            Value::Array(arr) => stack.extend(arr),
+            Value::Object(obj) => {
+                count += obj.len();
+                stack.extend(obj.values());
+            }
+            _ => continue,
+        }
+    }
+    count
+}
+
+/// Rejects metadata with more than `max_keys` keys in total
+pub fn check_metadata_keys(metadata: &Value, max_keys: usize) -> Result<(), SamplyBeamError> {
+    let count = key_count(metadata);
+    if count > max_keys {
+        return Err(SamplyBeamError::RequestValidationFailed(format!(
+            "Metadata has {count} keys which exceeds the maximum of {max_keys}"
+        )));
+    }
+    Ok(())
+}
+
+/// Applies the configured depth and key count limits to a message's metadata
+pub fn check_metadata(metadata: &Value) -> Result<(), SamplyBeamError> {
+    MetadataLimits::configured().check(metadata)
+}
+
+/// Rejects metadata that is nested deeper than `max_depth` levels
+pub fn check_metadata_depth(metadata: &Value, max_depth: usize) -> Result<(), SamplyBeamError> {
+    let depth = nesting_depth(metadata);
+    if depth > max_depth {
+        return Err(SamplyBeamError::RequestValidationFailed(format!(
+            "Metadata is nested {depth} levels deep which exceeds the maximum of {max_depth}"
+        )));
+    }
+    Ok(())
+}
+
+/// Top level metadata keys starting with this prefix are reserved for annotations added by beam itself
+pub const RESERVED_KEY_PREFIX: &str = "beam_";
+
+/// What to do with reserved keys in metadata supplied by apps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReservedKeyPolicy {
+    /// Silently remove reserved keys
+    #[default]
+    Strip,
+    /// Reject the message
+    Reject,
+}
+
+/// Removes or rejects reserved top level keys in app supplied metadata so apps cannot forge beam's own annotations
+pub fn enforce_reserved_keys(metadata: &mut Value, policy: ReservedKeyPolicy) -> Result<(), SamplyBeamError> {
+    let Value::Object(obj) = metadata else {
+        return Ok(());
+    };
+    match policy {
+        ReservedKeyPolicy::Strip => obj.retain(|key, _| !key.starts_with(RESERVED_KEY_PREFIX)),
+        ReservedKeyPolicy::Reject => {
+            if let Some(key) = obj.keys().find(|key| key.starts_with(RESERVED_KEY_PREFIX)) {
+                return Err(SamplyBeamError::RequestValidationFailed(format!(
+                    "Metadata key {key} is reserved for internal use"
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses `--default-metadata`: a json object whose keys are merged into the metadata of outgoing messages.
+/// Reserved keys are rejected so that beam's own annotations cannot be injected this way either.
+pub fn parse_default_metadata(value: &str) -> Result<Map<String, Value>, String> {
+    let Value::Object(defaults) = serde_json::from_str(value).map_err(|e| e.to_string())? else {
+        return Err("Default metadata must be a json object".to_string());
+    };
+    if let Some(key) = defaults.keys().find(|key| key.starts_with(RESERVED_KEY_PREFIX)) {
+        return Err(format!("Metadata key {key} is reserved for internal use"));
+    }
+    Ok(defaults)
+}
+
+/// Adds the default keys to the top level of a message's metadata. Keys set by the app are kept unless `overwrite` is set.
+/// Metadata that is neither absent nor an object is left untouched.
+pub fn merge_default_metadata(metadata: &mut Value, defaults: &Map<String, Value>, overwrite: bool) {
+    if defaults.is_empty() {
+        return;
+    }
+    if metadata.is_null() {
+        *metadata = Value::Object(Map::new());
+    }
+    let Value::Object(obj) = metadata else {
+        return;
+    };
+    for (key, value) in defaults {
+        if overwrite || !obj.contains_key(key) {
+            obj.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+/// Metadata key under which the sending proxy records the content type an app declared for a message's body
+pub const CONTENT_TYPE_KEY: &str = "beam_content_type";
+
+/// Content types an app may declare for a message's body
+const CONTENT_TYPES: [&str; 3] = ["application/json", "text/plain", "application/octet-stream"];
+
+/// Checks the content type an app declared for a message's `body` and records it in the metadata
+/// so it can be logged and routed on without decrypting the body.
+/// Binary bodies have to be base64 encoded as the body is a string.
+pub fn annotate_content_type(metadata: &mut Value, declared: &str, body: Option<&str>) -> Result<(), SamplyBeamError> {
+    let Some(content_type) = CONTENT_TYPES.into_iter().find(|ct| ct.eq_ignore_ascii_case(declared.trim())) else {
+        return Err(SamplyBeamError::RequestValidationFailed(format!(
+            "Unsupported body content type {declared}, expected one of {}", CONTENT_TYPES.join(", ")
+        )));
+    };
+    let body = body.unwrap_or_default();
+    let matches = match content_type {
+        "application/json" => serde_json::from_str::<serde::de::IgnoredAny>(body).is_ok(),
+        "application/octet-stream" => openssl::base64::decode_block(body).is_ok(),
+        _ => true,
+    };
+    if !matches {
+        return Err(SamplyBeamError::RequestValidationFailed(format!(
+            "Body does not match the declared content type {content_type}"
+        )));
+    }
+    if metadata.is_null() {
+        *metadata = Value::Object(Default::default());
+    }
+    let Value::Object(obj) = metadata else {
+        return Err(SamplyBeamError::RequestValidationFailed(
+            "A body content type can only be declared for messages without metadata or with an object as metadata".into()
+        ));
+    };
+    obj.insert(CONTENT_TYPE_KEY.to_string(), Value::String(content_type.to_string()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn nested(depth: usize) -> Value {
+        (0..depth).fold(json!("leaf"), |inner, i| if i % 2 == 0 {
+            json!([inner])
+        } else {
+            json!({ "nested": inner })
+        })
+    }
+
+    #[test]
+    fn test_nesting_depth() {
+        assert_eq!(nesting_depth(&json!(null)), 0);
+        assert_eq!(nesting_depth(&json!({})), 1);
+        assert_eq!(nesting_depth(&json!({"a": [1, {"b": 2}], "c": 3})), 3);
+        assert_eq!(nesting_depth(&nested(32)), 32);
+    }
+
+    #[test]
+    fn test_metadata_too_deep() {
+        assert!(check_metadata_depth(&nested(32), 32).is_ok());
+        let err = check_metadata_depth(&nested(33), 32).unwrap_err();
+        assert!(err.to_string().contains("exceeds the maximum of 32"), "{err}");
+    }
+
+    #[test]
+    fn test_reserved_keys() {
+        let client_metadata = json!({"beam_verified_from": "app.proxy1.broker", "purpose": "test"});
+
+        let mut stripped = client_metadata.clone();
+        enforce_reserved_keys(&mut stripped, ReservedKeyPolicy::Strip).unwrap();
+        assert_eq!(stripped, json!({"purpose": "test"}));
+
+        let mut rejected = client_metadata.clone();
+        let err = enforce_reserved_keys(&mut rejected, ReservedKeyPolicy::Reject).unwrap_err();
+        assert!(err.to_string().contains("beam_verified_from"), "{err}");
+
+        // Only the top level is reserved
+        let mut nested = json!({"inner": {"beam_verified_from": "x"}});
+        assert!(enforce_reserved_keys(&mut nested, ReservedKeyPolicy::Reject).is_ok());
+    }
+
+    #[test]
+    fn test_default_metadata() {
+        let defaults = parse_default_metadata(r#"{"environment": "staging", "region": "eu"}"#).unwrap();
+
+        let mut metadata = json!({"region": "us", "purpose": "test"});
+        merge_default_metadata(&mut metadata, &defaults, false);
+        assert_eq!(metadata, json!({"environment": "staging", "region": "us", "purpose": "test"}));
+
+        let mut overwritten = json!({"region": "us"});
+        merge_default_metadata(&mut overwritten, &defaults, true);
+        assert_eq!(overwritten, json!({"environment": "staging", "region": "eu"}));
+
+        let mut absent = Value::Null;
+        merge_default_metadata(&mut absent, &defaults, false);
+        assert_eq!(absent, json!({"environment": "staging", "region": "eu"}));
+        let mut scalar = json!("The broker can read this");
+        merge_default_metadata(&mut scalar, &defaults, false);
+        assert_eq!(scalar, json!("The broker can read this"));
+
+        let err = parse_default_metadata(r#"{"beam_content_type": "text/plain"}"#).unwrap_err();
+        assert!(err.contains("beam_content_type"), "{err}");
+        assert!(parse_default_metadata(r#"["not", "an", "object"]"#).is_err());
+    }
+
+    #[test]
+    fn test_metadata_key_limit() {
+        let keys = |n: usize| Value::Object((0..n).map(|i| (format!("key{i}"), Value::Null)).collect());
+        assert_eq!(key_count(&json!({"a": [{"b": 1}, {"c": {"d": 2}}]})), 4);
+        assert!(check_metadata_keys(&keys(128), 128).is_ok());
+        let err = check_metadata_keys(&keys(129), 128).unwrap_err();
+        assert!(err.to_string().contains("129 keys"), "{err}");
+        // Nested keys count as well
+        assert!(check_metadata_keys(&json!({"outer": keys(128)}), 128).is_err());
+    }
+
+    #[test]
+    fn test_declared_content_type() {
+        let mut metadata = json!({"purpose": "test"});
+        annotate_content_type(&mut metadata, "Application/JSON", Some(r#"{"query": 1}"#)).unwrap();
+        assert_eq!(metadata, json!({"purpose": "test", "beam_content_type": "application/json"}));
+
+        let mut metadata = Value::Null;
+        annotate_content_type(&mut metadata, "application/octet-stream", Some("AAEC")).unwrap();
+        assert_eq!(metadata, json!({"beam_content_type": "application/octet-stream"}));
+
+        let err = annotate_content_type(&mut Value::Null, "image/png", Some("x")).unwrap_err();
+        assert!(err.to_string().contains("Unsupported"), "{err}");
+        // Contradicting bodies are rejected
+        assert!(annotate_content_type(&mut Value::Null, "application/json", Some("not json")).is_err());
+        assert!(annotate_content_type(&mut Value::Null, "application/octet-stream", Some("not base64!")).is_err());
+        assert!(annotate_content_type(&mut json!("string metadata"), "text/plain", Some("hi")).is_err());
+    }
+}
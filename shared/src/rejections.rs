@@ -0,0 +1,85 @@
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+
+use once_cell::sync::Lazy;
+
+use crate::config;
+
+/// Rejected requests of the broker, cf. `--rejection-log-window`
+pub static REJECTIONS: Lazy<RejectionLog> = Lazy::new(|| RejectionLog::new(config::CONFIG_SHARED.rejection_log_window));
+
+/// Counts rejected requests by reason and samples which of them are logged, so that a flood of rejections does not drown the log.
+/// The first rejection for a reason within a window is logged, the others are only counted.
+pub struct RejectionLog {
+    window: Duration,
+    reasons: Mutex<HashMap<&'static str, ReasonStats>>,
+}
+
+#[derive(Default)]
+struct ReasonStats {
+    total: u64,
+    logged_at: Option<Instant>,
+    suppressed: u64,
+}
+
+impl RejectionLog {
+    pub fn new(window: Duration) -> Self {
+        Self { window, reasons: Default::default() }
+    }
+
+    /// Counts a rejection. Returns the number of rejections for this reason that were not logged since the last logged one
+    /// if this one should be logged.
+    pub fn record(&self, reason: &'static str, now: Instant) -> Option<u64> {
+        let mut reasons = self.reasons.lock().unwrap();
+        let stats = reasons.entry(reason).or_default();
+        stats.total += 1;
+        match stats.logged_at {
+            Some(logged_at) if now.saturating_duration_since(logged_at) < self.window => {
+                stats.suppressed += 1;
+                None
+            }
+            _ => {
+                stats.logged_at = Some(now);
+                Some(std::mem::take(&mut stats.suppressed))
+            }
+        }
+    }
+
+    /// Number of rejections by reason, including those that were not logged
+    pub fn counts(&self) -> HashMap<&'static str, u64> {
+        self.reasons.lock().unwrap().iter().map(|(reason, stats)| (*reason, stats.total)).collect()
+    }
+}
+
+/// Logs a rejection with `warn!` unless another one for the same reason has been logged within the window
+#[macro_export]
+macro_rules! warn_rejected {
+    ($reason:literal, $($arg:tt)+) => {
+        if let Some(suppressed) = $crate::rejections::REJECTIONS.record($reason, std::time::Instant::now()) {
+            tracing::warn!(reason = $reason, suppressed, $($arg)+);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling_reduces_logs_but_counts_all() {
+        let log = RejectionLog::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let logged = (0..100).filter(|i| log.record("replay", now + Duration::from_millis(*i)).is_some()).count();
+        assert_eq!(logged, 1);
+        // Other reasons are sampled on their own
+        assert_eq!(log.record("stale", now), Some(0));
+        // The next window logs again, reporting what was left out
+        assert_eq!(log.record("replay", now + Duration::from_secs(61)), Some(99));
+        let counts = log.counts();
+        assert_eq!(counts["replay"], 101);
+        assert_eq!(counts["stale"], 1);
+
+        // Without a window everything is logged
+        let log = RejectionLog::new(Duration::ZERO);
+        assert!((0..10).all(|_| log.record("replay", now).is_some()));
+    }
+}
@@ -16,7 +16,7 @@ pub mod serialize_time {
     where
         S: Serializer,
     {
-        let ttl = match time.duration_since(SystemTime::now()) {
+        let ttl = match time.duration_since(crate::clock::now()) {
             Ok(v) => v,
             Err(e) => {
                 error!("Internal Error: Tried to serialize a task which should have expired and expunged from memory {} seconds ago. Will return TTL=0. Cause: {}", e.duration().as_secs(), e);
@@ -32,7 +32,7 @@ pub mod serialize_time {
     {
         let duration = &String::deserialize(deserializer)?;
         let ttl = parse_duration(&duration).map_err(serde::de::Error::custom)?;
-        let expire = SystemTime::now() + ttl;
+        let expire = crate::clock::now() + ttl;
         trace!("Deserialized {:?} to time {:?}", duration, expire);
         Ok(expire)
     }
@@ -123,3 +123,30 @@ fn test_socket_task() {
     assert_eq!(a_str, b_str);
     assert!(serde_json::from_str::<beam_lib::SocketTask>(&b_str).is_ok());
 }
+
+#[cfg(feature = "sockets")]
+#[test]
+fn test_socket_task_recipients() {
+    set_broker_id("broker.samply.de".to_string());
+    let from = AppOrProxyId::new("app1.proxy1.broker.samply.de").unwrap();
+    let to = AppOrProxyId::new("app2.proxy2.broker.samply.de").unwrap();
+    let task = |to: Vec<AppOrProxyId>| crate::MsgSocketRequest {
+        from: from.clone(),
+        to,
+        secret: Plain { body: Some("secret".to_string()) },
+        expire: SystemTime::now() + Duration::from_secs(10),
+        id: MsgId::new(),
+        metadata: serde_json::Value::Null
+    };
+
+    let single = serde_json::to_value(task(vec![to.clone()])).unwrap();
+    assert_eq!(single["to"], json!("app2.proxy2.broker.samply.de"));
+    let parsed: crate::MsgSocketRequest<Plain> = serde_json::from_value(single.clone()).unwrap();
+    assert_eq!(parsed.to, vec![to.clone()]);
+    assert_eq!(serde_json::from_value::<beam_lib::SocketTask>(single).unwrap().to, vec![to.clone()]);
+
+    let many = serde_json::to_value(task(vec![to.clone(), from.clone()])).unwrap();
+    assert_eq!(many["to"], json!(["app2.proxy2.broker.samply.de", "app1.proxy1.broker.samply.de"]));
+    let parsed: crate::MsgSocketRequest<Plain> = serde_json::from_value(many).unwrap();
+    assert_eq!(parsed.to, vec![to, from]);
+}
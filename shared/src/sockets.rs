@@ -1,17 +1,20 @@
-use std::time::SystemTime;
+use std::{collections::HashSet, time::{Duration, SystemTime}};
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::{MsgState, serialize_time, MsgId, Msg, DecryptableMsg, Plain, Encrypted, EncryptableMsg, HasWaitId};
+use crate::{errors::SamplyBeamError, metadata, MsgState, serialize_time, MsgId, Msg, DecryptableMsg, Plain, Cipher, Encrypted, EncryptableMsg, HasWaitId};
 use beam_lib::AppOrProxyId;
 
+/// How long a socket request stays valid. Requests may not expire later than this after their creation.
+pub const SOCKET_REQUEST_TTL: Duration = Duration::from_secs(60);
+
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct MsgSocketRequest<State>
 where State: MsgState {
     pub from: AppOrProxyId,
-    // TODO: Tell serde to serialize only one
+    #[serde(with = "beam_lib::serde_one_or_many")]
     pub to: Vec<AppOrProxyId>,
     #[serde(with="serialize_time", rename="ttl")]
     pub expire: SystemTime,
@@ -47,6 +50,10 @@ impl DecryptableMsg for MsgSocketRequest<Encrypted> {
         let Self { from, to, expire, id, metadata, .. } = self;
         Self::Output { from, to, expire, secret: body.into(), id, metadata }
     }
+
+    fn get_id(&self) -> Option<&MsgId> {
+        Some(&self.id)
+    }
 }
 
 impl EncryptableMsg for MsgSocketRequest<Plain> {
@@ -60,6 +67,73 @@ impl EncryptableMsg for MsgSocketRequest<Plain> {
     fn get_plain(&self) -> &Plain {
         &self.secret
     }
+
+    fn get_id(&self) -> Option<&MsgId> {
+        Some(&self.id)
+    }
+}
+
+/// Size of the XChaCha20Poly1305 nonce prepended to the ciphertext
+const NONCE_LEN: usize = 24;
+/// Size of the Poly1305 authentication tag appended to the ciphertext
+const TAG_LEN: usize = 16;
+/// Size of a symmetric key wrapped with RSA-OAEP for a 4096 bit recipient key
+const WRAPPED_KEY_LEN: usize = 4096 / 8;
+
+fn base64_len(len: usize) -> usize {
+    len.div_ceil(3) * 4
+}
+
+impl MsgSocketRequest<Plain> {
+    /// Estimates the size in bytes of this request once it is encrypted for `recipients` recipients and serialized to json.
+    /// Messages are not compressed, so the estimate accounts for the AEAD nonce and tag and one wrapped key per recipient
+    /// assuming 4096 bit RSA keys. The signature added by the proxy is not included.
+    pub fn estimate_encrypted_size(&self, recipients: usize) -> usize {
+        let envelope = MsgSocketRequest {
+            from: self.from.clone(),
+            to: self.to.clone(),
+            expire: self.expire,
+            id: self.id,
+            secret: Encrypted { cipher: Some(Cipher::XChaCha20Poly1305Bound), ..Default::default() },
+            metadata: self.metadata.clone(),
+        };
+        let envelope_len = serde_json::to_vec(&envelope)
+            .expect("Serializing a socket request should never fail")
+            .len();
+        let plain_len = self.secret.body.as_ref().map(String::len).unwrap_or_default();
+        // Every wrapped key is a quoted string and all but the first are preceded by a comma
+        let keys_len = recipients * (base64_len(WRAPPED_KEY_LEN) + 3) - recipients.min(1);
+        envelope_len + base64_len(NONCE_LEN + plain_len + TAG_LEN) + keys_len
+    }
+}
+
+impl<State: MsgState> MsgSocketRequest<State> {
+    /// Runs the structural checks the proxy and broker apply to a socket request without encrypting it or contacting the broker.
+    /// Pass [`metadata::MetadataLimits::configured`] to check the metadata like the running proxy does. Returns the first violation found.
+    pub fn validate_envelope(&self, metadata_limits: metadata::MetadataLimits) -> Result<(), SamplyBeamError> {
+        if self.to.is_empty() {
+            return Err(SamplyBeamError::RequestValidationFailed("Socket request has no recipients".to_string()));
+        }
+        // Ids may have been constructed unchecked so parse them again
+        for id in std::iter::once(&self.from).chain(&self.to) {
+            AppOrProxyId::new(id.as_ref())?;
+        }
+        let mut seen = HashSet::new();
+        if let Some(dup) = self.to.iter().find(|to| !seen.insert(*to)) {
+            return Err(SamplyBeamError::RequestValidationFailed(format!("Recipient {dup} is listed more than once")));
+        }
+        let now = crate::clock::now();
+        if self.expire <= now {
+            return Err(SamplyBeamError::RequestValidationFailed("Socket request has already expired".to_string()));
+        }
+        if self.expire > now + SOCKET_REQUEST_TTL {
+            return Err(SamplyBeamError::RequestValidationFailed(format!(
+                "Socket request ttl exceeds the maximum of {}s",
+                SOCKET_REQUEST_TTL.as_secs()
+            )));
+        }
+        metadata_limits.check(&self.metadata)
+    }
 }
 
 impl<State: MsgState> HasWaitId<MsgId> for MsgSocketRequest<State> {
@@ -67,3 +141,69 @@ impl<State: MsgState> HasWaitId<MsgId> for MsgSocketRequest<State> {
         self.id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use beam_lib::AppId;
+    use rsa::{pkcs1::DecodeRsaPublicKey, RsaPublicKey};
+
+    use super::*;
+
+    #[test]
+    fn estimate_encrypted_size() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let app1 = AppOrProxyId::App(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let app2 = AppOrProxyId::App(AppId::new("app.proxy2.broker.samply.de").unwrap());
+        let msg = MsgSocketRequest {
+            from: app1.clone(),
+            to: vec![app1, app2],
+            expire: SystemTime::now() + Duration::from_secs(60),
+            id: MsgId::new(),
+            secret: "secret key material".into(),
+            metadata: serde_json::json!({"purpose": "test"}),
+        };
+        // Generating a 4096 bit key with openssl is a lot faster than with rsa in debug builds
+        let key = openssl::rsa::Rsa::generate(4096).unwrap();
+        let public = RsaPublicKey::from_pkcs1_der(&key.public_key_to_der_pkcs1().unwrap()).unwrap();
+
+        let estimate = msg.estimate_encrypted_size(2);
+        let public = crate::RecipientKey { serial: "01".to_string(), key: public };
+        let encrypted = msg.encrypt(&[public.clone(), public], crate::Cipher::XChaCha20Poly1305Bound).unwrap();
+        let actual = serde_json::to_vec(&encrypted).unwrap().len();
+        assert!(estimate.abs_diff(actual) <= 8, "Estimated {estimate} bytes but got {actual}");
+    }
+
+    #[test]
+    fn validate_envelope() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let app1 = AppOrProxyId::App(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let app2 = AppOrProxyId::App(AppId::new("app.proxy2.broker.samply.de").unwrap());
+        let valid = MsgSocketRequest {
+            from: app1.clone(),
+            to: vec![app2.clone()],
+            expire: crate::clock::now() + SOCKET_REQUEST_TTL / 2,
+            id: MsgId::new(),
+            secret: Plain::from("secret"),
+            metadata: serde_json::json!({"purpose": "test"}),
+        };
+        let limits = metadata::MetadataLimits { max_depth: 4, max_keys: 8 };
+        assert!(valid.validate_envelope(limits).is_ok());
+
+        let check = |f: &dyn Fn(&mut MsgSocketRequest<Plain>), expected: &str| {
+            let mut msg = valid.clone();
+            f(&mut msg);
+            let err = msg.validate_envelope(limits).unwrap_err();
+            assert!(err.to_string().contains(expected), "Expected {expected:?} but got {err}");
+        };
+        check(&|msg| msg.to.clear(), "no recipients");
+        check(&|msg| msg.to.push(app2.clone()), "more than once");
+        check(&|msg| msg.to = vec![AppOrProxyId::App(AppId::new_unchecked("app.proxy2.other.broker"))], "Invalid Beam ID");
+        check(&|msg| msg.expire = crate::clock::now() - Duration::from_secs(1), "already expired");
+        check(&|msg| msg.expire = crate::clock::now() + SOCKET_REQUEST_TTL * 2, "ttl exceeds");
+        // The given limits apply instead of the defaults
+        check(&|msg| msg.metadata = (0..=limits.max_depth).fold(Value::Null, |inner, _| serde_json::json!([inner])), "nested");
+        check(&|msg| msg.metadata = Value::Object((0..=limits.max_keys).map(|i| (i.to_string(), Value::Null)).collect()), "keys");
+    }
+}